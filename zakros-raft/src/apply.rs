@@ -0,0 +1,134 @@
+//! Application of committed entries to the state machine.
+//!
+//! The state machine runs in its own task, so that applying a large batch
+//! of commands doesn't delay heartbeats and RPCs handled by the server. The
+//! server hands the committed commands over to the task together with the
+//! write requests waiting for them, and the task acknowledges the writes as
+//! it applies the commands. Everything sent to the task is processed in
+//! order, so acknowledgements fire in log order.
+//!
+//! Snapshots are serialized by the task as well, and the server writes
+//! them to the storage in the background, so taking a snapshot doesn't
+//! stall the server either.
+
+use crate::{
+    server::{ReadResponder, WriteResponder},
+    ApplyContext, Command, StateMachine,
+};
+use bytes::Bytes;
+use futures::Future;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, oneshot};
+
+pub struct Applier<C: Command> {
+    tx: mpsc::UnboundedSender<Task<C>>,
+
+    /// index of the last entry the state machine has applied, which lags
+    /// behind the entries handed over to the task
+    applied_index: Arc<AtomicU64>,
+}
+
+/// Commands to apply, and the write requests waiting for them
+pub type Batch<C> = Vec<(
+    (ApplyContext, C),
+    Option<WriteResponder<<C as Command>::Output>>,
+)>;
+
+enum Task<C: Command> {
+    Apply(Batch<C>, u64),
+    Read(u64, ReadResponder),
+    Snapshot(oneshot::Sender<Bytes>),
+    Restore(Bytes, u64, oneshot::Sender<Result<(), String>>),
+}
+
+impl<C: Command> Applier<C> {
+    /// Spawns the task that owns the state machine.
+    pub fn spawn<M: StateMachine<Command = C>>(state_machine: M) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let applied_index = Arc::new(AtomicU64::new(0));
+        tokio::spawn(run(state_machine, rx, applied_index.clone()));
+        Self { tx, applied_index }
+    }
+
+    /// Returns the index of the last entry the state machine has applied.
+    pub fn applied_index(&self) -> u64 {
+        self.applied_index.load(Ordering::Acquire)
+    }
+
+    /// Applies the commands, and acknowledges the writes with the outputs.
+    ///
+    /// `last_index` is the index of the last entry the batch covers, which
+    /// counts the NoOp and membership entries that have no commands.
+    pub fn apply(&self, batch: Batch<C>, last_index: u64) {
+        let _ = self.tx.send(Task::Apply(batch, last_index));
+    }
+
+    /// Acknowledges the read with the read index once all the commands
+    /// handed over so far are applied.
+    pub fn read(&self, index: u64, tx: ReadResponder) {
+        let _ = self.tx.send(Task::Read(index, tx));
+    }
+
+    /// Serializes the state after all the commands handed over so far are
+    /// applied.
+    ///
+    /// The returned future doesn't borrow the applier, so the server can
+    /// keep handing over commands while it waits for the snapshot.
+    pub fn snapshot(&self) -> impl Future<Output = Bytes> + Send + 'static {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Task::Snapshot(tx));
+        async move { rx.await.expect("state machine task stopped") }
+    }
+
+    /// Replaces the state with the snapshot that includes the entries up to
+    /// `index`, or returns the reason it couldn't be restored.
+    pub async fn restore(&self, snapshot: Bytes, index: u64) -> Result<(), String> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Task::Restore(snapshot, index, tx));
+        rx.await.expect("state machine task stopped")
+    }
+}
+
+/// Processes the tasks until the server is dropped.
+async fn run<M: StateMachine>(
+    mut state_machine: M,
+    mut rx: mpsc::UnboundedReceiver<Task<M::Command>>,
+    applied_index: Arc<AtomicU64>,
+) {
+    while let Some(task) = rx.recv().await {
+        match task {
+            Task::Apply(batch, last_index) => {
+                let (commands, txs): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+                let outputs = if commands.is_empty() {
+                    Vec::new()
+                } else {
+                    state_machine.apply_batch(commands).await
+                };
+                applied_index.store(last_index, Ordering::Release);
+                assert_eq!(outputs.len(), txs.len());
+                for (output, tx) in outputs.into_iter().zip(txs) {
+                    if let Some(tx) = tx {
+                        tracing::trace!("acknowledging write request");
+                        let _ = tx.send(Ok(output));
+                    }
+                }
+            }
+            Task::Read(index, tx) => {
+                let _ = tx.send(Ok(index));
+            }
+            Task::Snapshot(tx) => {
+                let _ = tx.send(state_machine.snapshot().await);
+            }
+            Task::Restore(snapshot, index, tx) => {
+                let result = state_machine.restore(snapshot).await;
+                if result.is_ok() {
+                    applied_index.store(index, Ordering::Release);
+                }
+                let _ = tx.send(result.map_err(|err| format!("{err:?}")));
+            }
+        }
+    }
+}
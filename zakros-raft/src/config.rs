@@ -1,4 +1,4 @@
-use rand::{distributions::Uniform, prelude::Distribution};
+use rand::{distributions::Uniform, prelude::Distribution, Rng};
 use std::time::Duration;
 use tokio::time::Instant;
 
@@ -7,6 +7,15 @@ pub struct RaftConfig {
     pub(crate) heartbeat_interval: Duration,
     pub(crate) election_timeout_min: Duration,
     pub(crate) election_timeout_max: Duration,
+    pub(crate) snapshot_threshold: u64,
+    pub(crate) pre_vote: bool,
+    pub(crate) check_quorum: bool,
+    pub(crate) lease_read: bool,
+    pub(crate) max_clock_drift: Duration,
+    pub(crate) follower_read: bool,
+    pub(crate) max_entries_per_append: usize,
+    pub(crate) max_bytes_per_append: u64,
+    pub(crate) max_in_flight_appends: usize,
 }
 
 impl Default for RaftConfig {
@@ -15,6 +24,15 @@ impl Default for RaftConfig {
             heartbeat_interval: Duration::from_millis(200),
             election_timeout_min: Duration::from_secs(1),
             election_timeout_max: Duration::from_secs(2),
+            snapshot_threshold: 10000,
+            pre_vote: true,
+            check_quorum: true,
+            lease_read: false,
+            max_clock_drift: Duration::from_millis(100),
+            follower_read: false,
+            max_entries_per_append: 1024,
+            max_bytes_per_append: 1024 * 1024,
+            max_in_flight_appends: 8,
         }
     }
 }
@@ -24,9 +42,9 @@ impl RaftConfig {
         RaftConfigBuilder::default()
     }
 
-    pub(crate) fn random_election_deadline(&self) -> Instant {
+    pub(crate) fn random_election_deadline<R: Rng>(&self, rng: &mut R) -> Instant {
         let dist = Uniform::new(self.election_timeout_min, self.election_timeout_max);
-        tokio::time::Instant::now() + dist.sample(&mut rand::thread_rng())
+        tokio::time::Instant::now() + dist.sample(rng)
     }
 }
 
@@ -45,10 +63,90 @@ impl RaftConfigBuilder {
         self
     }
 
+    /// Number of applied entries after which a new snapshot is taken and
+    /// the log is compacted. 0 disables snapshotting.
+    pub fn snapshot_threshold(&mut self, threshold: u64) -> &mut Self {
+        self.0.snapshot_threshold = threshold;
+        self
+    }
+
+    /// Enables the PreVote phase, in which a node makes sure it can win
+    /// an election before incrementing its term.
+    pub fn pre_vote(&mut self, enabled: bool) -> &mut Self {
+        self.0.pre_vote = enabled;
+        self
+    }
+
+    /// Makes the leader step down when it doesn't hear from a majority of
+    /// the cluster within an election timeout.
+    pub fn check_quorum(&mut self, enabled: bool) -> &mut Self {
+        self.0.check_quorum = enabled;
+        self
+    }
+
+    /// Lets the leader serve reads without contacting a quorum while it
+    /// holds a lease, which is renewed whenever a quorum acknowledges
+    /// AppendEntries RPCs. Requires PreVote.
+    ///
+    /// Unlike the default quorum reads, this relies on bounded clock
+    /// drift between nodes.
+    pub fn lease_read(&mut self, enabled: bool) -> &mut Self {
+        self.0.lease_read = enabled;
+        self
+    }
+
+    /// Maximum clock drift between nodes within an election timeout.
+    /// The lease expires this much earlier than the minimum election
+    /// timeout.
+    pub fn max_clock_drift(&mut self, drift: Duration) -> &mut Self {
+        self.0.max_clock_drift = drift;
+        self
+    }
+
+    /// Lets followers serve linearizable reads. A follower asks the leader
+    /// for its commit index with a ReadIndex RPC, and serves the read once
+    /// it applies the entries up to that index.
+    pub fn follower_read(&mut self, enabled: bool) -> &mut Self {
+        self.0.follower_read = enabled;
+        self
+    }
+
+    /// Maximum number of entries sent in a single AppendEntries RPC.
+    pub fn max_entries_per_append(&mut self, max_entries: usize) -> &mut Self {
+        self.0.max_entries_per_append = max_entries;
+        self
+    }
+
+    /// Maximum total size in bytes of the entries sent in a single
+    /// AppendEntries RPC. An entry larger than this is sent on its own.
+    pub fn max_bytes_per_append(&mut self, max_bytes: u64) -> &mut Self {
+        self.0.max_bytes_per_append = max_bytes;
+        self
+    }
+
+    /// Maximum number of AppendEntries RPCs sent to a follower that haven't
+    /// been responded to. The leader sends the following entries without
+    /// waiting for the responses as long as the number is below this.
+    pub fn max_in_flight_appends(&mut self, max_in_flight: usize) -> &mut Self {
+        self.0.max_in_flight_appends = max_in_flight;
+        self
+    }
+
     pub fn build(&self) -> Result<RaftConfig, RaftConfigError> {
         if self.0.election_timeout_min >= self.0.election_timeout_max {
             return Err(RaftConfigError::InvalidElectionTimeoutRange);
         }
+        if self.0.max_entries_per_append == 0 || self.0.max_in_flight_appends == 0 {
+            return Err(RaftConfigError::ZeroFlowControlLimit);
+        }
+        if self.0.lease_read {
+            if !self.0.pre_vote {
+                return Err(RaftConfigError::LeaseReadWithoutPreVote);
+            }
+            if self.0.max_clock_drift >= self.0.election_timeout_min {
+                return Err(RaftConfigError::ClockDriftTooLarge);
+            }
+        }
         Ok(self.0.clone())
     }
 }
@@ -57,4 +155,13 @@ impl RaftConfigBuilder {
 pub enum RaftConfigError {
     #[error("election timeout range has to satisfy min < max")]
     InvalidElectionTimeoutRange,
+
+    #[error("number of entries or RPCs in flight has to be positive")]
+    ZeroFlowControlLimit,
+
+    #[error("lease read requires PreVote")]
+    LeaseReadWithoutPreVote,
+
+    #[error("max clock drift has to be less than the minimum election timeout")]
+    ClockDriftTooLarge,
 }
@@ -2,26 +2,39 @@ pub mod config;
 pub mod rpc;
 pub mod storage;
 
+mod apply;
 mod server;
+#[cfg(test)]
+mod simulation;
 
+use bytes::Bytes;
 use config::RaftConfig;
 use futures::Future;
-use rpc::{AppendEntries, AppendEntriesResponse, RequestVote, RequestVoteResponse, Transport};
+use rpc::{
+    AppendEntries, AppendEntriesResponse, InstallSnapshot, InstallSnapshotResponse, PreVote,
+    PreVoteResponse, ReadIndex, ReadIndexResponse, RequestVote, RequestVoteResponse, TimeoutNow,
+    TimeoutNowResponse, Transport,
+};
 use serde::{Deserialize, Serialize};
 use server::{Message, Server};
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc, time::Duration};
 use storage::Storage;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::Instant,
+};
 
 #[derive(Clone)]
 pub struct Raft<C: Command> {
     tx: mpsc::UnboundedSender<Message<C>>,
+    events: broadcast::Sender<Event>,
 }
 
 impl<C: Command> Raft<C> {
     pub fn new<M, S, T>(
         id: NodeId,
         nodes: Vec<NodeId>,
+        learners: Vec<NodeId>,
         config: RaftConfig,
         state_machine: M,
         storage: S,
@@ -33,13 +46,19 @@ impl<C: Command> Raft<C> {
         T: Transport<Command = C>,
     {
         let (tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            Server::new(id, nodes, config, state_machine, storage, transport, rx)
-                .await
-                .run()
-                .await;
-        });
-        Self { tx }
+        let membership = Membership::new(nodes, learners);
+        let mut server = Server::new(
+            id,
+            membership,
+            config,
+            state_machine,
+            storage,
+            transport,
+            rx,
+        );
+        let events = server.events().clone();
+        tokio::spawn(async move { server.run().await });
+        Self { tx, events }
     }
 
     pub async fn write(&self, command: C) -> Result<C::Output, RaftError> {
@@ -50,11 +69,68 @@ impl<C: Command> Raft<C> {
         rx.await.map_err(|_| RaftError::Shutdown)?
     }
 
+    /// Waits until it is safe to serve a linearizable read from the local
+    /// state machine.
+    ///
+    /// If follower reads are enabled, a follower asks the leader for its
+    /// commit index and waits until it applies the entries up to that
+    /// index. Otherwise, only the leader can serve reads.
     pub async fn read(&self) -> Result<(), RaftError> {
         let (tx, rx) = oneshot::channel();
         self.tx
             .send(Message::Read(tx))
             .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?.map(|_| ())
+    }
+
+    /// Adds a node to the cluster, or promotes a learner to a voting member.
+    ///
+    /// The node is added as a voting member as soon as the change is
+    /// appended to the leader's log. Only one membership change can be in
+    /// progress at a time.
+    pub async fn add_node(&self, node_id: NodeId) -> Result<(), RaftError> {
+        self.change_membership(MembershipChange::AddNode(node_id))
+            .await
+    }
+
+    /// Adds a learner to the cluster.
+    ///
+    /// Learners receive the replicated log, but they don't vote and are not
+    /// counted when committing entries.
+    pub async fn add_learner(&self, node_id: NodeId) -> Result<(), RaftError> {
+        self.change_membership(MembershipChange::AddLearner(node_id))
+            .await
+    }
+
+    /// Removes a node from the cluster.
+    ///
+    /// If the leader removes itself, it steps down once the change is
+    /// committed.
+    pub async fn remove_node(&self, node_id: NodeId) -> Result<(), RaftError> {
+        self.change_membership(MembershipChange::RemoveNode(node_id))
+            .await
+    }
+
+    /// Transfers leadership to `target`, or to the most up-to-date node if
+    /// `target` is `None`.
+    ///
+    /// The leader stops accepting writes, brings the target's log up to
+    /// date, and then tells the target to start an election immediately.
+    /// Returns once the target is known to have become the leader. Fails
+    /// with `NotLeader` if another node became the leader instead.
+    pub async fn transfer_leadership(&self, target: Option<NodeId>) -> Result<(), RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::TransferLeadership(target, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    async fn change_membership(&self, change: MembershipChange) -> Result<(), RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::ChangeMembership(change, tx))
+            .map_err(|_| RaftError::Shutdown)?;
         rx.await.map_err(|_| RaftError::Shutdown)?
     }
 
@@ -66,7 +142,7 @@ impl<C: Command> Raft<C> {
         self.tx
             .send(Message::AppendEntries(request, tx))
             .map_err(|_| RaftError::Shutdown)?;
-        rx.await.map_err(|_| RaftError::Shutdown)
+        rx.await.map_err(|_| RaftError::Shutdown)?
     }
 
     pub async fn request_vote(
@@ -77,7 +153,65 @@ impl<C: Command> Raft<C> {
         self.tx
             .send(Message::RequestVote(request, tx))
             .map_err(|_| RaftError::Shutdown)?;
-        rx.await.map_err(|_| RaftError::Shutdown)
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    pub async fn pre_vote(&self, request: PreVote) -> Result<PreVoteResponse, RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::PreVote(request, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    pub async fn install_snapshot(
+        &self,
+        request: InstallSnapshot,
+    ) -> Result<InstallSnapshotResponse, RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::InstallSnapshot(request, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    pub async fn timeout_now(&self, request: TimeoutNow) -> Result<TimeoutNowResponse, RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::TimeoutNow(request, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    pub async fn read_index(&self, request: ReadIndex) -> Result<ReadIndexResponse, RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::ReadIndex(request, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    /// Stops the Raft server after making its log durable.
+    ///
+    /// If `snapshot` is true, a snapshot of the state machine is taken
+    /// first so that the node doesn't have to replay the log when it
+    /// restarts. Pending requests and the requests made afterwards fail with
+    /// `RaftError::Shutdown`.
+    pub async fn shutdown(&self, snapshot: bool) -> Result<(), RaftError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Message::Shutdown(snapshot, tx))
+            .map_err(|_| RaftError::Shutdown)?;
+        rx.await.map_err(|_| RaftError::Shutdown)?
+    }
+
+    /// Subscribes to the changes in the state of this node.
+    ///
+    /// Only the changes made after the subscription are received. A
+    /// subscriber that falls too far behind misses the oldest events, and
+    /// should call `status` to catch up.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
     }
 
     pub async fn status(&self) -> Result<Status, RaftError> {
@@ -94,11 +228,71 @@ pub struct Status {
     pub state: State,
     pub node_id: NodeId,
     pub leader_id: Option<NodeId>,
+    pub term: u64,
+
+    /// index of highest log entry known to be committed
+    pub commit_index: u64,
+
+    /// index of highest log entry applied to state machine
+    pub last_applied_index: u64,
+
+    /// index of the last entry in the log
+    pub last_log_index: u64,
+
+    /// number of entries in the log, excluding the entries replaced by the
+    /// snapshot
+    pub num_log_entries: usize,
+
+    pub nodes: Vec<NodeId>,
+    pub learners: Vec<NodeId>,
+
+    /// replication progress of the other members, only tracked by the
+    /// leader
+    pub peers: BTreeMap<NodeId, PeerStatus>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub role: Role,
+
+    /// index of the next log entry to send to the peer
+    pub next_index: u64,
+
+    /// index of highest log entry known to be replicated on the peer
+    pub match_index: u64,
+
+    /// time since the peer last responded to AppendEntries or
+    /// InstallSnapshot, or `None` if it hasn't responded in this term
+    pub since_last_ack: Option<Duration>,
+
+    /// number of AppendEntries and InstallSnapshot RPCs to the peer that
+    /// failed in this term
+    pub num_failed_rpcs: u64,
+}
+
+/// Change in the state of a node, reported to the subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The node saw a newer term.
+    TermChanged { term: u64 },
+
+    /// The node became a follower, a candidate or the leader.
+    StateChanged { state: State, term: u64 },
+
+    /// The node learned of a new leader, or lost track of the leader.
+    LeaderChanged {
+        leader_id: Option<NodeId>,
+        term: u64,
+    },
+
+    /// The entries up to `index` were committed.
+    Committed { index: u64 },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     Follower,
+    PreCandidate,
     Candidate,
     Leader,
 }
@@ -119,6 +313,8 @@ impl From<NodeId> for u64 {
 }
 
 struct Node {
+    role: Role,
+
     // Volatile state on leaders:
     /// index of the next log entry to send to that server
     next_index: u64,
@@ -128,15 +324,43 @@ struct Node {
 
     match_message_index: u64,
     voted_for_me: bool,
+
+    /// offset of the next snapshot chunk to send to that server
+    snapshot_offset: u64,
+
+    /// true if the server responded since the last quorum check
+    recent_active: bool,
+
+    /// number of AppendEntries and InstallSnapshot RPCs sent to the server
+    /// that haven't been responded to
+    num_in_flight: usize,
+
+    /// last time an AppendEntries or InstallSnapshot RPC was sent to the
+    /// server
+    last_sent_at: Option<Instant>,
+
+    /// last time the server responded to AppendEntries or InstallSnapshot
+    last_ack_at: Option<Instant>,
+
+    /// number of AppendEntries and InstallSnapshot RPCs to the server that
+    /// failed
+    num_failed_rpcs: u64,
 }
 
 impl Default for Node {
     fn default() -> Self {
         Self {
+            role: Role::Voter,
             next_index: 1,
             match_index: 0,
             match_message_index: 0,
             voted_for_me: false,
+            snapshot_offset: 0,
+            recent_active: false,
+            num_in_flight: 0,
+            last_sent_at: None,
+            last_ack_at: None,
+            num_failed_rpcs: 0,
         }
     }
 }
@@ -151,15 +375,63 @@ impl Node {
 pub struct Entry<C> {
     kind: EntryKind<C>,
     term: u64,
+
+    /// wall-clock time of the leader when the entry was appended
+    timestamp: u64,
+
+    /// random number chosen by the leader
+    seed: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum EntryKind<C> {
     NoOp,
     Command(C),
+    Membership(Membership),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// votes in elections and is counted when committing entries
+    Voter,
+
+    /// only receives the replicated log
+    Learner,
+}
+
+/// Members of the cluster.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    nodes: BTreeMap<NodeId, Role>,
+}
+
+impl Membership {
+    fn new(
+        voters: impl IntoIterator<Item = NodeId>,
+        learners: impl IntoIterator<Item = NodeId>,
+    ) -> Self {
+        let voters = voters.into_iter().map(|node_id| (node_id, Role::Voter));
+        let learners = learners.into_iter().map(|node_id| (node_id, Role::Learner));
+        Self {
+            nodes: learners.chain(voters).collect(),
+        }
+    }
+
+    fn num_voters(&self) -> usize {
+        self.nodes
+            .values()
+            .filter(|role| **role == Role::Voter)
+            .count()
+    }
+}
+
+enum MembershipChange {
+    AddNode(NodeId),
+    AddLearner(NodeId),
+    RemoveNode(NodeId),
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Metadata {
     /// latest term server has seen
     current_term: u64,
@@ -168,6 +440,21 @@ pub struct Metadata {
     voted_for: Option<NodeId>,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotMetadata {
+    /// the snapshot replaces all entries up through and including this index
+    last_included_index: u64,
+
+    /// term of lastIncludedIndex
+    last_included_term: u64,
+
+    /// latest membership as of lastIncludedIndex
+    membership: Membership,
+
+    /// size of the snapshot data in bytes
+    size: u64,
+}
+
 #[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize)]
 pub enum RaftError {
     #[error("Raft server is shut down")]
@@ -175,6 +462,21 @@ pub enum RaftError {
 
     #[error("this is not a leader node")]
     NotLeader { leader_id: Option<NodeId> },
+
+    #[error("another membership change is in progress")]
+    MembershipChangeInProgress,
+
+    #[error("cluster must have at least one node")]
+    EmptyMembership,
+
+    #[error("no node to transfer leadership to")]
+    NoTransferTarget,
+
+    #[error("leadership transfer timed out")]
+    LeadershipTransferTimeout,
+
+    #[error("storage error: {0}")]
+    Storage(String),
 }
 
 pub type RaftResult<T> = Result<T, RaftError>;
@@ -183,11 +485,46 @@ pub trait Command: Send + Sync + Clone + 'static {
     type Output: Send;
 }
 
+/// Information about the entry being applied.
+///
+/// The context is chosen by the leader and replicated with the entry, so
+/// commands that depend on time or randomness give identical results on
+/// every node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApplyContext {
+    /// index of the entry in the log
+    pub index: u64,
+
+    /// term of the entry
+    pub term: u64,
+
+    /// wall-clock time of the leader when the entry was appended, in
+    /// milliseconds since the UNIX epoch. Never decreases along the log.
+    pub timestamp: u64,
+
+    /// random number chosen by the leader, to seed random number generators
+    pub seed: u64,
+}
+
 pub trait StateMachine: Send + Sync + 'static {
     type Command: Command;
+    type Error: Send + std::fmt::Debug;
 
-    fn apply(
+    /// Applies the committed commands in order, and returns their outputs.
+    ///
+    /// The commands are applied in a task separate from the Raft server, so
+    /// this doesn't delay heartbeats even if the batch is large.
+    fn apply_batch(
         &mut self,
-        command: Self::Command,
-    ) -> impl Future<Output = <Self::Command as Command>::Output> + Send;
+        commands: Vec<(ApplyContext, Self::Command)>,
+    ) -> impl Future<Output = Vec<<Self::Command as Command>::Output>> + Send;
+
+    /// Serializes the current state of the state machine.
+    fn snapshot(&self) -> impl Future<Output = Bytes> + Send;
+
+    /// Replaces the state of the state machine with the given snapshot.
+    ///
+    /// If the snapshot can't be restored, the node is taken out of the
+    /// cluster in the same way as when the storage fails.
+    fn restore(&mut self, snapshot: Bytes) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
@@ -1,4 +1,5 @@
-use crate::{Command, Entry, NodeId};
+use crate::{Command, Entry, NodeId, SnapshotMetadata};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 pub trait Transport: Send + Sync + 'static {
@@ -16,6 +17,31 @@ pub trait Transport: Send + Sync + 'static {
         dest: NodeId,
         request: RequestVote,
     ) -> impl std::future::Future<Output = Result<RequestVoteResponse, Self::Error>> + std::marker::Send;
+
+    fn send_pre_vote(
+        &self,
+        dest: NodeId,
+        request: PreVote,
+    ) -> impl std::future::Future<Output = Result<PreVoteResponse, Self::Error>> + std::marker::Send;
+
+    fn send_install_snapshot(
+        &self,
+        dest: NodeId,
+        request: InstallSnapshot,
+    ) -> impl std::future::Future<Output = Result<InstallSnapshotResponse, Self::Error>>
+           + std::marker::Send;
+
+    fn send_timeout_now(
+        &self,
+        dest: NodeId,
+        request: TimeoutNow,
+    ) -> impl std::future::Future<Output = Result<TimeoutNowResponse, Self::Error>> + std::marker::Send;
+
+    fn send_read_index(
+        &self,
+        dest: NodeId,
+        request: ReadIndex,
+    ) -> impl std::future::Future<Output = Result<ReadIndexResponse, Self::Error>> + std::marker::Send;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +76,14 @@ pub struct AppendEntriesResponse {
 
     pub(crate) message_index: u64,
     pub(crate) current_index: u64,
+
+    /// term of the follower's entry at prevLogIndex if it doesn't match
+    /// prevLogTerm
+    pub(crate) conflict_term: Option<u64>,
+
+    /// first index of conflictTerm in the follower's log, or the index
+    /// following its last entry if the log doesn't contain prevLogIndex
+    pub(crate) conflict_index: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,3 +109,90 @@ pub struct RequestVoteResponse {
     /// true means candidate received vote
     pub(crate) vote_granted: bool,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreVote {
+    /// term the candidate would use in the next election
+    pub(crate) term: u64,
+
+    /// candidate requesting pre-vote
+    pub(crate) candidate_id: NodeId,
+
+    /// index of candidate's last log entry
+    pub(crate) last_log_index: u64,
+
+    /// term of candidate's last log entry
+    pub(crate) last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreVoteResponse {
+    /// requested term if granted, currentTerm otherwise
+    pub(crate) term: u64,
+
+    /// true means candidate would receive vote
+    pub(crate) vote_granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshot {
+    /// leader's term
+    pub(crate) term: u64,
+
+    pub(crate) leader_id: NodeId,
+
+    pub(crate) metadata: SnapshotMetadata,
+
+    /// byte offset where chunk is positioned in the snapshot file
+    pub(crate) offset: u64,
+
+    /// raw bytes of the snapshot chunk, starting at offset
+    pub(crate) data: Bytes,
+
+    /// true if this is the last chunk
+    pub(crate) done: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstallSnapshotResponse {
+    /// currentTerm, for leader to update itself
+    pub(crate) term: u64,
+
+    pub(crate) metadata: SnapshotMetadata,
+
+    /// offset of the next chunk the follower expects
+    pub(crate) next_offset: u64,
+}
+
+/// Sent by the leader to transfer leadership to the receiver.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutNow {
+    /// leader's term
+    pub(crate) term: u64,
+
+    pub(crate) leader_id: NodeId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeoutNowResponse {
+    /// currentTerm, for leader to update itself
+    pub(crate) term: u64,
+}
+
+/// Sent by a follower to learn the index up to which it has to apply
+/// entries before serving a linearizable read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadIndex {
+    /// follower's term
+    pub(crate) term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadIndexResponse {
+    /// currentTerm, for follower to update itself
+    pub(crate) term: u64,
+
+    /// index of the latest committed entry, or None if the receiver
+    /// couldn't confirm that it is the leader
+    pub(crate) read_index: Option<u64>,
+}
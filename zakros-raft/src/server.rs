@@ -1,36 +1,73 @@
 use crate::{
-    rpc::{AppendEntries, AppendEntriesResponse, RequestVote, RequestVoteResponse, Transport},
+    apply::Applier,
+    rpc::{
+        AppendEntries, AppendEntriesResponse, InstallSnapshot, InstallSnapshotResponse, PreVote,
+        PreVoteResponse, ReadIndex, ReadIndexResponse, RequestVote, RequestVoteResponse,
+        TimeoutNow, TimeoutNowResponse, Transport,
+    },
     storage::{Storage, StorageExt},
-    Command, Entry, EntryKind, Metadata, Node, NodeId, RaftConfig, RaftError, State, StateMachine,
-    Status,
+    ApplyContext, Command, Entry, EntryKind, Event, Membership, MembershipChange, Metadata, Node,
+    NodeId, PeerStatus, RaftConfig, RaftError, RaftResult, Role, SnapshotMetadata, State,
+    StateMachine, Status,
 };
-use futures::{stream::FuturesUnordered, StreamExt};
+use bytes::{Bytes, BytesMut};
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::{
     collections::{BTreeMap, VecDeque},
     sync::Arc,
+    time::SystemTime,
 };
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::JoinHandle,
     time::Instant,
 };
 
-// TODO: to improve performance, do not immediately flush after receiving
-//       client requests and do more batching
-// TODO: implement PreVote and CheckQuorum optimizations
-// TODO: support dynamic cluster membership
+/// maximum size of the snapshot data sent in a single InstallSnapshot RPC
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
 
-pub struct Server<C, M, S, T>
+/// maximum number of client writes appended to the log at once
+const MAX_WRITE_BATCH_SIZE: usize = 1024;
+
+/// maximum number of commands handed over to the state machine at once
+const MAX_APPLY_BATCH_SIZE: usize = 1024;
+
+/// number of events buffered for each subscriber before it starts missing
+/// events
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Failure that takes the node out of the cluster
+#[derive(Debug)]
+enum ServerError<E> {
+    Storage(E),
+
+    /// The state machine couldn't restore a snapshot.
+    Restore(String),
+
+    /// An entry that must be in the log is missing from the storage.
+    MissingEntry(u64),
+}
+
+impl<E> From<E> for ServerError<E> {
+    fn from(err: E) -> Self {
+        Self::Storage(err)
+    }
+}
+
+pub struct Server<C, S, T>
 where
     C: Command,
+    S: Storage<Command = C>,
     T: Transport<Command = C>,
 {
     node_id: NodeId,
     config: RaftConfig,
 
-    state_machine: M,
+    applier: Applier<C>,
     storage: S,
     transport: Arc<T>,
+    rng: StdRng,
 
     // Persistent state on all servers:
     /// latest term server has seen
@@ -43,128 +80,532 @@ where
     /// index of highest log entry known to be committed
     commit_index: u64,
 
-    /// index of highest log entry applied to state machine
+    /// index of highest log entry applied to state machine. The commands
+    /// up to this index have been handed over to the applier, which may
+    /// still be applying them.
     last_applied_index: u64,
 
     last_applied_term: u64,
     last_message_index: u64,
 
+    /// timestamp of the latest entry in the log, as of the last entry
+    /// appended as the leader
+    last_timestamp: u64,
+
+    /// members of the latest membership in the log
     nodes: BTreeMap<NodeId, Node>,
 
+    /// index of the entry that defined the latest membership
+    membership_index: u64,
+
+    /// latest membership as of lastApplied, and the index of the entry
+    /// that defined it
+    applied_membership: (u64, Membership),
+
     state: State,
     leader_id: Option<NodeId>,
     election_deadline: Instant,
 
+    /// last time we received AppendEntries from the current leader
+    last_leader_contact: Option<Instant>,
+
+    check_quorum_deadline: Instant,
+
+    /// leadership transfer in progress
+    leadership_transfer: Option<LeadershipTransfer>,
+
+    /// message index and send time of AppendEntries RPCs not yet
+    /// acknowledged by a quorum
+    append_entries_sent_at: VecDeque<(u64, Instant)>,
+
+    /// the leader can serve reads without contacting a quorum until this
+    /// time
+    lease_deadline: Option<Instant>,
+
+    /// true if the leader gave up its lease for the rest of the term
+    lease_revoked: bool,
+
     rx: mpsc::UnboundedReceiver<Message<C>>,
 
+    /// message received while batching writes, handled after the writes
+    deferred_message: Option<Message<C>>,
+
+    events: broadcast::Sender<Event>,
+
+    /// state as of the last events sent to the subscribers
+    published: Published,
+
+    /// true once the server has been asked to shut down
+    is_shut_down: bool,
+
     pending_write_requests: VecDeque<WriteRequest<C::Output>>,
     pending_read_requests: VecDeque<ReadRequest>,
 
+    /// reads forwarded to the leader, waiting for lastApplied to reach
+    /// the read index
+    pending_forwarded_read_requests: Vec<ForwardedReadRequest>,
+    pending_membership_request: Option<WriteRequest<()>>,
+
+    /// snapshot being received from the leader
+    pending_snapshot: Option<PendingSnapshot>,
+
+    /// snapshot of the state machine being taken in the background
+    snapshot_task: Option<SnapshotTask<S::Error>>,
+
     pending_append_entries_responses:
         FuturesUnordered<JoinHandle<RpcResponse<AppendEntriesResponse, T::Error>>>,
     pending_request_vote_responses:
         FuturesUnordered<JoinHandle<RpcResponse<RequestVoteResponse, T::Error>>>,
+    pending_pre_vote_responses:
+        FuturesUnordered<JoinHandle<RpcResponse<PreVoteResponse, T::Error>>>,
+    pending_install_snapshot_responses:
+        FuturesUnordered<JoinHandle<RpcResponse<InstallSnapshotResponse, T::Error>>>,
+    pending_timeout_now_responses:
+        FuturesUnordered<JoinHandle<RpcResponse<TimeoutNowResponse, T::Error>>>,
+    pending_read_index_responses: FuturesUnordered<JoinHandle<ReadIndexRpcResponse<T::Error>>>,
 }
 
-impl<C, M, S, T> Server<C, M, S, T>
+impl<C, S, T> Server<C, S, T>
 where
     C: Command,
-    M: StateMachine<Command = C>,
     S: Storage<Command = C>,
     T: Transport<Command = C>,
 {
-    pub async fn new(
+    pub fn new<M: StateMachine<Command = C>>(
         id: NodeId,
-        nodes: Vec<NodeId>,
+        membership: Membership,
         config: RaftConfig,
         state_machine: M,
-        mut storage: S,
+        storage: S,
         transport: Arc<T>,
         rx: mpsc::UnboundedReceiver<Message<C>>,
     ) -> Self {
-        let election_deadline = config.random_election_deadline();
-        let Metadata {
-            current_term,
-            voted_for,
-        } = storage.load().await.unwrap();
-        tracing::info!("loaded {} entries", storage.num_entries());
+        let mut rng = StdRng::from_entropy();
+        let election_deadline = config.random_election_deadline(&mut rng);
         Self {
             node_id: id,
             config,
-            state_machine,
+            applier: Applier::spawn(state_machine),
             storage,
             transport,
-            current_term,
-            voted_for,
+            rng,
+            current_term: 0,
+            voted_for: None,
             commit_index: 0,
             last_applied_index: 0,
             last_applied_term: 0,
             last_message_index: 0,
-            nodes: nodes.into_iter().map(|id| (id, Node::new())).collect(),
+            last_timestamp: 0,
+            nodes: Default::default(),
+            membership_index: 0,
+            // Until the log contains a membership, the given membership is
+            // used.
+            applied_membership: (0, membership),
             state: State::Follower,
             leader_id: None,
             election_deadline,
+            last_leader_contact: None,
+            check_quorum_deadline: Instant::now(),
+            leadership_transfer: None,
+            append_entries_sent_at: Default::default(),
+            lease_deadline: None,
+            lease_revoked: false,
             rx,
+            deferred_message: None,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            published: Published {
+                state: State::Follower,
+                leader_id: None,
+                term: 0,
+                commit_index: 0,
+            },
+            is_shut_down: false,
             pending_write_requests: Default::default(),
             pending_read_requests: Default::default(),
+            pending_forwarded_read_requests: Default::default(),
+            pending_membership_request: None,
+            pending_snapshot: None,
+            snapshot_task: None,
             pending_append_entries_responses: Default::default(),
             pending_request_vote_responses: Default::default(),
+            pending_pre_vote_responses: Default::default(),
+            pending_install_snapshot_responses: Default::default(),
+            pending_timeout_now_responses: Default::default(),
+            pending_read_index_responses: Default::default(),
         }
     }
 
+    pub fn events(&self) -> &broadcast::Sender<Event> {
+        &self.events
+    }
+
+    /// Makes the election timeouts reproducible.
+    #[cfg(test)]
+    pub(crate) fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.election_deadline = self.config.random_election_deadline(&mut self.rng);
+    }
+
     pub async fn run(&mut self) {
+        if let Err(err) = self.serve().await {
+            self.fence(err).await;
+        }
+    }
+
+    /// Returns the entry at `index`, which must be in the log.
+    ///
+    /// A missing entry means the storage lost it, so it is treated as a
+    /// storage failure.
+    async fn entry(&mut self, index: u64) -> Result<Entry<C>, ServerError<S::Error>> {
+        self.storage
+            .entry(index)
+            .await?
+            .ok_or(ServerError::MissingEntry(index))
+    }
+
+    /// Restores the persisted state.
+    async fn load(&mut self) -> Result<(), ServerError<S::Error>> {
+        let Metadata {
+            current_term,
+            voted_for,
+        } = self.storage.load().await?;
+        self.current_term = current_term;
+        self.voted_for = voted_for;
+
+        let snapshot_metadata = self.storage.snapshot_metadata().clone();
+        if snapshot_metadata.last_included_index > 0 {
+            let data = self
+                .storage
+                .read_snapshot(0, snapshot_metadata.size.try_into().unwrap())
+                .await?;
+            self.applier
+                .restore(data, snapshot_metadata.last_included_index)
+                .await
+                .map_err(ServerError::Restore)?;
+            tracing::info!(
+                index = snapshot_metadata.last_included_index,
+                "restored snapshot"
+            );
+            self.commit_index = snapshot_metadata.last_included_index;
+            self.last_applied_index = snapshot_metadata.last_included_index;
+            self.last_applied_term = snapshot_metadata.last_included_term;
+            self.applied_membership = (
+                snapshot_metadata.last_included_index,
+                snapshot_metadata.membership,
+            );
+        }
+        tracing::info!("loaded {} entries", self.storage.num_entries());
+
+        let (index, membership) = match self.find_membership(self.last_applied_index).await? {
+            Some(membership) => membership,
+            None => self.applied_membership.clone(),
+        };
+        self.set_membership(index, membership);
+        self.publish_events();
+        Ok(())
+    }
+
+    /// Runs the server until the `Raft` handles are dropped or the storage
+    /// fails.
+    async fn serve(&mut self) -> Result<(), ServerError<S::Error>> {
+        self.load().await?;
+
         let mut heartbeat_timer = tokio::time::interval(self.config.heartbeat_interval);
         heartbeat_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-        if self.nodes.len() == 1 {
-            self.start_election().await;
+        if self.num_voters() == 1 && self.is_voter(self.node_id) {
+            self.start_election().await?;
         }
 
         loop {
             let election_timer = tokio::time::sleep_until(self.election_deadline);
+            let check_quorum_timer = tokio::time::sleep_until(self.check_quorum_deadline);
+            let leadership_transfer_timer = tokio::time::sleep_until(
+                self.leadership_transfer
+                    .as_ref()
+                    .map_or_else(Instant::now, |transfer| transfer.deadline),
+            );
+            let forwarded_read_timer = tokio::time::sleep_until(
+                self.pending_forwarded_read_requests
+                    .first()
+                    .map_or_else(Instant::now, |request| request.deadline),
+            );
+            // The branches are polled in order, rather than randomly, so
+            // that runs with the same inputs are reproducible. Timers come
+            // first because they fire rarely, and RPC responses come before
+            // client requests because they are bounded by the in-flight
+            // windows.
             tokio::select! {
+                biased;
+
                 _ = heartbeat_timer.tick(), if self.state == State::Leader => {
-                    self.send_append_entries_to_all().await
+                    self.send_append_entries_to_all().await?
                 }
-                _ = election_timer, if matches!(self.state, State::Follower | State::Candidate) => {
+                _ = election_timer, if self.state != State::Leader && self.is_voter(self.node_id) => {
                     // If election timeout elapses: start new election
-                    self.start_election().await
+                    self.start_election().await?
+                }
+                _ = check_quorum_timer, if self.state == State::Leader && self.config.check_quorum => {
+                    self.check_quorum()
+                }
+                _ = leadership_transfer_timer, if self.leadership_transfer.is_some() => {
+                    self.abort_leadership_transfer()
+                }
+                _ = forwarded_read_timer, if !self.pending_forwarded_read_requests.is_empty() => {
+                    self.expire_forwarded_reads()
                 }
-                maybe_message = self.rx.recv() => match maybe_message {
-                    Some(message) => self.handle_message(message).await,
-                    None => break,
-                },
                 Some(response) = self.pending_append_entries_responses.next() => {
-                    self.handle_append_entries_response(response.unwrap()).await
+                    self.handle_append_entries_response(response.unwrap()).await?
                 }
                 Some(response) = self.pending_request_vote_responses.next() => {
-                    self.handle_request_vote_response(response.unwrap()).await
+                    self.handle_request_vote_response(response.unwrap()).await?
+                }
+                Some(response) = self.pending_pre_vote_responses.next() => {
+                    self.handle_pre_vote_response(response.unwrap()).await?
+                }
+                Some(response) = self.pending_install_snapshot_responses.next() => {
+                    self.handle_install_snapshot_response(response.unwrap()).await?
+                }
+                Some(response) = self.pending_timeout_now_responses.next() => {
+                    self.handle_timeout_now_response(response.unwrap()).await?
+                }
+                Some(response) = self.pending_read_index_responses.next() => {
+                    self.handle_read_index_response(response.unwrap()).await?
+                }
+                progress = snapshot_progress(&mut self.snapshot_task) => {
+                    self.handle_snapshot_progress(progress).await?
+                }
+                maybe_message = self.rx.recv() => match maybe_message {
+                    Some(Message::Write(command, tx)) => {
+                        self.handle_write_batch(command, tx).await?
+                    }
+                    Some(message) => self.handle_message(message).await?,
+                    None => return Ok(()),
+                },
+            }
+            self.publish_events();
+            if self.is_shut_down {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Takes the node out of the cluster after the storage failed.
+    ///
+    /// The persisted state may no longer match the in-memory state, so
+    /// the node stops voting and accepting entries until it is restarted.
+    /// Requests are answered with `RaftError::Storage` in the meantime.
+    async fn fence(&mut self, err: ServerError<S::Error>) {
+        let error = match err {
+            ServerError::Storage(err) => format!("{err:?}"),
+            ServerError::Restore(err) => format!("failed to restore snapshot: {err}"),
+            ServerError::MissingEntry(index) => format!("entry {index} is missing from the log"),
+        };
+        tracing::error!(
+            term = self.current_term,
+            "fencing the node: storage failed: {}",
+            error
+        );
+        let error = RaftError::Storage(error);
+
+        if let Some(transfer) = self.leadership_transfer.take() {
+            let _ = transfer.tx.send(Err(error.clone()));
+        }
+        if self.state != State::Follower {
+            self.become_follower();
+        }
+        self.leader_id = None;
+        self.publish_events();
+
+        self.fail_pending_requests(&error);
+
+        loop {
+            let message = match self.deferred_message.take() {
+                Some(message) => message,
+                None => match self.rx.recv().await {
+                    Some(message) => message,
+                    None => return,
+                },
+            };
+            match message {
+                Message::AppendEntries(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::RequestVote(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::PreVote(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::InstallSnapshot(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::TimeoutNow(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::ReadIndex(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::Write(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::Read(tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::ChangeMembership(_, tx) | Message::TransferLeadership(_, tx) => {
+                    let _ = tx.send(Err(error.clone()));
+                }
+                Message::Status(tx) => {
+                    let _ = tx.send(self.status());
+                }
+                Message::Shutdown(_, tx) => {
+                    let _ = tx.send(Ok(()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Fails the requests waiting for entries to be committed or applied,
+    /// and the reads forwarded to the leader.
+    fn fail_pending_requests(&mut self, error: &RaftError) {
+        for request in self.pending_write_requests.drain(..) {
+            let _ = request.tx.send(Err(error.clone()));
+        }
+        for request in self.pending_read_requests.drain(..) {
+            let _ = request.tx.send(Err(error.clone()));
+        }
+        for request in self.pending_forwarded_read_requests.drain(..) {
+            let _ = request.tx.send(Err(error.clone()));
+        }
+        if let Some(request) = self.pending_membership_request.take() {
+            let _ = request.tx.send(Err(error.clone()));
+        }
+        let mut pending_read_index_responses =
+            std::mem::take(&mut self.pending_read_index_responses);
+        let error = error.clone();
+        tokio::spawn(async move {
+            while let Some(response) = pending_read_index_responses.next().await {
+                if let Ok(response) = response {
+                    let _ = response.tx.send(Err(error.clone()));
                 }
             }
+        });
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            state: self.state,
+            node_id: self.node_id,
+            leader_id: self.leader_id,
+            term: self.current_term,
+            commit_index: self.commit_index,
+            last_applied_index: self.applier.applied_index(),
+            last_log_index: self.storage.current_index(),
+            num_log_entries: self.storage.num_entries(),
+            nodes: self.node_ids(Role::Voter),
+            learners: self.node_ids(Role::Learner),
+            peers: self.peer_statuses(),
+        }
+    }
+
+    /// Sends events for the changes since the last call.
+    fn publish_events(&mut self) {
+        let current = Published {
+            state: self.state,
+            leader_id: self.leader_id,
+            term: self.current_term,
+            commit_index: self.commit_index,
+        };
+        let published = std::mem::replace(&mut self.published, current);
+        if current == published {
+            return;
+        }
+        let term = current.term;
+        if term != published.term {
+            let _ = self.events.send(Event::TermChanged { term });
+        }
+        if current.state != published.state {
+            let _ = self.events.send(Event::StateChanged {
+                state: current.state,
+                term,
+            });
+        }
+        if current.leader_id != published.leader_id {
+            let _ = self.events.send(Event::LeaderChanged {
+                leader_id: current.leader_id,
+                term,
+            });
+        }
+        if current.commit_index > published.commit_index {
+            let _ = self.events.send(Event::Committed {
+                index: current.commit_index,
+            });
+        }
+    }
+
+    fn peer_statuses(&self) -> BTreeMap<NodeId, PeerStatus> {
+        if self.state != State::Leader {
+            return BTreeMap::new();
         }
+        let now = Instant::now();
+        self.nodes
+            .iter()
+            .filter(|(node_id, _)| **node_id != self.node_id)
+            .map(|(node_id, node)| {
+                let status = PeerStatus {
+                    role: node.role,
+                    next_index: node.next_index,
+                    match_index: node.match_index,
+                    since_last_ack: node.last_ack_at.map(|at| now - at),
+                    num_failed_rpcs: node.num_failed_rpcs,
+                };
+                (*node_id, status)
+            })
+            .collect()
     }
 
-    async fn handle_message(&mut self, message: Message<C>) {
+    async fn handle_message(&mut self, message: Message<C>) -> Result<(), ServerError<S::Error>> {
         match message {
             Message::AppendEntries(request, tx) => {
-                let _ = tx.send(self.handle_append_entries(request).await);
+                let _ = tx.send(Ok(self.handle_append_entries(request).await?));
             }
             Message::RequestVote(request, tx) => {
-                let _ = tx.send(self.handle_request_vote(request).await);
+                let _ = tx.send(Ok(self.handle_request_vote(request).await?));
+            }
+            Message::PreVote(request, tx) => {
+                let _ = tx.send(Ok(self.handle_pre_vote(request).await?));
+            }
+            Message::InstallSnapshot(request, tx) => {
+                let _ = tx.send(Ok(self.handle_install_snapshot(request).await?));
+            }
+            Message::TimeoutNow(request, tx) => {
+                let _ = tx.send(Ok(self.handle_timeout_now(request).await?));
+            }
+            Message::ReadIndex(request, tx) => self.handle_read_index(request, tx).await?,
+            Message::Write(command, tx) => self.handle_writes(vec![(command, tx)]).await?,
+            Message::Read(tx) => self.handle_read(tx).await?,
+            Message::ChangeMembership(change, tx) => {
+                self.handle_change_membership(change, tx).await?
+            }
+            Message::TransferLeadership(target, tx) => {
+                self.handle_transfer_leadership(target, tx).await?
             }
-            Message::Write(command, tx) => self.handle_write(command, tx).await,
-            Message::Read(tx) => self.handle_read(tx).await,
             Message::Status(tx) => {
-                let _ = tx.send(Status {
-                    state: self.state,
-                    node_id: self.node_id,
-                    leader_id: self.leader_id,
-                });
+                let _ = tx.send(self.status());
+            }
+            Message::Shutdown(snapshot, tx) => {
+                self.handle_shutdown(snapshot).await?;
+                let _ = tx.send(Ok(()));
             }
         }
+        Ok(())
     }
 
-    async fn handle_append_entries(&mut self, request: AppendEntries<C>) -> AppendEntriesResponse {
+    async fn handle_append_entries(
+        &mut self,
+        request: AppendEntries<C>,
+    ) -> Result<AppendEntriesResponse, ServerError<S::Error>> {
         tracing::trace!("received AppendEntries");
 
         let AppendEntries {
@@ -181,16 +622,18 @@ where
         // 1. Reply false if term < currentTerm (S5.1)
         if term < self.current_term {
             tracing::trace!("replying to AppendEntries with false: term < currentTerm");
-            return AppendEntriesResponse {
+            return Ok(AppendEntriesResponse {
                 term: self.current_term,
                 current_index,
                 success: false,
                 message_index,
-            };
+                conflict_term: None,
+                conflict_index: current_index + 1,
+            });
         }
 
         if term > self.current_term {
-            self.update_current_term(term).await;
+            self.update_current_term(term).await?;
         }
 
         self.reset_election_timer();
@@ -198,31 +641,43 @@ where
             self.become_follower();
         }
         self.leader_id = Some(leader_id);
+        self.last_leader_contact = Some(Instant::now());
+        self.complete_leadership_transfer(leader_id);
 
-        if prev_log_index > 0 {
+        let snapshot_index = self.storage.snapshot_metadata().last_included_index;
+        if prev_log_index > snapshot_index {
             // 2. Reply false if log doesn't contain an entry at prevLogIndex
             // whose term matches prevLogTerm (S5.3)
-            match self.storage.entry(prev_log_index).await.unwrap() {
+            match self.storage.entry(prev_log_index).await? {
                 Some(entry) if entry.term != prev_log_term => {
-                    self.truncate_log(prev_log_index).await;
+                    // Tell the leader where the conflicting term starts, so
+                    // that it can skip all the entries in the term at once
+                    let conflict_index = self
+                        .storage
+                        .first_index_after_term(entry.term.saturating_sub(1), snapshot_index + 1)
+                        .await?;
                     tracing::trace!(
                         "replying to AppendEntries with false: log[prevLogIndex].term != prevLogTerm"
                     );
-                    return AppendEntriesResponse {
+                    return Ok(AppendEntriesResponse {
                         term: self.current_term,
                         current_index,
                         success: false,
                         message_index,
-                    };
+                        conflict_term: Some(entry.term),
+                        conflict_index,
+                    });
                 }
                 None => {
                     tracing::trace!("replying AppendEntries with false: no log[prevLogIndex]");
-                    return AppendEntriesResponse {
+                    return Ok(AppendEntriesResponse {
                         term: self.current_term,
                         current_index,
                         success: false,
                         message_index,
-                    };
+                        conflict_term: None,
+                        conflict_index: current_index + 1,
+                    });
                 }
                 _ => (),
             }
@@ -234,11 +689,17 @@ where
         let mut num_matching = 0;
         for (i, new_entry) in entries.iter().enumerate() {
             let index_in_log = prev_log_index + TryInto::<u64>::try_into(i).unwrap() + 1;
-            let Some(existing_entry) = self.storage.entry(index_in_log).await.unwrap() else {
+            if index_in_log <= snapshot_index {
+                // Entries in the snapshot are committed, so they never
+                // conflict with the leader's entries.
+                num_matching += 1;
+                continue;
+            }
+            let Some(existing_entry) = self.storage.entry(index_in_log).await? else {
                 break;
             };
             if existing_entry.term != new_entry.term {
-                self.truncate_log(index_in_log).await;
+                self.truncate_log(index_in_log).await?;
                 break;
             }
             num_matching += 1;
@@ -246,79 +707,141 @@ where
 
         // 4. Append any new entries not already in the log
         if num_matching < entries.len() {
-            self.storage
-                .append_entries(&entries[num_matching..])
-                .await
-                .unwrap();
+            let new_entries = &entries[num_matching..];
+            let first_index = self.storage.current_index() + 1;
+            self.storage.append_entries(new_entries).await?;
+            self.storage.persist_entries().await?;
+
+            // A server always uses the latest membership in its log,
+            // regardless of whether the entry is committed.
+            for (index, entry) in (first_index..).zip(new_entries) {
+                if let EntryKind::Membership(membership) = &entry.kind {
+                    self.set_membership(index, membership.clone());
+                }
+            }
         }
 
         // 5. If leaderCommit > commitIndex, set commitIndex =
         // min(leaderCommit, index of last new entry)
+        //
+        // Entries after the last new entry may not match the leader's log,
+        // e.g. when a heartbeat only covers the entries known to be
+        // replicated.
+        let last_new_index = prev_log_index + TryInto::<u64>::try_into(entries.len()).unwrap();
         if leader_commit > self.commit_index {
-            self.commit_index = self.storage.current_index().clamp(1, leader_commit);
+            self.commit_index = self.commit_index.max(last_new_index.min(leader_commit));
         }
 
-        self.exec_operations().await;
+        self.exec_operations().await?;
 
         tracing::trace!("acknowledging AppendEntries");
-        AppendEntriesResponse {
+        Ok(AppendEntriesResponse {
             term: self.current_term,
-            current_index: prev_log_index + TryInto::<u64>::try_into(entries.len()).unwrap(),
+            current_index: last_new_index,
             success: true,
             message_index,
-        }
+            conflict_term: None,
+            conflict_index: 0,
+        })
     }
 
     async fn handle_append_entries_response(
         &mut self,
         response: RpcResponse<AppendEntriesResponse, T::Error>,
-    ) {
+    ) -> Result<(), ServerError<S::Error>> {
         let RpcResponse { node_id, result } = response;
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            node.num_in_flight = node.num_in_flight.saturating_sub(1);
+        }
         let response = match result {
             Ok(response) => response,
             Err(err) => {
                 tracing::trace!("AppendEntries request to {:?} failed: {:?}", node_id, err);
-                return;
+                if let Some(node) = self.nodes.get_mut(&node_id) {
+                    node.num_failed_rpcs += 1;
+                }
+                return Ok(());
             }
         };
         tracing::trace!("received AppendEntries reply from {:?}", node_id);
 
         if self.state != State::Leader {
-            return;
+            return Ok(());
         }
 
         // If RPC request or response contains term T > currentTerm:
         // set currentTerm = T, convert to follower (S5.1)
         if response.term > self.current_term {
-            self.update_current_term(response.term).await;
+            self.update_current_term(response.term).await?;
             self.become_follower();
-            return;
+            return Ok(());
         }
 
-        let node = self.nodes.get_mut(&node_id).unwrap();
+        let Some(node) = self.nodes.get_mut(&node_id) else {
+            // The node has been removed from the cluster
+            return Ok(());
+        };
+        node.recent_active = true;
+        node.last_ack_at = Some(Instant::now());
         if !response.success {
             if response.current_index < node.match_index {
-                return;
+                return Ok(());
             }
-            node.next_index = self
-                .storage
-                .current_index()
-                .clamp(1, response.current_index + 1);
-            let fut = self.spawn_append_entries_task(node_id).await;
-            self.pending_append_entries_responses.push(fut);
-            return;
+
+            // If AppendEntries fails because of log inconsistency:
+            // decrement nextIndex and retry (S5.3)
+            let next_index = self.next_index_after_conflict(&response).await?;
+            let current_index = self.storage.current_index();
+            if let Some(node) = self.nodes.get_mut(&node_id) {
+                node.next_index = next_index.clamp(1, current_index.max(1));
+            }
+            self.send_append_entries(node_id).await?;
+            return Ok(());
         }
 
         // If successful: update nextIndex and matchIndex for
         // follower (S5.3)
-        node.next_index = self.storage.current_index().max(1);
+        node.next_index = node.next_index.max(response.current_index + 1);
         node.match_index = node.match_index.max(response.current_index);
         node.match_message_index = node.match_message_index.max(response.message_index);
 
-        self.flush().await;
+        self.flush().await?;
+        self.maybe_send_timeout_now(node_id);
+        self.send_new_entries(node_id).await?;
+        Ok(())
+    }
+
+    /// Returns nextIndex that skips all the conflicting entries in the
+    /// term the follower reported.
+    async fn next_index_after_conflict(
+        &mut self,
+        response: &AppendEntriesResponse,
+    ) -> Result<u64, ServerError<S::Error>> {
+        let conflict_index = response.conflict_index;
+        let Some(conflict_term) = response.conflict_term else {
+            return Ok(conflict_index);
+        };
+        if conflict_index <= self.storage.snapshot_metadata().last_included_index {
+            return Ok(conflict_index);
+        }
+
+        // If the leader has entries in conflictTerm, the logs may match up
+        // to the last of them
+        let index = self
+            .storage
+            .first_index_after_term(conflict_term, conflict_index)
+            .await?;
+        if index > conflict_index && self.storage.term(index - 1).await? == Some(conflict_term) {
+            Ok(index)
+        } else {
+            Ok(conflict_index)
+        }
     }
 
-    async fn handle_request_vote(&mut self, request: RequestVote) -> RequestVoteResponse {
+    async fn handle_request_vote(
+        &mut self,
+        request: RequestVote,
+    ) -> Result<RequestVoteResponse, ServerError<S::Error>> {
         tracing::trace!("received RequestVote");
 
         let RequestVote {
@@ -331,17 +854,17 @@ where
         // If RPC request or response contains term T > currentTerm:
         // set currentTerm = T, convert to follower (S5.1)
         if term > self.current_term {
-            self.update_current_term(term).await;
+            self.update_current_term(term).await?;
             self.become_follower();
         }
 
         // 1. Reply false if term < currentTerm (S5.1)
         if term < self.current_term {
             tracing::trace!("rejecting RequestVote: term < currentTerm");
-            return RequestVoteResponse {
+            return Ok(RequestVoteResponse {
                 term: self.current_term,
                 vote_granted: false,
-            };
+            });
         }
 
         // 2. If votedFor is null or candidateId, and candidate's log is at
@@ -351,44 +874,47 @@ where
             Some(node_id) if node_id == candidate_id => (),
             Some(node_id) => {
                 tracing::trace!("rejecting RequestVote: already voted for {:?}", node_id);
-                return RequestVoteResponse {
+                return Ok(RequestVoteResponse {
                     term: self.current_term,
                     vote_granted: false,
-                };
+                });
             }
         }
 
         let current_index = self.storage.current_index();
-        let last_term = self.storage.last_term().await.unwrap();
+        let last_term = self.storage.last_term().await?;
         if (last_log_term, last_log_index) < (last_term, current_index) {
             tracing::trace!("rejecting RequestVote: last log is too old",);
-            return RequestVoteResponse {
+            return Ok(RequestVoteResponse {
                 term: self.current_term,
                 vote_granted: false,
-            };
+            });
         }
 
+        if self.state == State::PreCandidate {
+            self.become_follower();
+        }
         assert_eq!(self.state, State::Follower);
-        self.vote_for(candidate_id).await;
+        self.vote_for(candidate_id).await?;
         self.reset_election_timer();
 
         tracing::trace!("voting to {:?}", candidate_id);
-        RequestVoteResponse {
+        Ok(RequestVoteResponse {
             term: self.current_term,
             vote_granted: true,
-        }
+        })
     }
 
     async fn handle_request_vote_response(
         &mut self,
         response: RpcResponse<RequestVoteResponse, T::Error>,
-    ) {
+    ) -> Result<(), ServerError<S::Error>> {
         let RpcResponse { node_id, result } = response;
         let response = match result {
             Ok(response) => response,
             Err(err) => {
                 tracing::trace!("RequestVote request to {:?} failed: {:?}", node_id, err);
-                return;
+                return Ok(());
             }
         };
         tracing::trace!("received RequestVote response from {:?}", node_id);
@@ -396,147 +922,1064 @@ where
         // If RPC request or response contains term T > currentTerm:
         // set currentTerm = T, convert to follower (S5.1)
         if response.term > self.current_term {
-            self.update_current_term(response.term).await;
+            self.update_current_term(response.term).await?;
             self.become_follower();
-            return;
+            return Ok(());
         }
 
         if self.state != State::Candidate || response.term != self.current_term {
-            return;
+            return Ok(());
         }
 
         // If votes received from majority of servers: become leader
         if response.vote_granted {
-            self.nodes.get_mut(&node_id).unwrap().voted_for_me = true;
-            if self.nodes.values().filter(|node| node.voted_for_me).count() > self.nodes.len() / 2 {
-                self.become_leader().await;
+            let Some(node) = self.nodes.get_mut(&node_id) else {
+                return Ok(());
+            };
+            node.voted_for_me = true;
+            if self.is_quorum(|node| node.voted_for_me) {
+                self.become_leader().await?;
             }
         }
+        Ok(())
     }
 
-    async fn handle_write(
+    async fn handle_pre_vote(
         &mut self,
-        command: C,
-        tx: oneshot::Sender<Result<C::Output, RaftError>>,
-    ) {
-        if self.state != State::Leader {
-            tracing::trace!("rejecting write request");
-            let _ = tx.send(Err(RaftError::NotLeader {
-                leader_id: self.leader_id,
-            }));
-            return;
-        }
+        request: PreVote,
+    ) -> Result<PreVoteResponse, ServerError<S::Error>> {
+        tracing::trace!("received PreVote");
 
-        // If command received from client: append entry to local log,
-        // respond after entry applied to state machine (S5.3)
-        self.storage
-            .append_entries(&[Entry {
-                kind: EntryKind::Command(command),
-                term: self.current_term,
-            }])
-            .await
-            .unwrap();
+        let PreVote {
+            term,
+            candidate_id,
+            last_log_index,
+            last_log_term,
+        } = request;
 
-        let index = self.storage.current_index();
-        self.nodes.get_mut(&self.node_id).unwrap().match_index = index;
+        // Unlike RequestVote, PreVote doesn't modify the state of receivers.
 
-        tracing::trace!(index, "pending write request",);
-        self.pending_write_requests
-            .push_back(WriteRequest { index, tx });
+        if term < self.current_term {
+            tracing::trace!("rejecting PreVote: term < currentTerm");
+            return Ok(PreVoteResponse {
+                term: self.current_term,
+                vote_granted: false,
+            });
+        }
 
-        self.flush().await;
-    }
+        // Reject if we believe the current leader is alive
+        let heard_from_leader = self.state == State::Leader
+            || (self.leader_id.is_some()
+                && self
+                    .last_leader_contact
+                    .is_some_and(|contact| contact.elapsed() < self.config.election_timeout_min));
+        if heard_from_leader {
+            tracing::trace!("rejecting PreVote from {:?}: leader is alive", candidate_id);
+            return Ok(PreVoteResponse {
+                term: self.current_term,
+                vote_granted: false,
+            });
+        }
 
-    async fn handle_read(&mut self, tx: oneshot::Sender<Result<(), RaftError>>) {
-        if self.state == State::Leader {
-            let index = self.storage.current_index();
-            tracing::trace!(index, "pending read request",);
-            self.last_message_index += 1;
-            self.pending_read_requests.push_back(ReadRequest {
-                index,
-                message_index: self.last_message_index,
-                tx,
+        let current_index = self.storage.current_index();
+        let last_term = self.storage.last_term().await?;
+        if (last_log_term, last_log_index) < (last_term, current_index) {
+            tracing::trace!("rejecting PreVote: last log is too old");
+            return Ok(PreVoteResponse {
+                term: self.current_term,
+                vote_granted: false,
             });
-            self.flush().await;
-        } else {
-            tracing::trace!("rejecting read request");
-            let _ = tx.send(Err(RaftError::NotLeader {
-                leader_id: self.leader_id,
-            }));
         }
-    }
 
-    async fn flush(&mut self) {
-        assert_eq!(self.state, State::Leader);
-        self.update_commit_index().await;
-        self.exec_operations().await;
+        tracing::trace!("pre-voting to {:?}", candidate_id);
+        Ok(PreVoteResponse {
+            term,
+            vote_granted: true,
+        })
     }
 
-    async fn truncate_log(&mut self, index: u64) {
-        assert_eq!(self.state, State::Follower);
-        self.storage.truncate_entries(index).await.unwrap();
-        while let Some(request) = self.pending_write_requests.back() {
-            if request.index < index {
-                break;
+    async fn handle_pre_vote_response(
+        &mut self,
+        response: RpcResponse<PreVoteResponse, T::Error>,
+    ) -> Result<(), ServerError<S::Error>> {
+        let RpcResponse { node_id, result } = response;
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::trace!("PreVote request to {:?} failed: {:?}", node_id, err);
+                return Ok(());
             }
-            let request = self.pending_write_requests.pop_back().unwrap();
-            let _ = request.tx.send(Err(RaftError::NotLeader {
-                leader_id: self.leader_id,
-            }));
+        };
+        tracing::trace!("received PreVote response from {:?}", node_id);
+
+        if !response.vote_granted && response.term > self.current_term {
+            self.update_current_term(response.term).await?;
+            self.become_follower();
+            return Ok(());
         }
-    }
 
-    async fn send_append_entries_to_all(&mut self) {
-        assert_eq!(self.state, State::Leader);
+        if self.state != State::PreCandidate || !response.vote_granted {
+            return Ok(());
+        }
 
-        let node_ids: Vec<_> = self
-            .nodes
-            .keys()
-            .filter(|node_id| **node_id != self.node_id)
-            .copied()
-            .collect();
-        for node_id in node_ids {
-            // If last log index ≥ nextIndex for a follower: send
-            // AppendEntries RPC with log entries starting at nextIndex
-            let fut = self.spawn_append_entries_task(node_id).await;
-            self.pending_append_entries_responses.push(fut);
+        // If pre-votes received from majority of servers: start a real
+        // election
+        let Some(node) = self.nodes.get_mut(&node_id) else {
+            return Ok(());
+        };
+        node.voted_for_me = true;
+        if self.is_quorum(|node| node.voted_for_me) {
+            self.become_candidate().await?;
         }
+        Ok(())
     }
 
-    async fn exec_operations(&mut self) {
-        // If commitIndex > lastApplied: increment lastApplied, apply
-        // log[lastApplied] to state machine (S5.3)
+    async fn handle_install_snapshot(
+        &mut self,
+        request: InstallSnapshot,
+    ) -> Result<InstallSnapshotResponse, ServerError<S::Error>> {
+        tracing::trace!("received InstallSnapshot");
+
+        let InstallSnapshot {
+            term,
+            leader_id,
+            metadata,
+            offset,
+            data,
+            done,
+        } = request;
+
+        // 1. Reply immediately if term < currentTerm
+        if term < self.current_term {
+            tracing::trace!("replying to InstallSnapshot: term < currentTerm");
+            return Ok(InstallSnapshotResponse {
+                term: self.current_term,
+                metadata,
+                next_offset: 0,
+            });
+        }
+
+        if term > self.current_term {
+            self.update_current_term(term).await?;
+        }
+
+        self.reset_election_timer();
+        if self.state != State::Follower {
+            self.become_follower();
+        }
+        self.leader_id = Some(leader_id);
+        self.last_leader_contact = Some(Instant::now());
+        self.complete_leadership_transfer(leader_id);
+
+        if metadata.last_included_index <= self.last_applied_index {
+            tracing::trace!("replying to InstallSnapshot: already applied");
+            self.pending_snapshot = None;
+            let next_offset = metadata.size;
+            return Ok(InstallSnapshotResponse {
+                term: self.current_term,
+                metadata,
+                next_offset,
+            });
+        }
+
+        // 2. Create new snapshot file if first chunk (offset is 0)
+        let pending_snapshot = match &mut self.pending_snapshot {
+            Some(pending_snapshot) if pending_snapshot.metadata == metadata => pending_snapshot,
+            _ if offset == 0 => self.pending_snapshot.insert(PendingSnapshot {
+                metadata: metadata.clone(),
+                data: BytesMut::with_capacity(metadata.size.try_into().unwrap()),
+            }),
+            _ => {
+                tracing::trace!("replying to InstallSnapshot: unknown snapshot");
+                return Ok(InstallSnapshotResponse {
+                    term: self.current_term,
+                    metadata,
+                    next_offset: 0,
+                });
+            }
+        };
+
+        // 3. Write data into snapshot file at given offset
+        let received = TryInto::<u64>::try_into(pending_snapshot.data.len()).unwrap();
+        let is_next_chunk = offset == received;
+        if is_next_chunk {
+            pending_snapshot.data.extend_from_slice(&data);
+        }
+        let next_offset = TryInto::<u64>::try_into(pending_snapshot.data.len()).unwrap();
+
+        // 4. Reply and wait for more data chunks if done is false
+        if !done || !is_next_chunk {
+            return Ok(InstallSnapshotResponse {
+                term: self.current_term,
+                metadata,
+                next_offset,
+            });
+        }
+
+        // 5. Save snapshot file, discard any existing or partial snapshot
+        // with a smaller index
+        // 6. If existing log entry has same index and term as snapshot's
+        // last included entry, retain log entries following it and reply
+        // 7. Discard the entire log
+        let PendingSnapshot { metadata, data } = self.pending_snapshot.take().unwrap();
+        let data = data.freeze();
+        self.cancel_snapshot().await;
+        self.storage
+            .install_snapshot(metadata.clone(), data.clone())
+            .await?;
+
+        // 8. Reset state machine using snapshot contents
+        self.applier
+            .restore(data, metadata.last_included_index)
+            .await
+            .map_err(ServerError::Restore)?;
+        let index = metadata.last_included_index;
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied_index = index;
+        self.last_applied_term = metadata.last_included_term;
+        self.applied_membership = (index, metadata.membership.clone());
+        tracing::info!(index, "installed snapshot");
+        self.complete_forwarded_reads();
+
+        // The retained entries may contain a newer membership
+        let (membership_index, membership) = match self.find_membership(index).await? {
+            Some(membership) => membership,
+            None => self.applied_membership.clone(),
+        };
+        self.set_membership(membership_index, membership);
+
+        // Outputs of the commands in the snapshot are lost, and the entries
+        // following the snapshot are gone if the log was discarded
+        let last_index = self.storage.current_index();
+        for request in std::mem::take(&mut self.pending_write_requests) {
+            if index < request.index && request.index <= last_index {
+                self.pending_write_requests.push_back(request);
+                continue;
+            }
+            let _ = request.tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+        }
+        if let Some(request) = self
+            .pending_membership_request
+            .take_if(|r| r.index > last_index)
+        {
+            let _ = request.tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+        }
+
+        self.exec_operations().await?;
+
+        Ok(InstallSnapshotResponse {
+            term: self.current_term,
+            metadata,
+            next_offset,
+        })
+    }
+
+    async fn handle_install_snapshot_response(
+        &mut self,
+        response: RpcResponse<InstallSnapshotResponse, T::Error>,
+    ) -> Result<(), ServerError<S::Error>> {
+        let RpcResponse { node_id, result } = response;
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            node.num_in_flight = node.num_in_flight.saturating_sub(1);
+        }
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::trace!("InstallSnapshot request to {:?} failed: {:?}", node_id, err);
+                if let Some(node) = self.nodes.get_mut(&node_id) {
+                    node.num_failed_rpcs += 1;
+                }
+                return Ok(());
+            }
+        };
+        tracing::trace!("received InstallSnapshot reply from {:?}", node_id);
+
+        if self.state != State::Leader {
+            return Ok(());
+        }
+
+        // If RPC request or response contains term T > currentTerm:
+        // set currentTerm = T, convert to follower (S5.1)
+        if response.term > self.current_term {
+            self.update_current_term(response.term).await?;
+            self.become_follower();
+            return Ok(());
+        }
+
+        let metadata = self.storage.snapshot_metadata();
+        let Some(node) = self.nodes.get_mut(&node_id) else {
+            return Ok(());
+        };
+        node.recent_active = true;
+        node.last_ack_at = Some(Instant::now());
+        if response.metadata != *metadata {
+            // The snapshot has been replaced with a newer one.
+            // Start over with the new snapshot.
+            node.snapshot_offset = 0;
+            return Ok(());
+        }
+
+        if response.next_offset >= metadata.size {
+            // The follower has installed the snapshot
+            node.snapshot_offset = 0;
+            node.next_index = node.next_index.max(metadata.last_included_index + 1);
+            node.match_index = node.match_index.max(metadata.last_included_index);
+            self.send_append_entries(node_id).await?;
+            self.flush().await?;
+            return Ok(());
+        }
+
+        if response.next_offset != node.snapshot_offset {
+            node.snapshot_offset = response.next_offset;
+            self.send_snapshot_chunk(node_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles `command` together with the writes queued after it, so
+    /// that they are appended and persisted at once.
+    async fn handle_write_batch(
+        &mut self,
+        command: C,
+        tx: WriteResponder<C::Output>,
+    ) -> Result<(), ServerError<S::Error>> {
+        let mut writes = vec![(command, tx)];
+        let mut next_message = None;
+        while writes.len() < MAX_WRITE_BATCH_SIZE {
+            match self.rx.try_recv() {
+                Ok(Message::Write(command, tx)) => writes.push((command, tx)),
+                Ok(message) => {
+                    next_message = Some(message);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        self.deferred_message = next_message;
+        self.handle_writes(writes).await?;
+        if let Some(message) = self.deferred_message.take() {
+            self.handle_message(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_writes(
+        &mut self,
+        writes: Vec<(C, WriteResponder<C::Output>)>,
+    ) -> Result<(), ServerError<S::Error>> {
+        if self.state != State::Leader {
+            tracing::trace!("rejecting {} write requests", writes.len());
+            for (_, tx) in writes {
+                let _ = tx.send(Err(RaftError::NotLeader {
+                    leader_id: self.leader_id,
+                }));
+            }
+            return Ok(());
+        }
+        if self.leadership_transfer.is_some() {
+            // New entries would delay the transfer
+            tracing::trace!("rejecting write requests: transferring leadership");
+            for (_, tx) in writes {
+                let _ = tx.send(Err(RaftError::NotLeader { leader_id: None }));
+            }
+            return Ok(());
+        }
+
+        // If command received from client: append entry to local log,
+        // respond after entry applied to state machine (S5.3)
+        let (commands, txs): (Vec<_>, Vec<_>) = writes.into_iter().unzip();
+        let entries: Vec<_> = commands
+            .into_iter()
+            .map(|command| self.new_entry(EntryKind::Command(command)))
+            .collect();
+        let first_index = self.storage.current_index() + 1;
+        tracing::trace!(first_index, "{} pending write requests", txs.len());
+        for (index, tx) in (first_index..).zip(txs) {
+            self.pending_write_requests
+                .push_back(WriteRequest { index, tx });
+        }
+        self.storage.append_entries(&entries).await?;
+
+        self.replicate().await?;
+        Ok(())
+    }
+
+    async fn handle_read(&mut self, tx: ReadResponder) -> Result<(), ServerError<S::Error>> {
+        match (self.state, self.leader_id) {
+            (State::Leader, _) => self.read_on_leader(tx).await?,
+            (State::Follower, Some(leader_id)) if self.config.follower_read => {
+                self.forward_read(leader_id, tx)
+            }
+            _ => {
+                tracing::trace!("rejecting read request");
+                let _ = tx.send(Err(RaftError::NotLeader {
+                    leader_id: self.leader_id,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replies with the read index once the leader confirms that it is
+    /// still the leader.
+    async fn read_on_leader(&mut self, tx: ReadResponder) -> Result<(), ServerError<S::Error>> {
+        assert_eq!(self.state, State::Leader);
+        if self.has_lease() {
+            tracing::trace!("acknowledging read request with lease");
+            self.applier.read(self.commit_index, tx);
+            return Ok(());
+        }
+        let index = self.storage.current_index();
+        tracing::trace!(index, "pending read request",);
+        self.last_message_index += 1;
+        self.pending_read_requests.push_back(ReadRequest {
+            index,
+            message_index: self.last_message_index,
+            tx,
+        });
+        self.flush().await?;
+        Ok(())
+    }
+
+    /// Asks the leader for the read index, and serves the read once the
+    /// state machine catches up with it.
+    fn forward_read(&mut self, leader_id: NodeId, tx: ReadResponder) {
+        let request = ReadIndex {
+            term: self.current_term,
+        };
+        let transport = self.transport.clone();
+        tracing::trace!("sending ReadIndex to {:?}", leader_id);
+        self.pending_read_index_responses
+            .push(tokio::spawn(async move {
+                ReadIndexRpcResponse {
+                    response: RpcResponse {
+                        node_id: leader_id,
+                        result: transport.send_read_index(leader_id, request).await,
+                    },
+                    tx,
+                }
+            }));
+    }
+
+    async fn handle_read_index(
+        &mut self,
+        request: ReadIndex,
+        tx: oneshot::Sender<RaftResult<ReadIndexResponse>>,
+    ) -> Result<(), ServerError<S::Error>> {
+        tracing::trace!("received ReadIndex");
+
+        // If RPC request or response contains term T > currentTerm:
+        // set currentTerm = T, convert to follower (S5.1)
+        if request.term > self.current_term {
+            self.update_current_term(request.term).await?;
+            self.become_follower();
+        }
+
+        let term = self.current_term;
+        if self.state != State::Leader {
+            let _ = tx.send(Ok(ReadIndexResponse {
+                term,
+                read_index: None,
+            }));
+            return Ok(());
+        }
+
+        let (read_tx, read_rx) = oneshot::channel();
+        self.read_on_leader(read_tx).await?;
+        tokio::spawn(async move {
+            let read_index = read_rx.await.ok().and_then(Result::ok);
+            let _ = tx.send(Ok(ReadIndexResponse { term, read_index }));
+        });
+        Ok(())
+    }
+
+    async fn handle_read_index_response(
+        &mut self,
+        response: ReadIndexRpcResponse<T::Error>,
+    ) -> Result<(), ServerError<S::Error>> {
+        let ReadIndexRpcResponse {
+            response: RpcResponse { node_id, result },
+            tx,
+        } = response;
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::trace!("ReadIndex request to {:?} failed: {:?}", node_id, err);
+                let _ = tx.send(Err(RaftError::NotLeader {
+                    leader_id: Some(node_id),
+                }));
+                return Ok(());
+            }
+        };
+        tracing::trace!("received ReadIndex reply from {:?}", node_id);
+
+        let has_newer_term = response.term > self.current_term;
+        match response.read_index {
+            Some(index) => self
+                .pending_forwarded_read_requests
+                .push(ForwardedReadRequest {
+                    index,
+                    deadline: Instant::now() + self.config.election_timeout_max,
+                    tx,
+                }),
+            None => {
+                let _ = tx.send(Err(RaftError::NotLeader {
+                    leader_id: if has_newer_term { None } else { self.leader_id },
+                }));
+            }
+        }
+
+        // If RPC request or response contains term T > currentTerm:
+        // set currentTerm = T, convert to follower (S5.1)
+        if has_newer_term {
+            self.update_current_term(response.term).await?;
+            self.become_follower();
+        }
+
+        self.complete_forwarded_reads();
+        Ok(())
+    }
+
+    /// Acknowledges the forwarded reads whose read index has been applied.
+    fn complete_forwarded_reads(&mut self) {
+        // The order is kept so that the first request expires first
+        let (completed, pending) = std::mem::take(&mut self.pending_forwarded_read_requests)
+            .into_iter()
+            .partition(|request| request.index <= self.last_applied_index);
+        self.pending_forwarded_read_requests = pending;
+        for request in completed {
+            tracing::trace!("acknowledging forwarded read request");
+            self.applier.read(request.index, request.tx);
+        }
+    }
+
+    /// Fails the forwarded reads that have waited too long for the state
+    /// machine to catch up, e.g. because we lost contact with the leader.
+    fn expire_forwarded_reads(&mut self) {
+        let now = Instant::now();
+        let num_expired = self
+            .pending_forwarded_read_requests
+            .partition_point(|request| request.deadline <= now);
+        for request in self.pending_forwarded_read_requests.drain(..num_expired) {
+            tracing::trace!("forwarded read request timed out");
+            let _ = request.tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+        }
+    }
+
+    /// Fails all the forwarded reads, as the leader that gave their read
+    /// indices may no longer be the leader.
+    fn fail_forwarded_reads(&mut self) {
+        for request in self.pending_forwarded_read_requests.drain(..) {
+            let _ = request
+                .tx
+                .send(Err(RaftError::NotLeader { leader_id: None }));
+        }
+    }
+
+    async fn handle_change_membership(
+        &mut self,
+        change: MembershipChange,
+        tx: oneshot::Sender<Result<(), RaftError>>,
+    ) -> Result<(), ServerError<S::Error>> {
+        if self.state != State::Leader {
+            tracing::trace!("rejecting membership change");
+            let _ = tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+            return Ok(());
+        }
+        if self.leadership_transfer.is_some() {
+            let _ = tx.send(Err(RaftError::NotLeader { leader_id: None }));
+            return Ok(());
+        }
+
+        // Changes are made one server at a time, so that the majorities of
+        // the old and new memberships always overlap. A change can only
+        // start after the previous one and an entry from the current term
+        // are committed.
+        if self.membership_index > self.last_applied_index
+            || self.last_applied_term < self.current_term
+        {
+            let _ = tx.send(Err(RaftError::MembershipChangeInProgress));
+            return Ok(());
+        }
+
+        let mut membership = self.membership();
+        let changed = match change {
+            MembershipChange::AddNode(node_id) => {
+                membership.nodes.insert(node_id, Role::Voter) != Some(Role::Voter)
+            }
+            MembershipChange::AddLearner(node_id) => {
+                // Demoting a voter to a learner is not supported
+                !membership.nodes.contains_key(&node_id)
+                    && membership.nodes.insert(node_id, Role::Learner).is_none()
+            }
+            MembershipChange::RemoveNode(node_id) => membership.nodes.remove(&node_id).is_some(),
+        };
+        if !changed {
+            let _ = tx.send(Ok(()));
+            return Ok(());
+        }
+        if membership.num_voters() == 0 {
+            let _ = tx.send(Err(RaftError::EmptyMembership));
+            return Ok(());
+        }
+
+        let index = self.storage.current_index() + 1;
+        tracing::trace!(index, "pending membership change");
+        self.pending_membership_request = Some(WriteRequest { index, tx });
+        let entry = self.new_entry(EntryKind::Membership(membership.clone()));
+        self.storage.append_entries(&[entry]).await?;
+
+        // The new membership takes effect as soon as it is appended
+        self.set_membership(index, membership);
+
+        self.replicate().await?;
+        Ok(())
+    }
+
+    async fn handle_transfer_leadership(
+        &mut self,
+        target: Option<NodeId>,
+        tx: oneshot::Sender<Result<(), RaftError>>,
+    ) -> Result<(), ServerError<S::Error>> {
+        if self.state != State::Leader {
+            let _ = tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+            return Ok(());
+        }
+
+        let target = match target {
+            Some(target) if target == self.node_id => {
+                let _ = tx.send(Ok(()));
+                return Ok(());
+            }
+            Some(target) if self.is_voter(target) => target,
+            Some(_) => {
+                let _ = tx.send(Err(RaftError::NoTransferTarget));
+                return Ok(());
+            }
+            None => {
+                // Choose the node that takes the least time to catch up
+                let target = self
+                    .nodes
+                    .iter()
+                    .filter(|(node_id, node)| **node_id != self.node_id && node.role == Role::Voter)
+                    .max_by_key(|(_, node)| node.match_index)
+                    .map(|(node_id, _)| *node_id);
+                let Some(target) = target else {
+                    let _ = tx.send(Err(RaftError::NoTransferTarget));
+                    return Ok(());
+                };
+                target
+            }
+        };
+
+        if let Some(transfer) = self.leadership_transfer.take() {
+            let _ = transfer.tx.send(Err(RaftError::LeadershipTransferTimeout));
+        }
+        tracing::info!(
+            term = self.current_term,
+            "transferring leadership to {:?}",
+            target
+        );
+        self.leadership_transfer = Some(LeadershipTransfer {
+            target,
+            deadline: Instant::now() + self.config.election_timeout_max,
+            timeout_now_sent: false,
+            tx,
+        });
+
+        // Bring the target's log up to date. TimeoutNow is sent once the
+        // target acknowledges all the entries.
+        self.maybe_send_timeout_now(target);
+        if self
+            .leadership_transfer
+            .as_ref()
+            .is_some_and(|transfer| !transfer.timeout_now_sent)
+        {
+            self.send_append_entries(target).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_timeout_now(
+        &mut self,
+        request: TimeoutNow,
+    ) -> Result<TimeoutNowResponse, ServerError<S::Error>> {
+        tracing::trace!("received TimeoutNow");
+
+        let TimeoutNow { term, leader_id } = request;
+        if term < self.current_term {
+            tracing::trace!("ignoring TimeoutNow: term < currentTerm");
+            return Ok(TimeoutNowResponse {
+                term: self.current_term,
+            });
+        }
+        if term > self.current_term {
+            self.update_current_term(term).await?;
+            self.become_follower();
+        }
+
+        if self.state != State::Leader && self.is_voter(self.node_id) {
+            tracing::info!("starting election at the request of {:?}", leader_id);
+
+            // Skip the pre-vote phase because the leader is stepping down
+            // on purpose.
+            self.become_candidate().await?;
+        }
+        Ok(TimeoutNowResponse {
+            term: self.current_term,
+        })
+    }
+
+    async fn handle_timeout_now_response(
+        &mut self,
+        response: RpcResponse<TimeoutNowResponse, T::Error>,
+    ) -> Result<(), ServerError<S::Error>> {
+        let RpcResponse { node_id, result } = response;
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::trace!("TimeoutNow request to {:?} failed: {:?}", node_id, err);
+                return Ok(());
+            }
+        };
+        tracing::trace!("received TimeoutNow reply from {:?}", node_id);
+
+        // If RPC request or response contains term T > currentTerm:
+        // set currentTerm = T, convert to follower (S5.1)
+        if response.term > self.current_term {
+            self.update_current_term(response.term).await?;
+            self.become_follower();
+        }
+        Ok(())
+    }
+
+    /// Sends TimeoutNow to `node_id` if it is the target of the leadership
+    /// transfer and its log is up to date.
+    fn maybe_send_timeout_now(&mut self, node_id: NodeId) {
+        if self.state != State::Leader {
+            return;
+        }
+        let Some(transfer) = &mut self.leadership_transfer else {
+            return;
+        };
+        if transfer.target != node_id || transfer.timeout_now_sent {
+            return;
+        }
+        let Some(node) = self.nodes.get(&node_id) else {
+            return;
+        };
+        if node.match_index < self.storage.current_index() {
+            return;
+        }
+        transfer.timeout_now_sent = true;
+
+        // The target is about to win an election with the votes of the
+        // nodes that granted us the lease
+        self.lease_deadline = None;
+        self.lease_revoked = true;
+
+        let request = TimeoutNow {
+            term: self.current_term,
+            leader_id: self.node_id,
+        };
+        let transport = self.transport.clone();
+        tracing::trace!("sending TimeoutNow to {:?}", node_id);
+        self.pending_timeout_now_responses
+            .push(tokio::spawn(async move {
+                RpcResponse {
+                    node_id,
+                    result: transport.send_timeout_now(node_id, request).await,
+                }
+            }));
+    }
+
+    fn abort_leadership_transfer(&mut self) {
+        let Some(transfer) = self.leadership_transfer.take() else {
+            return;
+        };
+        tracing::warn!(
+            term = self.current_term,
+            "leadership transfer to {:?} timed out",
+            transfer.target
+        );
+        let _ = transfer.tx.send(Err(RaftError::LeadershipTransferTimeout));
+    }
+
+    /// Sends the new entries to the followers, and persists them to the
+    /// local log while the AppendEntries RPCs are in flight.
+    async fn replicate(&mut self) -> Result<(), ServerError<S::Error>> {
+        assert_eq!(self.state, State::Leader);
+        let node_ids: Vec<_> = self
+            .nodes
+            .keys()
+            .filter(|node_id| **node_id != self.node_id)
+            .copied()
+            .collect();
+        for node_id in node_ids {
+            self.send_new_entries(node_id).await?;
+        }
+
+        // The leader counts itself toward a majority only after the
+        // entries become durable
+        self.storage.persist_entries().await?;
+        let current_index = self.storage.current_index();
+        if let Some(node) = self.nodes.get_mut(&self.node_id) {
+            node.match_index = current_index;
+        }
+
+        self.flush().await?;
+        Ok(())
+    }
+
+    /// Sends the entries the follower doesn't have yet until the window of
+    /// RPCs in flight is full. The rest are sent as responses arrive.
+    async fn send_new_entries(&mut self, node_id: NodeId) -> Result<(), ServerError<S::Error>> {
+        while self.state == State::Leader {
+            let Some(node) = self.nodes.get(&node_id) else {
+                return Ok(());
+            };
+            if node.next_index > self.storage.current_index()
+                || node.num_in_flight >= self.config.max_in_flight_appends
+            {
+                return Ok(());
+            }
+            let sends_snapshot =
+                node.next_index <= self.storage.snapshot_metadata().last_included_index;
+            self.send_append_entries(node_id).await?;
+            if sends_snapshot {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), ServerError<S::Error>> {
+        assert_eq!(self.state, State::Leader);
+        self.update_commit_index().await?;
+        self.exec_operations().await?;
+        Ok(())
+    }
+
+    async fn truncate_log(&mut self, index: u64) -> Result<(), ServerError<S::Error>> {
+        assert_eq!(self.state, State::Follower);
+        self.storage.truncate_entries(index).await?;
+        while let Some(request) = self.pending_write_requests.back() {
+            if request.index < index {
+                break;
+            }
+            let request = self.pending_write_requests.pop_back().unwrap();
+            let _ = request.tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+        }
+        if let Some(request) = self
+            .pending_membership_request
+            .take_if(|r| r.index >= index)
+        {
+            let _ = request.tx.send(Err(RaftError::NotLeader {
+                leader_id: self.leader_id,
+            }));
+        }
+
+        // Revert to the previous membership if the latest one was removed
+        if self.membership_index >= index {
+            let (index, membership) = match self.find_membership(self.last_applied_index).await? {
+                Some(membership) => membership,
+                None => self.applied_membership.clone(),
+            };
+            self.set_membership(index, membership);
+        }
+        Ok(())
+    }
+
+    /// Returns the latest membership in the log entries following
+    /// `after`, and the index of the entry that defined it.
+    async fn find_membership(
+        &mut self,
+        after: u64,
+    ) -> Result<Option<(u64, Membership)>, ServerError<S::Error>> {
+        let mut index = self.storage.current_index();
+        while index > after {
+            let Some(entry) = self.storage.entry(index).await? else {
+                return Ok(None);
+            };
+            if let EntryKind::Membership(membership) = entry.kind {
+                return Ok(Some((index, membership)));
+            }
+            index -= 1;
+        }
+        Ok(None)
+    }
+
+    fn set_membership(&mut self, index: u64, membership: Membership) {
+        let next_index = self.storage.current_index().max(1);
+        self.nodes
+            .retain(|node_id, _| membership.nodes.contains_key(node_id));
+        for (node_id, role) in membership.nodes {
+            self.nodes
+                .entry(node_id)
+                .or_insert_with(|| Node {
+                    next_index,
+                    ..Node::new()
+                })
+                .role = role;
+        }
+        self.membership_index = index;
+        tracing::info!(
+            index,
+            "membership: voters={:?} learners={:?}",
+            self.node_ids(Role::Voter),
+            self.node_ids(Role::Learner)
+        );
+    }
+
+    fn membership(&self) -> Membership {
+        Membership {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(node_id, node)| (*node_id, node.role))
+                .collect(),
+        }
+    }
+
+    fn node_ids(&self, role: Role) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.role == role)
+            .map(|(node_id, _)| *node_id)
+            .collect()
+    }
+
+    fn is_voter(&self, node_id: NodeId) -> bool {
+        self.nodes
+            .get(&node_id)
+            .is_some_and(|node| node.role == Role::Voter)
+    }
+
+    fn num_voters(&self) -> usize {
+        self.voters().count()
+    }
+
+    fn voters(&self) -> impl Iterator<Item = (&NodeId, &Node)> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.role == Role::Voter)
+    }
+
+    /// Returns true if the voters satisfying `f` form a majority.
+    fn is_quorum<F: Fn(&Node) -> bool>(&self, f: F) -> bool {
+        self.voters().filter(|(_, node)| f(node)).count() > self.num_voters() / 2
+    }
+
+    async fn send_append_entries_to_all(&mut self) -> Result<(), ServerError<S::Error>> {
+        assert_eq!(self.state, State::Leader);
+
+        let node_ids: Vec<_> = self
+            .nodes
+            .keys()
+            .filter(|node_id| **node_id != self.node_id)
+            .copied()
+            .collect();
+        for node_id in node_ids {
+            let node = &self.nodes[&node_id];
+            if node.num_in_flight == 0 {
+                // If last log index ≥ nextIndex for a follower: send
+                // AppendEntries RPC with log entries starting at nextIndex
+                self.send_append_entries(node_id).await?;
+            } else if node
+                .last_sent_at
+                .is_none_or(|sent_at| sent_at.elapsed() >= self.config.heartbeat_interval)
+            {
+                // The RPCs in flight may take as long as the election
+                // timeout to be answered, so they don't serve as heartbeats
+                self.send_heartbeat(node_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn exec_operations(&mut self) -> Result<(), ServerError<S::Error>> {
+        // If commitIndex > lastApplied: increment lastApplied, apply
+        // log[lastApplied] to state machine (S5.3)
         tracing::trace!(
             commit_index = self.commit_index,
             last_applied_index = self.last_applied_index,
             "applying {} entries",
             self.commit_index - self.last_applied_index
         );
+        let mut batch = Vec::new();
+        let last_handed_over_index = self.last_applied_index;
         while self.commit_index > self.last_applied_index {
             let next_applied_index = self.last_applied_index + 1;
-            let entry = self
-                .storage
-                .entry(next_applied_index)
-                .await
-                .unwrap()
-                .unwrap();
+            let entry = self.entry(next_applied_index).await?;
             match entry.kind {
                 EntryKind::NoOp => (),
+                EntryKind::Membership(membership) => {
+                    if let Some(request) = self
+                        .pending_membership_request
+                        .take_if(|r| r.index == next_applied_index)
+                    {
+                        tracing::trace!("acknowledging membership change");
+                        let _ = request.tx.send(Ok(()));
+                    }
+                    self.applied_membership = (next_applied_index, membership);
+                }
                 EntryKind::Command(command) => {
-                    let output = self.state_machine.apply(command).await;
+                    let context = ApplyContext {
+                        index: next_applied_index,
+                        term: entry.term,
+                        timestamp: entry.timestamp,
+                        seed: entry.seed,
+                    };
+                    let mut tx = None;
                     if let Some(request) = self.pending_write_requests.front() {
-                        assert!(request.index <= next_applied_index);
+                        assert!(request.index >= next_applied_index);
                         if request.index == next_applied_index {
-                            let request = self.pending_write_requests.pop_front().unwrap();
-                            tracing::trace!("acknowledging write request");
-                            let _ = request.tx.send(Ok(output));
+                            tx = self.pending_write_requests.pop_front().map(|r| r.tx);
                         }
                     }
+                    batch.push(((context, command), tx));
+                    if batch.len() >= MAX_APPLY_BATCH_SIZE {
+                        self.applier
+                            .apply(std::mem::take(&mut batch), next_applied_index);
+                    }
                 }
             }
             self.last_applied_index = next_applied_index;
             self.last_applied_term = entry.term;
         }
+        // NoOp and membership entries don't reach the state machine, but
+        // the applier still has to count them as applied
+        if self.last_applied_index > last_handed_over_index {
+            self.applier.apply(batch, self.last_applied_index);
+        }
+
+        // A leader that has been removed from the cluster steps down once
+        // the membership without it is committed
+        if self.state == State::Leader
+            && !self.is_voter(self.node_id)
+            && self.membership_index <= self.last_applied_index
+        {
+            tracing::info!(
+                term = self.current_term,
+                "stepping down: removed from the cluster"
+            );
+            self.become_follower();
+        }
+
+        self.maybe_take_snapshot();
+        self.complete_forwarded_reads();
 
         if self.state != State::Leader {
             for request in self.pending_read_requests.drain(..) {
@@ -544,16 +1987,35 @@ where
                     leader_id: self.leader_id,
                 }));
             }
-            return;
+            return Ok(());
         }
 
         if self.last_applied_term < self.current_term {
-            return;
+            return Ok(());
+        }
+
+        let Some(quorum_message_index) = self.quorum_message_index() else {
+            return Ok(());
+        };
+        self.extend_lease(quorum_message_index);
+
+        while let Some(request) = self.pending_read_requests.front() {
+            if request.message_index > quorum_message_index
+                || request.index > self.last_applied_index
+            {
+                break;
+            }
+            let request = self.pending_read_requests.pop_front().unwrap();
+            tracing::trace!("acknowledging read request");
+            self.applier.read(request.index, request.tx);
         }
+        Ok(())
+    }
 
+    /// Returns the highest message index acknowledged by a quorum.
+    fn quorum_message_index(&self) -> Option<u64> {
         let mut match_message_indices: Vec<_> = self
-            .nodes
-            .iter()
+            .voters()
             .map(|(node_id, node)| {
                 if *node_id == self.node_id {
                     self.last_message_index
@@ -562,77 +2024,266 @@ where
                 }
             })
             .collect();
-        let middle = match_message_indices.len() / 2;
+        if match_message_indices.is_empty() {
+            return None;
+        }
+        let middle = (match_message_indices.len() - 1) / 2;
         let (_, &mut quorum_message_index, _) = match_message_indices.select_nth_unstable(middle);
+        Some(quorum_message_index)
+    }
 
-        while let Some(request) = self.pending_read_requests.front() {
-            if request.message_index > quorum_message_index
-                || request.index > self.last_applied_index
-            {
+    /// Extends the lease with the AppendEntries RPCs acknowledged by a
+    /// quorum.
+    ///
+    /// A follower that acknowledged an RPC doesn't grant a pre-vote to
+    /// another node until an election timeout elapses since it received
+    /// the RPC. Hence no other leader can be elected until
+    /// electionTimeoutMin after we sent the RPC, minus the clock drift.
+    fn extend_lease(&mut self, quorum_message_index: u64) {
+        let mut sent_at = None;
+        while let Some(&(message_index, instant)) = self.append_entries_sent_at.front() {
+            if message_index > quorum_message_index {
                 break;
             }
-            let request = self.pending_read_requests.pop_front().unwrap();
-            tracing::trace!("acknowledging read request");
-            let _ = request.tx.send(Ok(()));
+            sent_at = Some(instant);
+            self.append_entries_sent_at.pop_front();
+        }
+        if !self.config.lease_read || self.lease_revoked {
+            return;
+        }
+        let Some(sent_at) = sent_at else {
+            return;
+        };
+        let deadline = sent_at + self.config.election_timeout_min - self.config.max_clock_drift;
+        self.lease_deadline = Some(self.lease_deadline.map_or(deadline, |d| d.max(deadline)));
+    }
+
+    /// Returns true if the leader can serve reads without contacting a
+    /// quorum.
+    fn has_lease(&self) -> bool {
+        self.state == State::Leader
+            && self.leadership_transfer.is_none()
+            && self.last_applied_term == self.current_term
+            && self.last_applied_index >= self.commit_index
+            && self
+                .lease_deadline
+                .is_some_and(|deadline| Instant::now() < deadline)
+    }
+
+    /// Starts taking a snapshot in the background once enough entries have
+    /// been applied since the last one.
+    fn maybe_take_snapshot(&mut self) {
+        let threshold = self.config.snapshot_threshold;
+        let snapshot_index = self.storage.snapshot_metadata().last_included_index;
+        if threshold == 0
+            || self.snapshot_task.is_some()
+            || self.last_applied_index < snapshot_index + threshold
+        {
+            return;
+        }
+        self.snapshot_task = Some(SnapshotTask {
+            metadata: self.applied_snapshot_metadata(),
+            step: SnapshotStep::Serializing(self.applier.snapshot().boxed()),
+        });
+    }
+
+    /// Writes the snapshot once the state machine has serialized it, and
+    /// replaces the current snapshot with it once it is durable.
+    async fn handle_snapshot_progress(
+        &mut self,
+        progress: SnapshotProgress<S::Error>,
+    ) -> Result<(), ServerError<S::Error>> {
+        match progress {
+            SnapshotProgress::Serialized(data) => {
+                let task = self.snapshot_task.as_mut().unwrap();
+                task.metadata.size = data.len().try_into().unwrap();
+                let write = self
+                    .storage
+                    .write_snapshot(task.metadata.clone(), data.clone());
+                task.step = SnapshotStep::Writing(data, tokio::spawn(write));
+            }
+            SnapshotProgress::Written(result) => {
+                let SnapshotTask { metadata, step } = self.snapshot_task.take().unwrap();
+                result?;
+                let SnapshotStep::Writing(data, _) = step else {
+                    unreachable!()
+                };
+                let index = metadata.last_included_index;
+                self.storage.commit_snapshot(metadata, data).await?;
+                tracing::info!(index, "took snapshot");
+            }
+        }
+        Ok(())
+    }
+
+    /// Abandons the snapshot being taken in the background, so that it
+    /// isn't written while another snapshot is.
+    async fn cancel_snapshot(&mut self) {
+        if let Some(SnapshotTask {
+            step: SnapshotStep::Writing(_, handle),
+            ..
+        }) = self.snapshot_task.take()
+        {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+
+    /// Takes a snapshot and waits until it is durable.
+    async fn take_snapshot(&mut self) -> Result<(), ServerError<S::Error>> {
+        self.cancel_snapshot().await;
+        let data = self.applier.snapshot().await;
+        let metadata = SnapshotMetadata {
+            size: data.len().try_into().unwrap(),
+            ..self.applied_snapshot_metadata()
+        };
+        self.storage.install_snapshot(metadata, data).await?;
+        tracing::info!(index = self.last_applied_index, "took snapshot");
+        Ok(())
+    }
+
+    /// Returns the metadata of a snapshot of the entries applied so far,
+    /// without the size of the serialized state.
+    fn applied_snapshot_metadata(&self) -> SnapshotMetadata {
+        SnapshotMetadata {
+            last_included_index: self.last_applied_index,
+            last_included_term: self.last_applied_term,
+            membership: self.applied_membership.1.clone(),
+            size: 0,
+        }
+    }
+
+    /// Makes the log durable and stops the server.
+    ///
+    /// The outcomes of the pending requests are unknown once the server
+    /// stops, so they are failed with `RaftError::Shutdown`.
+    async fn handle_shutdown(&mut self, snapshot: bool) -> Result<(), ServerError<S::Error>> {
+        tracing::info!(term = self.current_term, "shutting down");
+        if snapshot
+            && self.last_applied_index > self.storage.snapshot_metadata().last_included_index
+        {
+            self.take_snapshot().await?;
+        }
+        self.storage.persist_entries().await?;
+
+        if let Some(transfer) = self.leadership_transfer.take() {
+            let _ = transfer.tx.send(Err(RaftError::Shutdown));
         }
+        self.fail_pending_requests(&RaftError::Shutdown);
+        self.is_shut_down = true;
+        Ok(())
     }
 
-    async fn update_current_term(&mut self, new_term: u64) {
+    async fn update_current_term(&mut self, new_term: u64) -> Result<(), ServerError<S::Error>> {
         assert!(new_term > self.current_term);
         self.storage
             .persist_metadata(&Metadata {
                 current_term: new_term,
                 voted_for: None,
             })
-            .await
-            .unwrap();
+            .await?;
         self.current_term = new_term;
         self.voted_for = None;
+        Ok(())
     }
 
-    async fn vote_for(&mut self, node_id: NodeId) {
+    async fn vote_for(&mut self, node_id: NodeId) -> Result<(), ServerError<S::Error>> {
         self.storage
             .persist_metadata(&Metadata {
                 current_term: self.current_term,
                 voted_for: Some(node_id),
             })
-            .await
-            .unwrap();
+            .await?;
         self.voted_for = Some(node_id);
+        Ok(())
     }
 
-    async fn update_commit_index(&mut self) {
+    async fn update_commit_index(&mut self) -> Result<(), ServerError<S::Error>> {
         assert_eq!(self.state, State::Leader);
 
         // If there exists an N such that N > commitIndex, a majority
         // of matchIndex[i] >= N, and log[N].term == currentTerm:
         // set commitIndex = N (S5.3, S5.4).
-        let mut match_indices: Vec<_> = self.nodes.values().map(|node| node.match_index).collect();
-        let i = match_indices.len() / 2;
+        let mut match_indices: Vec<_> = self.voters().map(|(_, node)| node.match_index).collect();
+        if match_indices.is_empty() {
+            return Ok(());
+        }
+        let i = (match_indices.len() - 1) / 2;
         let (_, &mut n, _) = match_indices.select_nth_unstable(i);
         if n > self.commit_index {
-            let entry = self.storage.entry(n).await.unwrap().unwrap();
+            let entry = self.entry(n).await?;
             if entry.term == self.current_term {
                 assert!(n <= self.storage.current_index());
                 self.commit_index = n;
             }
         }
+        Ok(())
+    }
+
+    fn check_quorum(&mut self) {
+        assert_eq!(self.state, State::Leader);
+        let node_id = self.node_id;
+        let has_quorum = self
+            .voters()
+            .filter(|(id, node)| **id == node_id || node.recent_active)
+            .count()
+            > self.num_voters() / 2;
+        for node in self.nodes.values_mut() {
+            node.recent_active = false;
+        }
+        self.check_quorum_deadline = Instant::now() + self.config.election_timeout_min;
+        if has_quorum {
+            return;
+        }
+
+        if let Some(transfer) = self.leadership_transfer.take() {
+            let _ = transfer
+                .tx
+                .send(Err(RaftError::NotLeader { leader_id: None }));
+        }
+
+        tracing::warn!(
+            term = self.current_term,
+            "stepping down: lost contact with majority of servers"
+        );
+        self.become_follower();
+
+        // The pending requests may never complete, so let clients know
+        // that the cluster is unavailable.
+        for request in self.pending_write_requests.drain(..) {
+            let _ = request
+                .tx
+                .send(Err(RaftError::NotLeader { leader_id: None }));
+        }
+        for request in self.pending_read_requests.drain(..) {
+            let _ = request
+                .tx
+                .send(Err(RaftError::NotLeader { leader_id: None }));
+        }
+        if let Some(request) = self.pending_membership_request.take() {
+            let _ = request
+                .tx
+                .send(Err(RaftError::NotLeader { leader_id: None }));
+        }
     }
 
     fn reset_election_timer(&mut self) {
-        self.election_deadline = self.config.random_election_deadline();
+        self.election_deadline = self.config.random_election_deadline(&mut self.rng);
     }
 
-    async fn start_election(&mut self) {
-        assert!(matches!(self.state, State::Follower | State::Candidate));
+    async fn start_election(&mut self) -> Result<(), ServerError<S::Error>> {
+        assert_ne!(self.state, State::Leader);
         tracing::info!("start election");
-        if self.nodes.len() == 1 {
-            assert!(self.nodes.contains_key(&self.node_id));
-            self.update_current_term(self.current_term + 1).await;
-            self.become_leader().await;
+        if self.num_voters() == 1 {
+            assert!(self.is_voter(self.node_id));
+            self.update_current_term(self.current_term + 1).await?;
+            self.become_leader().await?;
+        } else if self.config.pre_vote {
+            self.become_pre_candidate().await?;
         } else {
-            self.become_candidate().await;
+            self.become_candidate().await?;
         }
+        Ok(())
     }
 
     fn become_follower(&mut self) {
@@ -640,19 +2291,101 @@ where
         self.state = State::Follower;
         self.leader_id = None;
         self.reset_election_timer();
+        self.append_entries_sent_at.clear();
+        self.lease_deadline = None;
+
+        if let Some(transfer) = self.leadership_transfer.take() {
+            if transfer.timeout_now_sent {
+                // The transfer completes once we learn who won the election
+                // started by TimeoutNow
+                self.leadership_transfer = Some(transfer);
+            } else {
+                let _ = transfer
+                    .tx
+                    .send(Err(RaftError::NotLeader { leader_id: None }));
+            }
+        }
+    }
+
+    /// Completes the leadership transfer we started as the previous leader,
+    /// now that the new leader is known.
+    fn complete_leadership_transfer(&mut self, leader_id: NodeId) {
+        let Some(transfer) = self.leadership_transfer.take() else {
+            return;
+        };
+        if leader_id == transfer.target {
+            tracing::info!("transferred leadership to {:?}", leader_id);
+            let _ = transfer.tx.send(Ok(()));
+        } else {
+            tracing::warn!(
+                term = self.current_term,
+                "leadership was transferred to {:?} instead of {:?}",
+                leader_id,
+                transfer.target
+            );
+            let _ = transfer.tx.send(Err(RaftError::NotLeader {
+                leader_id: Some(leader_id),
+            }));
+        }
+    }
+
+    async fn become_pre_candidate(&mut self) -> Result<(), ServerError<S::Error>> {
+        tracing::info!(term = self.current_term, "became pre-candidate");
+        self.state = State::PreCandidate;
+        self.reset_election_timer();
+        self.fail_forwarded_reads();
+
+        self.leader_id = None;
+        for (node_id, node) in self.nodes.iter_mut() {
+            node.voted_for_me = *node_id == self.node_id;
+        }
+
+        // Every round asks for the same term, so a response from an earlier
+        // round can't be told apart by its term. It may have been granted
+        // while we were behind, so it must not count in this round.
+        for response in std::mem::take(&mut self.pending_pre_vote_responses) {
+            response.abort();
+        }
+
+        // Ask other servers whether they would vote for us in the next term
+        let last_log_term = self.storage.last_term().await?;
+        let last_log_index = self.storage.current_index();
+        let request = PreVote {
+            term: self.current_term + 1,
+            candidate_id: self.node_id,
+            last_log_index,
+            last_log_term,
+        };
+        for (&node_id, _) in self
+            .voters()
+            .filter(|(node_id, _)| **node_id != self.node_id)
+        {
+            let transport = self.transport.clone();
+            let request = request.clone();
+            tracing::trace!("sending PreVote to {:?}", node_id);
+            self.pending_pre_vote_responses
+                .push(tokio::spawn(async move {
+                    RpcResponse {
+                        node_id,
+                        result: transport.send_pre_vote(node_id, request).await,
+                    }
+                }));
+        }
+        Ok(())
     }
 
-    async fn become_candidate(&mut self) {
+    async fn become_candidate(&mut self) -> Result<(), ServerError<S::Error>> {
         tracing::info!(term = self.current_term, "became candidate");
         self.state = State::Candidate;
+        self.fail_forwarded_reads();
 
         // On conversion to candidate, start election:
 
         // Increment currentTerm
-        self.update_current_term(self.current_term + 1).await;
+        self.update_current_term(self.current_term + 1).await?;
 
         // Vote for self
-        self.vote_for(self.node_id).await;
+        self.vote_for(self.node_id).await?;
 
         // Reset election timer
         self.reset_election_timer();
@@ -663,7 +2396,7 @@ where
         }
 
         // Send RequestVote RPCs to all other servers
-        let last_log_term = self.storage.last_term().await.unwrap();
+        let last_log_term = self.storage.last_term().await?;
         let last_log_index = self.storage.current_index();
         let request = RequestVote {
             term: self.current_term,
@@ -671,10 +2404,9 @@ where
             last_log_index,
             last_log_term,
         };
-        for &node_id in self
-            .nodes
-            .keys()
-            .filter(|node_id| **node_id != self.node_id)
+        for (&node_id, _) in self
+            .voters()
+            .filter(|(node_id, _)| **node_id != self.node_id)
         {
             let transport = self.transport.clone();
             let request = request.clone();
@@ -687,83 +2419,230 @@ where
                     }
                 }));
         }
+        Ok(())
+    }
+
+    /// Creates an entry to append as the leader.
+    fn new_entry(&mut self, kind: EntryKind<C>) -> Entry<C> {
+        assert_eq!(self.state, State::Leader);
+        // A clock set before the epoch or too far in the future is clamped,
+        // and the timestamp still never decreases
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| {
+                since_epoch.as_millis().try_into().unwrap_or(u64::MAX)
+            });
+        self.last_timestamp = self.last_timestamp.max(now);
+        Entry {
+            kind,
+            term: self.current_term,
+            timestamp: self.last_timestamp,
+            seed: self.rng.gen(),
+        }
     }
 
-    async fn become_leader(&mut self) {
+    async fn become_leader(&mut self) -> Result<(), ServerError<S::Error>> {
         tracing::info!(term = self.current_term, "became leader");
         self.state = State::Leader;
         self.leader_id = Some(self.node_id);
+        if let Some(transfer) = self.leadership_transfer.take() {
+            // The target didn't win the election started by TimeoutNow
+            let _ = transfer.tx.send(Err(RaftError::LeadershipTransferTimeout));
+        }
+        self.check_quorum_deadline = Instant::now() + self.config.election_timeout_min;
+        self.append_entries_sent_at.clear();
+        self.lease_deadline = None;
+        self.lease_revoked = false;
 
-        self.storage
-            .append_entries(&[Entry {
-                kind: EntryKind::NoOp,
-                term: self.current_term,
-            }])
-            .await
-            .unwrap();
+        // Timestamps must not go back even if the clock of the previous
+        // leader was ahead of ours
+        let current_index = self.storage.current_index();
+        if let Some(entry) = self.storage.entry(current_index).await? {
+            self.last_timestamp = self.last_timestamp.max(entry.timestamp);
+        }
+
+        let mut entries = vec![self.new_entry(EntryKind::NoOp)];
+        if self.membership_index == 0 {
+            // Record the initial membership in the log so that nodes
+            // joining the cluster later learn it
+            entries.push(self.new_entry(EntryKind::Membership(self.membership())));
+        }
+        self.storage.append_entries(&entries).await?;
+        if self.membership_index == 0 {
+            self.membership_index = self.storage.current_index();
+        }
 
         let current_index = self.storage.current_index();
         for (node_id, node) in self.nodes.iter_mut() {
-            if *node_id == self.node_id {
-                node.match_index = current_index;
-            } else {
+            if *node_id != self.node_id {
                 node.next_index = current_index.max(1);
-                node.match_index = 0;
+                node.snapshot_offset = 0;
             }
+            node.match_index = 0;
+            node.recent_active = false;
+            node.num_in_flight = 0;
+            node.last_sent_at = None;
+            node.last_ack_at = None;
+            node.num_failed_rpcs = 0;
         }
 
         // Upon election: send initial empty AppendEntries RPCs
         // (heartbeat) to each server
-        self.send_append_entries_to_all().await;
+        self.replicate().await?;
+        Ok(())
+    }
+
+    /// Sends AppendEntries with the entries starting at nextIndex, unless
+    /// the window of RPCs in flight to `dest` is full.
+    async fn send_append_entries(&mut self, dest: NodeId) -> Result<(), ServerError<S::Error>> {
+        let node = self.nodes.get(&dest).unwrap();
+        if node.num_in_flight >= self.config.max_in_flight_appends {
+            return Ok(());
+        }
+        let prev_log_index = node.next_index - 1;
+        if prev_log_index < self.storage.snapshot_metadata().last_included_index {
+            // The entries to send have been discarded. Send the snapshot
+            // instead, one chunk at a time.
+            if node.num_in_flight == 0 {
+                self.send_snapshot_chunk(dest).await?;
+            }
+            return Ok(());
+        }
+        let prev_log_term = self.storage.term(prev_log_index).await?.unwrap_or(0);
+        let entries = self
+            .storage
+            .entries(
+                node.next_index,
+                self.config.max_entries_per_append,
+                self.config.max_bytes_per_append,
+            )
+            .await?;
+
+        // Assume that the follower accepts the entries, so that the
+        // following entries can be sent without waiting for the response
+        let node = self.nodes.get_mut(&dest).unwrap();
+        node.next_index += TryInto::<u64>::try_into(entries.len()).unwrap();
+
+        self.spawn_append_entries(dest, prev_log_index, prev_log_term, entries);
+        Ok(())
+    }
 
-        self.flush().await;
+    /// Sends AppendEntries without entries regardless of the window of RPCs
+    /// in flight, so that the follower doesn't start an election while
+    /// the RPCs in flight are slow to be answered.
+    ///
+    /// The heartbeat only covers the entries the follower is known to
+    /// have, so it doesn't interfere with the entries in flight.
+    async fn send_heartbeat(&mut self, dest: NodeId) -> Result<(), ServerError<S::Error>> {
+        let match_index = self.nodes[&dest].match_index;
+        let (prev_log_index, prev_log_term) = match self.storage.term(match_index).await? {
+            Some(term) => (match_index, term),
+
+            // The entry has been replaced by the snapshot. The follower
+            // accepts any index covered by its own snapshot.
+            None => (0, 0),
+        };
+        self.spawn_append_entries(dest, prev_log_index, prev_log_term, Vec::new());
+        Ok(())
     }
 
-    async fn spawn_append_entries_task(
+    fn spawn_append_entries(
         &mut self,
         dest: NodeId,
-    ) -> JoinHandle<RpcResponse<AppendEntriesResponse, T::Error>> {
-        let node = self.nodes.get(&dest).unwrap();
-        let prev_log_term = self
-            .storage
-            .entry(node.next_index - 1)
-            .await
-            .unwrap()
-            .map(|entry| entry.term)
-            .unwrap_or(0);
-        let entries = self.storage.entries(node.next_index).await.unwrap();
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+    ) {
         let num_entries = entries.len();
         self.last_message_index += 1;
+        let now = Instant::now();
+        if self.config.lease_read {
+            self.append_entries_sent_at
+                .push_back((self.last_message_index, now));
+        }
         let request = AppendEntries {
             term: self.current_term,
             leader_id: self.node_id,
-            prev_log_index: node.next_index - 1,
+            prev_log_index,
             prev_log_term,
             entries,
             leader_commit: self.commit_index,
             message_index: self.last_message_index,
         };
+
+        let node = self.nodes.get_mut(&dest).unwrap();
+        node.num_in_flight += 1;
+        node.last_sent_at = Some(now);
+
         let transport = self.transport.clone();
         tracing::trace!(
             "sending AppendEntries with {} entries to {:?}",
             num_entries,
             dest
         );
-        tokio::spawn(async move {
-            RpcResponse {
-                node_id: dest,
-                result: transport.send_append_entries(dest, request).await,
-            }
-        })
+        self.pending_append_entries_responses
+            .push(tokio::spawn(async move {
+                RpcResponse {
+                    node_id: dest,
+                    result: transport.send_append_entries(dest, request).await,
+                }
+            }));
+    }
+
+    async fn send_snapshot_chunk(&mut self, dest: NodeId) -> Result<(), ServerError<S::Error>> {
+        let metadata = self.storage.snapshot_metadata().clone();
+        let offset = self.nodes.get(&dest).unwrap().snapshot_offset;
+        let data = self
+            .storage
+            .read_snapshot(offset, SNAPSHOT_CHUNK_SIZE)
+            .await?;
+        let done = offset + TryInto::<u64>::try_into(data.len()).unwrap() >= metadata.size;
+        let request = InstallSnapshot {
+            term: self.current_term,
+            leader_id: self.node_id,
+            metadata,
+            offset,
+            data,
+            done,
+        };
+        let node = self.nodes.get_mut(&dest).unwrap();
+        node.num_in_flight += 1;
+        node.last_sent_at = Some(Instant::now());
+        let transport = self.transport.clone();
+        tracing::trace!(offset, done, "sending InstallSnapshot to {:?}", dest);
+        self.pending_install_snapshot_responses
+            .push(tokio::spawn(async move {
+                RpcResponse {
+                    node_id: dest,
+                    result: transport.send_install_snapshot(dest, request).await,
+                }
+            }));
+        Ok(())
     }
 }
 
 pub enum Message<C: Command> {
-    AppendEntries(AppendEntries<C>, oneshot::Sender<AppendEntriesResponse>),
-    RequestVote(RequestVote, oneshot::Sender<RequestVoteResponse>),
+    AppendEntries(
+        AppendEntries<C>,
+        oneshot::Sender<RaftResult<AppendEntriesResponse>>,
+    ),
+    RequestVote(
+        RequestVote,
+        oneshot::Sender<RaftResult<RequestVoteResponse>>,
+    ),
+    PreVote(PreVote, oneshot::Sender<RaftResult<PreVoteResponse>>),
+    InstallSnapshot(
+        InstallSnapshot,
+        oneshot::Sender<RaftResult<InstallSnapshotResponse>>,
+    ),
+    TimeoutNow(TimeoutNow, oneshot::Sender<RaftResult<TimeoutNowResponse>>),
     Write(C, oneshot::Sender<Result<C::Output, RaftError>>),
-    Read(oneshot::Sender<Result<(), RaftError>>),
+    ReadIndex(ReadIndex, oneshot::Sender<RaftResult<ReadIndexResponse>>),
+    Read(ReadResponder),
+    ChangeMembership(MembershipChange, oneshot::Sender<Result<(), RaftError>>),
+    TransferLeadership(Option<NodeId>, oneshot::Sender<Result<(), RaftError>>),
     Status(oneshot::Sender<Status>),
+    Shutdown(bool, oneshot::Sender<Result<(), RaftError>>),
 }
 
 struct RpcResponse<R, E> {
@@ -771,15 +2650,98 @@ struct RpcResponse<R, E> {
     result: Result<R, E>,
 }
 
+/// ReadIndex response, with the sender to reply to the forwarded read
+struct ReadIndexRpcResponse<E> {
+    response: RpcResponse<ReadIndexResponse, E>,
+    tx: ReadResponder,
+}
+
 #[derive(Debug)]
 struct WriteRequest<O> {
     index: u64,
-    tx: oneshot::Sender<Result<O, RaftError>>,
+    tx: WriteResponder<O>,
 }
 
 #[derive(Debug)]
 struct ReadRequest {
     index: u64,
     message_index: u64,
+    tx: ReadResponder,
+}
+
+#[derive(Debug)]
+struct ForwardedReadRequest {
+    index: u64,
+
+    /// the request fails with `NotLeader` if the state machine doesn't
+    /// reach the read index by this time
+    deadline: Instant,
+
+    tx: ReadResponder,
+}
+
+pub(crate) type WriteResponder<O> = oneshot::Sender<Result<O, RaftError>>;
+
+/// Replies with the read index, i.e. the index the state machine has to
+/// reach before the read is served
+pub(crate) type ReadResponder = oneshot::Sender<Result<u64, RaftError>>;
+
+struct LeadershipTransfer {
+    target: NodeId,
+    deadline: Instant,
+    timeout_now_sent: bool,
     tx: oneshot::Sender<Result<(), RaftError>>,
 }
+
+struct PendingSnapshot {
+    metadata: SnapshotMetadata,
+    data: BytesMut,
+}
+
+/// Snapshot of the state machine taken in the background, so that
+/// serializing and writing a large state doesn't hold up the server
+struct SnapshotTask<E> {
+    /// metadata as of the time the snapshot was requested, since the entries
+    /// applied afterwards aren't included
+    metadata: SnapshotMetadata,
+
+    step: SnapshotStep<E>,
+}
+
+enum SnapshotStep<E> {
+    /// the state machine is serializing the state
+    Serializing(BoxFuture<'static, Bytes>),
+
+    /// the storage is writing the serialized state
+    Writing(Bytes, JoinHandle<Result<(), E>>),
+}
+
+enum SnapshotProgress<E> {
+    Serialized(Bytes),
+    Written(Result<(), E>),
+}
+
+/// Waits until the snapshot task finishes its current step, or forever if
+/// no snapshot is being taken.
+async fn snapshot_progress<E>(task: &mut Option<SnapshotTask<E>>) -> SnapshotProgress<E> {
+    match task {
+        Some(SnapshotTask {
+            step: SnapshotStep::Serializing(data),
+            ..
+        }) => SnapshotProgress::Serialized(data.await),
+        Some(SnapshotTask {
+            step: SnapshotStep::Writing(_, handle),
+            ..
+        }) => SnapshotProgress::Written(handle.await.unwrap()),
+        None => std::future::pending().await,
+    }
+}
+
+/// State of the node that is reported to the event subscribers
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Published {
+    state: State,
+    leader_id: Option<NodeId>,
+    term: u64,
+    commit_index: u64,
+}
@@ -0,0 +1,1395 @@
+//! Deterministic simulation of a Raft cluster.
+//!
+//! All the servers run in a single-threaded runtime with the paused clock
+//! and talk to each other through a simulated network, which drops, delays,
+//! duplicates and reorders messages and partitions the nodes. Nodes also
+//! crash and restart from the state they persisted. Every random
+//! decision is derived from a seed, so a failing run can be reproduced with
+//! `SIMULATION_SEED=<seed> cargo test -p zakros-raft simulation`.
+//!
+//! The safety properties of Raft are checked after every step:
+//!
+//! - Election Safety: at most one leader can be elected in a given term.
+//! - Log Matching: if two logs contain an entry with the same index and term,
+//!   then the logs are identical in all entries up through the given index.
+//! - State Machine Safety: if a server has applied a log entry at a given
+//!   index to its state machine, no other server will ever apply a different
+//!   log entry for the same index.
+//!
+//! Reads are served by followers as well as the leader. A read has to
+//! observe every write acknowledged before it started, and a prefix of the
+//! history the cluster ends up applying.
+//!
+//! At the end of a run, the network is healed and all the writes
+//! acknowledged to clients have to be applied on every node.
+
+use crate::{
+    config::RaftConfig,
+    rpc::{
+        AppendEntries, AppendEntriesResponse, InstallSnapshot, InstallSnapshotResponse, PreVote,
+        PreVoteResponse, ReadIndex, ReadIndexResponse, RequestVote, RequestVoteResponse,
+        TimeoutNow, TimeoutNowResponse, Transport,
+    },
+    server::Server,
+    storage::{MemoryStorage, Storage, StorageExt},
+    ApplyContext, Command, Entry, Event, Membership, Metadata, NodeId, Raft, RaftError, RaftResult,
+    SnapshotMetadata, State, StateMachine,
+};
+use bytes::Bytes;
+use futures::{Future, FutureExt};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::timeout,
+};
+
+const NUM_NODES: u64 = 5;
+const NUM_SEEDS: u64 = 20;
+const NUM_STEPS: usize = 400;
+const STEP: Duration = Duration::from_millis(50);
+
+const MAX_DELAY: Duration = Duration::from_millis(30);
+const RPC_TIMEOUT: Duration = Duration::from_millis(100);
+const DROP_RATE: f64 = 0.05;
+const DUPLICATE_RATE: f64 = 0.05;
+
+const WRITE_RATE: f64 = 0.5;
+const READ_RATE: f64 = 0.2;
+const PARTITION_RATE: f64 = 0.03;
+const HEAL_RATE: f64 = 0.05;
+const CRASH_RATE: f64 = 0.02;
+const RESTART_RATE: f64 = 0.05;
+
+/// Upper bound of the time it takes for the healed cluster to converge.
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn simulation() {
+    let seeds = match std::env::var("SIMULATION_SEED") {
+        Ok(seed) => vec![seed.parse().unwrap()],
+        Err(_) => (0..NUM_SEEDS).collect(),
+    };
+    let mut num_follower_reads = 0;
+    for seed in seeds {
+        num_follower_reads += run(seed).num_follower_reads;
+    }
+    assert!(num_follower_reads > 0);
+}
+
+#[test]
+fn same_seed_produces_same_run() {
+    assert_eq!(run(0), run(0));
+}
+
+#[test]
+fn shutdown_fails_requests_cleanly() {
+    block_on(async {
+        let mut simulation = Simulation::new(0);
+        for _ in 0..NUM_STEPS / 4 {
+            simulation.step().await;
+        }
+        let node = simulation
+            .nodes
+            .iter_mut()
+            .find(|node| !node.is_crashed)
+            .unwrap();
+        let status = node.raft.status().await.unwrap();
+        node.raft.shutdown(true).await.unwrap();
+        (&mut node.server).await.unwrap();
+
+        let snapshot_index = node.storage.lock().snapshot_metadata().last_included_index;
+        assert!(snapshot_index >= status.last_applied_index);
+        assert!(matches!(
+            node.raft.write(TestCommand(u64::MAX)).await,
+            Err(RaftError::Shutdown)
+        ));
+        assert!(matches!(node.raft.status().await, Err(RaftError::Shutdown)));
+    });
+}
+
+#[test]
+fn leader_without_quorum_steps_down() {
+    block_on(async {
+        let simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        simulation.partition(&[leader]);
+
+        // The write can't be committed, and fails once the leader notices
+        // that it lost the majority
+        let raft = &simulation.nodes[leader].raft;
+        let result = timeout(CONVERGENCE_TIMEOUT, raft.write(TestCommand(0)))
+            .await
+            .expect("write to the isolated leader never completed");
+        assert!(matches!(result, Err(RaftError::NotLeader { .. })));
+        assert_ne!(raft.status().await.unwrap().state, State::Leader);
+    });
+}
+
+#[test]
+fn membership_change_is_rejected_while_another_is_uncommitted() {
+    block_on(async {
+        let simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let raft = &simulation.nodes[leader].raft;
+
+        // Changes can only start after an entry of the term is committed
+        raft.write(TestCommand(0)).await.unwrap();
+
+        let learner = NodeId::from(NUM_NODES);
+        let first = raft.add_learner(learner);
+        futures::pin_mut!(first);
+        assert!(first.as_mut().now_or_never().is_none());
+        assert!(matches!(
+            raft.remove_node(learner).await,
+            Err(RaftError::MembershipChangeInProgress)
+        ));
+
+        first.await.unwrap();
+        raft.remove_node(learner).await.unwrap();
+        assert!(raft.status().await.unwrap().learners.is_empty());
+    });
+}
+
+#[test]
+fn learner_replicates_without_counting_toward_commit() {
+    block_on(async {
+        let mut simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        simulation.nodes[leader]
+            .raft
+            .write(TestCommand(0))
+            .await
+            .unwrap();
+
+        let learner = simulation.add_node();
+        let raft = &simulation.nodes[leader].raft;
+        raft.add_learner(NodeId::from(learner as u64))
+            .await
+            .unwrap();
+        raft.write(TestCommand(1)).await.unwrap();
+        let deadline = tokio::time::Instant::now() + CONVERGENCE_TIMEOUT;
+        while !simulation.nodes[learner].applied.lock().contains(&1) {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(STEP).await;
+        }
+
+        // The learner doesn't make up a majority with two of the voters,
+        // so the leader can't commit and steps down
+        let voter = (leader + 1) % NUM_NODES as usize;
+        simulation.partition(&[leader, voter, learner]);
+        let result = timeout(CONVERGENCE_TIMEOUT, raft.write(TestCommand(2)))
+            .await
+            .expect("write without a majority of the voters never completed");
+        assert!(matches!(result, Err(RaftError::NotLeader { .. })));
+
+        let status = simulation.nodes[learner].raft.status().await.unwrap();
+        assert_eq!(status.state, State::Follower);
+        assert!(!simulation.nodes[learner].applied.lock().contains(&2));
+    });
+}
+
+#[test]
+fn storage_failure_fences_the_node() {
+    block_on(async {
+        let simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let node = &simulation.nodes[leader];
+        let raft = &node.raft;
+        raft.write(TestCommand(0)).await.unwrap();
+        let term = raft.status().await.unwrap().term;
+
+        node.is_storage_broken.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            raft.write(TestCommand(1)).await,
+            Err(RaftError::Storage(_))
+        ));
+
+        // The node answers everything but status requests with the error
+        // until it is restarted, even after the disk recovers
+        node.is_storage_broken.store(false, Ordering::Relaxed);
+        fn is_storage_error<T>(result: RaftResult<T>) -> bool {
+            matches!(result, Err(RaftError::Storage(_)))
+        }
+        assert!(is_storage_error(raft.write(TestCommand(2)).await));
+        assert!(is_storage_error(raft.read().await));
+        assert!(is_storage_error(
+            raft.add_learner(NodeId::from(NUM_NODES)).await
+        ));
+        assert!(is_storage_error(raft.transfer_leadership(None).await));
+        let request = RequestVote {
+            term: term + 1,
+            candidate_id: NodeId::from((leader as u64 + 1) % NUM_NODES),
+            last_log_index: u64::MAX,
+            last_log_term: u64::MAX,
+        };
+        assert!(is_storage_error(raft.request_vote(request).await));
+
+        // The rest of the cluster elects a new leader without it
+        let new_leader = simulation.wait_for_leader(&[leader]).await;
+        simulation.nodes[new_leader]
+            .raft
+            .write(TestCommand(3))
+            .await
+            .unwrap();
+        let status = raft.status().await.unwrap();
+        assert_eq!(status.state, State::Follower);
+        assert_eq!(status.leader_id, None);
+        assert_eq!(status.term, term);
+        assert!(!node.applied.lock().contains(&3));
+    });
+}
+
+#[test]
+fn rejoining_node_does_not_disrupt_the_leader() {
+    block_on(async {
+        let config = RaftConfig {
+            pre_vote: true,
+            ..Default::default()
+        };
+        let simulation = Simulation::reliable(config.clone());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let raft = &simulation.nodes[leader].raft;
+        raft.write(TestCommand(0)).await.unwrap();
+        let term = raft.status().await.unwrap().term;
+
+        // The isolated node keeps asking for pre-votes that no one
+        // answers, without raising its term
+        let isolated = (leader + 1) % NUM_NODES as usize;
+        simulation.partition(&[isolated]);
+        tokio::time::sleep(config.election_timeout_max * 10).await;
+        let status = simulation.nodes[isolated].raft.status().await.unwrap();
+        assert_eq!(status.state, State::PreCandidate);
+        assert_eq!(status.term, term);
+        raft.write(TestCommand(1)).await.unwrap();
+
+        // Once it rejoins, the others refuse its pre-votes as they hear
+        // from the leader, and it catches up as a follower
+        simulation.partition(&[]);
+        let deadline = tokio::time::Instant::now() + CONVERGENCE_TIMEOUT;
+        while !simulation.nodes[isolated].applied.lock().contains(&1) {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(STEP).await;
+        }
+        for (i, node) in simulation.nodes.iter().enumerate() {
+            let status = node.raft.status().await.unwrap();
+            assert_eq!(status.term, term, "node {i} changed its term");
+            assert_eq!(status.leader_id, Some(NodeId::from(leader as u64)));
+        }
+        assert_eq!(raft.status().await.unwrap().state, State::Leader);
+    });
+}
+
+#[test]
+fn leadership_is_transferred_to_the_target() {
+    block_on(async {
+        let simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let raft = &simulation.nodes[leader].raft;
+
+        // The target has to catch up before it takes over
+        let target = (leader + 1) % NUM_NODES as usize;
+        simulation.partition(&[target]);
+        for i in 0..10 {
+            raft.write(TestCommand(i)).await.unwrap();
+        }
+        let last_log_index = raft.status().await.unwrap().last_log_index;
+        simulation.partition(&[]);
+
+        let target_id = NodeId::from(target as u64);
+        raft.transfer_leadership(Some(target_id)).await.unwrap();
+        let status = simulation.nodes[target].raft.status().await.unwrap();
+        assert_eq!(status.state, State::Leader);
+        assert!(status.last_log_index >= last_log_index);
+
+        let non_member = NodeId::from(NUM_NODES);
+        assert!(matches!(
+            simulation.nodes[target]
+                .raft
+                .transfer_leadership(Some(non_member))
+                .await,
+            Err(RaftError::NoTransferTarget)
+        ));
+    });
+}
+
+#[test]
+fn follower_read_waits_for_the_leaders_commit_index() {
+    block_on(async {
+        let config = RaftConfig {
+            follower_read: true,
+            ..Default::default()
+        };
+        let simulation = Simulation::reliable(config.clone());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let follower = (leader + 1) % NUM_NODES as usize;
+        let raft = &simulation.nodes[follower].raft;
+
+        // The follower learns that the write is committed only from the next
+        // AppendEntries, so the read has to wait for it
+        simulation.nodes[leader]
+            .raft
+            .write(TestCommand(0))
+            .await
+            .unwrap();
+        raft.read().await.unwrap();
+        assert_eq!(*simulation.nodes[follower].applied.lock(), vec![0]);
+
+        // A follower that can't catch up fails the read once the deadline
+        // passes, although it still hears from the leader
+        simulation
+            .network
+            .lagging
+            .lock()
+            .insert(NodeId::from(follower as u64));
+        simulation.nodes[leader]
+            .raft
+            .write(TestCommand(1))
+            .await
+            .unwrap();
+        let start = tokio::time::Instant::now();
+        let result = timeout(CONVERGENCE_TIMEOUT, raft.read())
+            .await
+            .expect("read on the lagging follower never completed");
+        assert!(matches!(result, Err(RaftError::NotLeader { .. })));
+        assert!(start.elapsed() >= config.election_timeout_max);
+        assert_eq!(*simulation.nodes[follower].applied.lock(), vec![0]);
+        let status = raft.status().await.unwrap();
+        assert_eq!(status.state, State::Follower);
+        assert_eq!(status.leader_id, Some(NodeId::from(leader as u64)));
+    });
+}
+
+#[test]
+fn lease_expires_without_acknowledgments() {
+    block_on(async {
+        let config = RaftConfig {
+            lease_read: true,
+            // Keeps the isolated leader from stepping down, so that only
+            // the lease stops it from serving reads
+            check_quorum: false,
+            ..Default::default()
+        };
+        let simulation = Simulation::reliable(config.clone());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let raft = &simulation.nodes[leader].raft;
+        raft.write(TestCommand(0)).await.unwrap();
+
+        // The lease lets the leader serve the read without a round trip
+        simulation.partition(&[leader]);
+        let start = tokio::time::Instant::now();
+        raft.read().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The other nodes may have elected a new leader by now
+        tokio::time::sleep(config.election_timeout_min).await;
+        let result = timeout(config.election_timeout_max, raft.read()).await;
+        assert!(!matches!(result, Ok(Ok(()))));
+    });
+}
+
+#[test]
+fn queued_writes_are_persisted_at_once() {
+    block_on(async {
+        let simulation = Simulation::reliable(RaftConfig::default());
+        let leader = simulation.wait_for_leader(&[]).await;
+        let node = &simulation.nodes[leader];
+        node.raft.write(TestCommand(0)).await.unwrap();
+
+        // The writes are all queued before the server handles the first
+        let num_persists = node.num_persists.load(Ordering::Relaxed);
+        let writes: Vec<_> = (1..100)
+            .map(|i| {
+                let raft = node.raft.clone();
+                tokio::spawn(async move { raft.write(TestCommand(i)).await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(node.num_persists.load(Ordering::Relaxed), num_persists + 1);
+    });
+}
+
+#[test]
+fn diverged_follower_is_caught_up_in_few_round_trips() {
+    block_on(async {
+        let config = RaftConfig {
+            // Keeps the isolated leader appending entries in its term
+            check_quorum: false,
+            ..Default::default()
+        };
+        let simulation = Simulation::reliable(config);
+        let old_leader = simulation.wait_for_leader(&[]).await;
+        simulation.nodes[old_leader]
+            .raft
+            .write(TestCommand(0))
+            .await
+            .unwrap();
+
+        // Both sides of the partition append 50 entries in their own terms
+        simulation.partition(&[old_leader]);
+        for i in 1..=50 {
+            let raft = simulation.nodes[old_leader].raft.clone();
+            tokio::spawn(async move { raft.write(TestCommand(i)).await });
+        }
+        let new_leader = simulation.wait_for_leader(&[old_leader]).await;
+        for i in 51..=100 {
+            simulation.nodes[new_leader]
+                .raft
+                .write(TestCommand(i))
+                .await
+                .unwrap();
+        }
+
+        // Without the conflict hints, the new leader would go back one
+        // entry per rejection
+        simulation.network.rejected_appends.lock().clear();
+        simulation.partition(&[]);
+        let expected = simulation.nodes[new_leader].applied.lock().clone();
+        let deadline = tokio::time::Instant::now() + CONVERGENCE_TIMEOUT;
+        while *simulation.nodes[old_leader].applied.lock() != expected {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "logs didn't converge"
+            );
+            tokio::time::sleep(STEP).await;
+        }
+        let old_leader_id = NodeId::from(old_leader as u64);
+        let num_rejected = simulation.network.rejected_appends.lock()[&old_leader_id];
+        assert!(num_rejected <= 2, "{num_rejected} appends were rejected");
+    });
+}
+
+/// Runs a simulation and returns its observable outcome.
+fn run(seed: u64) -> Outcome {
+    block_on(async {
+        let mut simulation = Simulation::new(seed);
+        for _ in 0..NUM_STEPS {
+            simulation.step().await;
+        }
+        simulation.converge().await
+    })
+}
+
+/// Runs the future in a single-threaded runtime with the paused clock.
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    leaders: BTreeMap<u64, NodeId>,
+    applied: Vec<u64>,
+    acknowledged: BTreeSet<u64>,
+    num_follower_reads: usize,
+}
+
+struct Simulation {
+    seed: u64,
+    config: RaftConfig,
+    rng: StdRng,
+    network: Arc<Network>,
+    nodes: Vec<SimNode>,
+    next_command: u64,
+    acknowledged: Arc<Mutex<BTreeSet<u64>>>,
+    reads: Arc<Mutex<Vec<Read>>>,
+
+    /// leader observed in each term
+    leaders: BTreeMap<u64, NodeId>,
+}
+
+/// Read that succeeded
+struct Read {
+    /// writes acknowledged before the read started
+    acknowledged: BTreeSet<u64>,
+
+    /// commands the node had applied when the read completed
+    observed: Vec<u64>,
+
+    on_follower: bool,
+}
+
+struct SimNode {
+    raft: Raft<TestCommand>,
+    events: broadcast::Receiver<Event>,
+
+    /// term and commit index reported by the last events
+    last_event_term: u64,
+    last_event_commit_index: u64,
+
+    storage: Arc<Mutex<MemoryStorage<TestCommand>>>,
+
+    /// term and vote persisted by the server
+    metadata: Arc<Mutex<Metadata>>,
+
+    applied: Arc<Mutex<Vec<u64>>>,
+    server: JoinHandle<()>,
+
+    /// number of times the node made its log durable
+    num_persists: Arc<AtomicUsize>,
+
+    /// index of the last entry that survives a crash
+    persisted_index: Arc<AtomicU64>,
+
+    /// true if the storage fails every write from now on
+    is_storage_broken: Arc<AtomicBool>,
+
+    /// true if the node has crashed and not been restarted yet
+    is_crashed: bool,
+}
+
+impl SimNode {
+    /// Starts a server that recovers the state persisted in `storage` and
+    /// `metadata`.
+    fn start(
+        node_id: NodeId,
+        config: &RaftConfig,
+        network: &Arc<Network>,
+        storage: Arc<Mutex<MemoryStorage<TestCommand>>>,
+        metadata: Arc<Mutex<Metadata>>,
+        seed: u64,
+    ) -> Self {
+        let node_ids: Vec<NodeId> = (0..NUM_NODES).map(NodeId::from).collect();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let num_persists = Arc::new(AtomicUsize::new(0));
+        let persisted_index = Arc::new(AtomicU64::new(storage.lock().current_index()));
+        let is_storage_broken = Arc::new(AtomicBool::new(false));
+        let snapshot_metadata = storage.lock().snapshot_metadata().clone();
+        let mut server = Server::new(
+            node_id,
+            Membership::new(node_ids, []),
+            config.clone(),
+            TestStateMachine {
+                applied: applied.clone(),
+                last_context: None,
+            },
+            SimStorage {
+                inner: storage.clone(),
+                snapshot_metadata,
+                metadata: metadata.clone(),
+                num_persists: num_persists.clone(),
+                persisted_index: persisted_index.clone(),
+                is_broken: is_storage_broken.clone(),
+            },
+            Arc::new(SimTransport {
+                node_id,
+                network: network.clone(),
+            }),
+            rx,
+        );
+        server.seed_rng(seed);
+        let raft = Raft {
+            tx,
+            events: server.events().clone(),
+        };
+        network.nodes.lock().insert(node_id, raft.clone());
+        Self {
+            events: raft.subscribe(),
+            last_event_term: 0,
+            last_event_commit_index: 0,
+            raft,
+            storage,
+            metadata,
+            applied,
+            server: tokio::spawn(async move { server.run().await }),
+            num_persists,
+            persisted_index,
+            is_storage_broken,
+            is_crashed: false,
+        }
+    }
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Self {
+        Self::with_config(
+            seed,
+            RaftConfig {
+                // Small enough to take snapshots and split appends frequently
+                snapshot_threshold: 64,
+                max_entries_per_append: 8,
+                max_in_flight_appends: 4,
+                follower_read: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Returns a simulation whose network delivers every message in order,
+    /// for tests of specific scenarios.
+    fn reliable(config: RaftConfig) -> Self {
+        let simulation = Self::with_config(0, config);
+        simulation.network.heal();
+        simulation
+    }
+
+    fn with_config(seed: u64, config: RaftConfig) -> Self {
+        let network = Arc::new(Network::new(seed));
+        let nodes = (0..NUM_NODES)
+            .map(|i| {
+                SimNode::start(
+                    NodeId::from(i),
+                    &config,
+                    &network,
+                    Default::default(),
+                    Default::default(),
+                    seed ^ i,
+                )
+            })
+            .collect();
+        Self {
+            seed,
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            network,
+            nodes,
+            next_command: 0,
+            acknowledged: Default::default(),
+            reads: Default::default(),
+            leaders: Default::default(),
+        }
+    }
+
+    async fn step(&mut self) {
+        if self.rng.gen_bool(PARTITION_RATE) {
+            let mut node_ids: Vec<NodeId> = (0..NUM_NODES).map(NodeId::from).collect();
+            node_ids.shuffle(&mut self.rng);
+            let len = self.rng.gen_range(1..NUM_NODES as usize);
+            *self.network.partition.lock() = node_ids[..len].iter().copied().collect();
+        } else if self.rng.gen_bool(HEAL_RATE) {
+            self.network.partition.lock().clear();
+        }
+        if self.rng.gen_bool(CRASH_RATE) {
+            // Crash at most a minority of the nodes so that the cluster
+            // can make progress
+            let i = self.rng.gen_range(0..self.nodes.len());
+            let num_crashed = self.nodes.iter().filter(|node| node.is_crashed).count();
+            if num_crashed < self.nodes.len() / 2 {
+                self.crash(i);
+            }
+        } else if self.rng.gen_bool(RESTART_RATE) {
+            self.restart_crashed();
+        }
+        if self.rng.gen_bool(WRITE_RATE) {
+            let node = self.nodes.choose(&mut self.rng).unwrap();
+            let raft = node.raft.clone();
+            let command = self.next_command;
+            self.next_command += 1;
+            let acknowledged = self.acknowledged.clone();
+            tokio::spawn(async move {
+                if raft.write(TestCommand(command)).await.is_ok() {
+                    acknowledged.lock().insert(command);
+                }
+            });
+        }
+        if self.rng.gen_bool(READ_RATE) {
+            let node = self.nodes.choose(&mut self.rng).unwrap();
+            let raft = node.raft.clone();
+            let applied = node.applied.clone();
+            let acknowledged = self.acknowledged.lock().clone();
+            let reads = self.reads.clone();
+            tokio::spawn(async move {
+                let Ok(status) = raft.status().await else {
+                    return;
+                };
+                if raft.read().await.is_ok() {
+                    let observed = applied.lock().clone();
+                    reads.lock().push(Read {
+                        acknowledged,
+                        observed,
+                        on_follower: status.state != State::Leader,
+                    });
+                }
+            });
+        }
+        tokio::time::sleep(STEP).await;
+        self.check_invariants().await;
+    }
+
+    /// Kills the node, which loses everything but the state it persisted.
+    fn crash(&mut self, i: usize) {
+        let node = &mut self.nodes[i];
+        if node.is_crashed {
+            return;
+        }
+        node.server.abort();
+        node.is_crashed = true;
+
+        // The entries appended since the log was last made durable are lost
+        let mut storage = node.storage.lock();
+        let start = (node.persisted_index.load(Ordering::Relaxed) + 1)
+            .max(storage.snapshot_metadata().last_included_index + 1);
+        if start <= storage.current_index() {
+            storage
+                .truncate_entries(start)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        drop(storage);
+        self.network.nodes.lock().remove(&NodeId::from(i as u64));
+    }
+
+    /// Restarts the crashed nodes from their storage.
+    fn restart_crashed(&mut self) {
+        for i in 0..self.nodes.len() {
+            let node = &self.nodes[i];
+            if node.is_crashed {
+                self.nodes[i] = SimNode::start(
+                    NodeId::from(i as u64),
+                    &self.config,
+                    &self.network,
+                    node.storage.clone(),
+                    node.metadata.clone(),
+                    self.rng.gen(),
+                );
+            }
+        }
+    }
+
+    /// Starts a node that isn't a member of the cluster yet, and returns its
+    /// index.
+    fn add_node(&mut self) -> usize {
+        let i = self.nodes.len();
+        self.nodes.push(SimNode::start(
+            NodeId::from(i as u64),
+            &self.config,
+            &self.network,
+            Default::default(),
+            Default::default(),
+            self.rng.gen(),
+        ));
+        i
+    }
+
+    /// Waits until one of the nodes other than `excluded` becomes the
+    /// leader, and returns its index.
+    async fn wait_for_leader(&self, excluded: &[usize]) -> usize {
+        let deadline = tokio::time::Instant::now() + CONVERGENCE_TIMEOUT;
+        loop {
+            for (i, node) in self.nodes.iter().enumerate() {
+                if !excluded.contains(&i)
+                    && node.raft.status().await.unwrap().state == State::Leader
+                {
+                    return i;
+                }
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "no leader was elected"
+            );
+            tokio::time::sleep(STEP).await;
+        }
+    }
+
+    /// Cuts the nodes off from the rest of the cluster.
+    fn partition(&self, nodes: &[usize]) {
+        *self.network.partition.lock() = nodes.iter().map(|&i| NodeId::from(i as u64)).collect();
+    }
+
+    /// Heals the network, restarts the crashed nodes, and waits until all
+    /// the nodes commit the same log and apply the same entries.
+    async fn converge(mut self) -> Outcome {
+        self.network.heal();
+        self.restart_crashed();
+        let deadline = tokio::time::Instant::now() + CONVERGENCE_TIMEOUT;
+        loop {
+            self.check_invariants().await;
+            // The whole log, including the trailing NoOp and membership
+            // entries, has to be committed and applied everywhere
+            let mut progress = BTreeSet::new();
+            for node in &self.nodes {
+                let status = node.raft.status().await.unwrap();
+                progress.insert((
+                    status.last_applied_index,
+                    status.commit_index,
+                    status.last_log_index,
+                ));
+            }
+            let is_log_applied = progress.len() == 1
+                && progress
+                    .first()
+                    .is_some_and(|(applied, commit, last)| applied == commit && commit == last);
+            let applied: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|node| node.applied.lock().clone())
+                .collect();
+            if is_log_applied && applied.windows(2).all(|w| w[0] == w[1]) {
+                let applied = applied.into_iter().next().unwrap();
+                let acknowledged = self.acknowledged.lock().clone();
+                for command in &acknowledged {
+                    assert!(
+                        applied.contains(command),
+                        "seed {}: acknowledged write {} was lost",
+                        self.seed,
+                        command
+                    );
+                }
+                let reads = std::mem::take(&mut *self.reads.lock());
+                for read in &reads {
+                    assert!(
+                        applied.starts_with(&read.observed),
+                        "seed {}: read observed a state that was never applied",
+                        self.seed
+                    );
+                    for command in &read.acknowledged {
+                        assert!(
+                            read.observed.contains(command),
+                            "seed {}: read missed acknowledged write {}",
+                            self.seed,
+                            command
+                        );
+                    }
+                }
+                return Outcome {
+                    leaders: self.leaders,
+                    applied,
+                    acknowledged,
+                    num_follower_reads: reads.iter().filter(|read| read.on_follower).count(),
+                };
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "seed {}: cluster did not converge",
+                self.seed
+            );
+            tokio::time::sleep(STEP).await;
+        }
+    }
+
+    async fn check_invariants(&mut self) {
+        let seed = self.seed;
+        for (i, node) in self.nodes.iter().enumerate() {
+            assert!(
+                node.is_crashed || !node.server.is_finished(),
+                "seed {seed}: node {i} stopped"
+            );
+        }
+
+        // Election Safety, and consistency of the reported progress
+        for node in self.nodes.iter().filter(|node| !node.is_crashed) {
+            let status = node.raft.status().await.unwrap();
+            assert!(
+                status.last_applied_index <= status.commit_index
+                    && status.commit_index <= status.last_log_index,
+                "seed {seed}: node {:?} applied entries beyond its log",
+                status.node_id
+            );
+            if status.state != State::Leader {
+                assert!(status.peers.is_empty());
+                continue;
+            }
+            for (peer_id, peer) in &status.peers {
+                assert!(
+                    peer.match_index <= status.last_log_index,
+                    "seed {seed}: {:?} matched entries that the leader doesn't have",
+                    peer_id
+                );
+            }
+            let leader_id = *self.leaders.entry(status.term).or_insert(status.node_id);
+            assert_eq!(
+                leader_id, status.node_id,
+                "seed {seed}: two leaders were elected in term {}",
+                status.term
+            );
+        }
+
+        // Election Safety as observed through the events, which also
+        // report the leaders elected between the steps
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if node.is_crashed {
+                continue;
+            }
+            let node_id = NodeId::from(i as u64);
+            loop {
+                match node.events.try_recv() {
+                    Ok(Event::TermChanged { term }) => {
+                        assert!(
+                            term > node.last_event_term,
+                            "seed {seed}: term of node {i} went back"
+                        );
+                        node.last_event_term = term;
+                    }
+                    Ok(Event::StateChanged {
+                        state: State::Leader,
+                        term,
+                    }) => {
+                        let leader_id = *self.leaders.entry(term).or_insert(node_id);
+                        assert_eq!(
+                            leader_id, node_id,
+                            "seed {seed}: two leaders were elected in term {}",
+                            term
+                        );
+                    }
+                    Ok(Event::Committed { index }) => {
+                        assert!(
+                            index > node.last_event_commit_index,
+                            "seed {seed}: commit index of node {i} went back"
+                        );
+                        node.last_event_commit_index = index;
+                    }
+                    Ok(_) => (),
+                    Err(broadcast::error::TryRecvError::Empty) => break,
+                    Err(err) => panic!("seed {seed}: node {i} missed events: {err}"),
+                }
+            }
+        }
+
+        // Log Matching
+        let logs: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let mut storage = node.storage.lock();
+                let start = storage.snapshot_metadata().last_included_index + 1;
+                let entries = storage
+                    .entries(start, usize::MAX, u64::MAX)
+                    .now_or_never()
+                    .unwrap()
+                    .unwrap();
+                (start, entries)
+            })
+            .collect();
+        for (i, (start_a, log_a)) in logs.iter().enumerate() {
+            for (start_b, log_b) in &logs[i + 1..] {
+                let start = *start_a.max(start_b);
+                let end = (start_a + log_a.len() as u64).min(start_b + log_b.len() as u64);
+                let entry_a = |index: u64| &log_a[(index - start_a) as usize];
+                let entry_b = |index: u64| &log_b[(index - start_b) as usize];
+                let Some(last_match) = (start..end)
+                    .rev()
+                    .find(|&index| entry_a(index).term == entry_b(index).term)
+                else {
+                    continue;
+                };
+                for index in start..=last_match {
+                    assert_eq!(
+                        serialize(entry_a(index)),
+                        serialize(entry_b(index)),
+                        "seed {seed}: logs differ at index {index}"
+                    );
+                }
+            }
+        }
+
+        // State Machine Safety
+        let applied: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| node.applied.lock().clone())
+            .collect();
+        for (i, a) in applied.iter().enumerate() {
+            for b in &applied[i + 1..] {
+                let len = a.len().min(b.len());
+                assert_eq!(
+                    a[..len],
+                    b[..len],
+                    "seed {seed}: state machines applied different commands"
+                );
+            }
+        }
+    }
+}
+
+fn serialize(entry: &Entry<TestCommand>) -> Vec<u8> {
+    bincode::serialize(entry).unwrap()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TestCommand(u64);
+
+impl Command for TestCommand {
+    type Output = ();
+}
+
+/// Records the applied commands in order.
+struct TestStateMachine {
+    applied: Arc<Mutex<Vec<u64>>>,
+    last_context: Option<ApplyContext>,
+}
+
+impl StateMachine for TestStateMachine {
+    type Command = TestCommand;
+    type Error = bincode::Error;
+
+    async fn apply_batch(&mut self, commands: Vec<(ApplyContext, TestCommand)>) -> Vec<()> {
+        let mut applied = self.applied.lock();
+        for (context, command) in &commands {
+            if let Some(last) = self.last_context {
+                assert!(context.index > last.index);
+                assert!(context.term >= last.term);
+                assert!(context.timestamp >= last.timestamp);
+            }
+            self.last_context = Some(*context);
+            applied.push(command.0);
+        }
+        vec![(); commands.len()]
+    }
+
+    async fn snapshot(&self) -> Bytes {
+        bincode::serialize(&*self.applied.lock()).unwrap().into()
+    }
+
+    async fn restore(&mut self, snapshot: Bytes) -> bincode::Result<()> {
+        *self.applied.lock() = bincode::deserialize(&snapshot)?;
+        self.last_context = None;
+        Ok(())
+    }
+}
+
+/// `MemoryStorage` that the simulation can inspect while the server owns it.
+///
+/// Only the metadata, the snapshot, and the entries up to `persisted_index`
+/// are durable, so a crash loses the entries appended after them.
+struct SimStorage {
+    inner: Arc<Mutex<MemoryStorage<TestCommand>>>,
+    snapshot_metadata: SnapshotMetadata,
+    metadata: Arc<Mutex<Metadata>>,
+    num_persists: Arc<AtomicUsize>,
+    persisted_index: Arc<AtomicU64>,
+
+    /// true if every write fails, as it does on a broken disk
+    is_broken: Arc<AtomicBool>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum SimStorageError {
+    #[error("injected storage fault")]
+    Broken,
+
+    #[error(transparent)]
+    Memory(#[from] <MemoryStorage<TestCommand> as Storage>::Error),
+}
+
+impl SimStorage {
+    fn check_broken(&self) -> Result<(), SimStorageError> {
+        if self.is_broken.load(Ordering::Relaxed) {
+            return Err(SimStorageError::Broken);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SimStorage {
+    type Command = TestCommand;
+    type Error = SimStorageError;
+
+    async fn load(&mut self) -> Result<Metadata, Self::Error> {
+        Ok(self.metadata.lock().clone())
+    }
+
+    fn num_entries(&self) -> usize {
+        self.inner.lock().num_entries()
+    }
+
+    async fn entry(&mut self, index: u64) -> Result<Option<Entry<TestCommand>>, Self::Error> {
+        Ok(self.inner.lock().entry(index).now_or_never().unwrap()?)
+    }
+
+    async fn entries(
+        &mut self,
+        start: u64,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<Entry<TestCommand>>, Self::Error> {
+        Ok(self
+            .inner
+            .lock()
+            .entries(start, max_entries, max_bytes)
+            .now_or_never()
+            .unwrap()?)
+    }
+
+    async fn append_entries(&mut self, entries: &[Entry<TestCommand>]) -> Result<(), Self::Error> {
+        self.check_broken()?;
+        Ok(self
+            .inner
+            .lock()
+            .append_entries(entries)
+            .now_or_never()
+            .unwrap()?)
+    }
+
+    async fn truncate_entries(&mut self, index: u64) -> Result<(), Self::Error> {
+        self.check_broken()?;
+        self.persisted_index.fetch_min(index - 1, Ordering::Relaxed);
+        Ok(self
+            .inner
+            .lock()
+            .truncate_entries(index)
+            .now_or_never()
+            .unwrap()?)
+    }
+
+    fn snapshot_metadata(&self) -> &SnapshotMetadata {
+        &self.snapshot_metadata
+    }
+
+    async fn read_snapshot(&mut self, offset: u64, len: usize) -> Result<Bytes, Self::Error> {
+        Ok(self
+            .inner
+            .lock()
+            .read_snapshot(offset, len)
+            .now_or_never()
+            .unwrap()?)
+    }
+
+    fn write_snapshot(
+        &self,
+        _metadata: SnapshotMetadata,
+        _data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static {
+        futures::future::ready(self.check_broken())
+    }
+
+    async fn commit_snapshot(
+        &mut self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> Result<(), Self::Error> {
+        self.check_broken()?;
+        self.snapshot_metadata = metadata.clone();
+        let index = metadata.last_included_index;
+        let mut inner = self.inner.lock();
+        inner
+            .commit_snapshot(metadata, data)
+            .now_or_never()
+            .unwrap()?;
+        // The entries following a snapshot that replaced the entire log
+        // haven't been persisted
+        if inner.num_entries() == 0 {
+            self.persisted_index.fetch_min(index, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    async fn persist_metadata(&mut self, metadata: &Metadata) -> Result<(), Self::Error> {
+        self.check_broken()?;
+        *self.metadata.lock() = metadata.clone();
+        Ok(())
+    }
+
+    async fn persist_entries(&mut self) -> Result<(), Self::Error> {
+        self.check_broken()?;
+        self.num_persists.fetch_add(1, Ordering::Relaxed);
+        self.persisted_index
+            .store(self.inner.lock().current_index(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum NetworkError {
+    #[error("request timed out")]
+    Timeout,
+
+    #[error(transparent)]
+    Raft(#[from] RaftError),
+}
+
+struct Network {
+    rng: Mutex<StdRng>,
+    nodes: Mutex<BTreeMap<NodeId, Raft<TestCommand>>>,
+
+    /// nodes that are cut off from the rest of the cluster
+    partition: Mutex<BTreeSet<NodeId>>,
+
+    /// whether messages are dropped, duplicated and delayed randomly
+    faulty: Mutex<bool>,
+
+    /// number of AppendEntries RPCs rejected by each node
+    rejected_appends: Mutex<BTreeMap<NodeId, usize>>,
+
+    /// nodes that don't receive new entries, but still receive heartbeats
+    /// and the other RPCs, so they fall behind without losing the leader
+    lagging: Mutex<BTreeSet<NodeId>>,
+}
+
+impl Network {
+    fn new(seed: u64) -> Self {
+        Self {
+            // Keeps the network's decisions independent of the number of
+            // decisions the simulation makes
+            rng: Mutex::new(StdRng::seed_from_u64(!seed)),
+            nodes: Default::default(),
+            partition: Default::default(),
+            faulty: Mutex::new(true),
+            rejected_appends: Default::default(),
+            lagging: Default::default(),
+        }
+    }
+
+    fn heal(&self) {
+        self.partition.lock().clear();
+        *self.faulty.lock() = false;
+    }
+
+    fn is_connected(&self, a: NodeId, b: NodeId) -> bool {
+        let partition = self.partition.lock();
+        partition.contains(&a) == partition.contains(&b)
+    }
+
+    /// Returns true with the given probability if the network is faulty.
+    fn chance(&self, probability: f64) -> bool {
+        *self.faulty.lock() && self.rng.lock().gen_bool(probability)
+    }
+
+    /// Random delays reorder the messages.
+    fn delay(&self) -> Duration {
+        if *self.faulty.lock() {
+            self.rng.lock().gen_range(Duration::ZERO..=MAX_DELAY)
+        } else {
+            Duration::from_millis(1)
+        }
+    }
+
+    async fn send<Req, Resp, F, Fut>(
+        &self,
+        src: NodeId,
+        dest: NodeId,
+        request: Req,
+        call: F,
+    ) -> Result<Resp, NetworkError>
+    where
+        Req: Clone,
+        F: Fn(Raft<TestCommand>, Req) -> Fut,
+        Fut: Future<Output = RaftResult<Resp>> + Send + 'static,
+    {
+        tokio::time::sleep(self.delay()).await;
+        let raft = self.nodes.lock().get(&dest).cloned();
+        let Some(raft) = raft.filter(|_| self.is_connected(src, dest) && !self.chance(DROP_RATE))
+        else {
+            tokio::time::sleep(RPC_TIMEOUT).await;
+            return Err(NetworkError::Timeout);
+        };
+        if self.chance(DUPLICATE_RATE) {
+            let delay = self.delay();
+            let duplicate = call(raft.clone(), request.clone());
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = duplicate.await;
+            });
+        }
+        let response = call(raft, request).await.map_err(NetworkError::Raft)?;
+        tokio::time::sleep(self.delay()).await;
+        if !self.is_connected(src, dest) || self.chance(DROP_RATE) {
+            tokio::time::sleep(RPC_TIMEOUT).await;
+            return Err(NetworkError::Timeout);
+        }
+        Ok(response)
+    }
+}
+
+struct SimTransport {
+    node_id: NodeId,
+    network: Arc<Network>,
+}
+
+impl Transport for SimTransport {
+    type Command = TestCommand;
+    type Error = NetworkError;
+
+    async fn send_append_entries(
+        &self,
+        dest: NodeId,
+        request: AppendEntries<TestCommand>,
+    ) -> Result<AppendEntriesResponse, Self::Error> {
+        if !request.entries.is_empty() && self.network.lagging.lock().contains(&dest) {
+            tokio::time::sleep(RPC_TIMEOUT).await;
+            return Err(NetworkError::Timeout);
+        }
+        let response = self
+            .network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.append_entries(request).await
+            })
+            .await?;
+        if !response.success {
+            *self
+                .network
+                .rejected_appends
+                .lock()
+                .entry(dest)
+                .or_default() += 1;
+        }
+        Ok(response)
+    }
+
+    async fn send_request_vote(
+        &self,
+        dest: NodeId,
+        request: RequestVote,
+    ) -> Result<RequestVoteResponse, Self::Error> {
+        self.network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.request_vote(request).await
+            })
+            .await
+    }
+
+    async fn send_pre_vote(
+        &self,
+        dest: NodeId,
+        request: PreVote,
+    ) -> Result<PreVoteResponse, Self::Error> {
+        self.network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.pre_vote(request).await
+            })
+            .await
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        dest: NodeId,
+        request: InstallSnapshot,
+    ) -> Result<InstallSnapshotResponse, Self::Error> {
+        self.network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.install_snapshot(request).await
+            })
+            .await
+    }
+
+    async fn send_timeout_now(
+        &self,
+        dest: NodeId,
+        request: TimeoutNow,
+    ) -> Result<TimeoutNowResponse, Self::Error> {
+        self.network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.timeout_now(request).await
+            })
+            .await
+    }
+
+    async fn send_read_index(
+        &self,
+        dest: NodeId,
+        request: ReadIndex,
+    ) -> Result<ReadIndexResponse, Self::Error> {
+        self.network
+            .send(self.node_id, dest, request, |raft, request| async move {
+                raft.read_index(request).await
+            })
+            .await
+    }
+}
@@ -1,30 +1,47 @@
 mod disk;
 mod memory;
 
-pub use disk::{DiskStorage, DiskStorageError};
+pub use disk::{DiskStorage, DiskStorageBuilder, DiskStorageError};
 pub use memory::MemoryStorage;
 
 use super::{Entry, Metadata};
-use crate::Command;
+use crate::{Command, SnapshotMetadata};
+use bytes::Bytes;
 use futures::Future;
 
-// TODO: support log compaction
-
 pub trait Storage: Send + Sync + 'static {
     type Command: Command;
     type Error: Send + std::fmt::Debug;
 
     fn load(&mut self) -> impl Future<Output = Result<Metadata, Self::Error>> + Send;
 
+    /// Returns the number of entries in the log, excluding the entries
+    /// replaced by the snapshot.
     fn num_entries(&self) -> usize;
+
+    /// Returns the entry at `index`, or `None` if the log doesn't contain it
+    /// or it has been replaced by the snapshot.
     fn entry(
         &mut self,
         index: u64,
     ) -> impl Future<Output = Result<Option<Entry<Self::Command>>, Self::Error>> + Send;
+
+    /// Returns at most `max_entries` entries starting at `start`.
+    ///
+    /// Entries are returned as long as their total serialized size doesn't
+    /// exceed `max_bytes`, except that the first entry is always returned
+    /// if the log contains it.
     fn entries(
         &mut self,
         start: u64,
+        max_entries: usize,
+        max_bytes: u64,
     ) -> impl Future<Output = Result<Vec<Entry<Self::Command>>, Self::Error>> + Send;
+
+    /// Appends entries to the log.
+    ///
+    /// The entries don't have to be durable until `persist_entries` is
+    /// called.
     fn append_entries(
         &mut self,
         entries: &[Entry<Self::Command>],
@@ -34,28 +51,99 @@ pub trait Storage: Send + Sync + 'static {
         index: u64,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
+    fn snapshot_metadata(&self) -> &SnapshotMetadata;
+    fn read_snapshot(
+        &mut self,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = Result<Bytes, Self::Error>> + Send;
+
+    /// Persists the snapshot and discards the entries replaced by it.
+    ///
+    /// If the log contains an entry with the same index and term as the
+    /// snapshot's last included entry, the entries following it are
+    /// retained. Otherwise, the entire log is discarded.
+    fn install_snapshot(
+        &mut self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move {
+            self.write_snapshot(metadata.clone(), data.clone()).await?;
+            self.commit_snapshot(metadata, data).await
+        }
+    }
+
+    /// Makes the snapshot durable without replacing the current one.
+    ///
+    /// The returned future doesn't borrow the storage, so that a large
+    /// snapshot can be written in the background while the log is in use.
+    /// Only one snapshot is written at a time.
+    fn write_snapshot(
+        &self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static;
+
+    /// Replaces the current snapshot with the one written by
+    /// `write_snapshot`, and discards the entries replaced by it as
+    /// `install_snapshot` does.
+    fn commit_snapshot(
+        &mut self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
     fn persist_metadata(
         &mut self,
         metadata: &Metadata,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Makes the appended entries durable.
     fn persist_entries(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
 pub(crate) trait StorageExt: Storage {
     fn current_index(&self) -> u64 {
-        self.num_entries().try_into().unwrap()
+        self.snapshot_metadata().last_included_index
+            + TryInto::<u64>::try_into(self.num_entries()).unwrap()
     }
 
     async fn last_term(&mut self) -> Result<u64, <Self as Storage>::Error> {
         let index = self.current_index();
-        Ok(if index > 0 {
-            self.entry(index)
-                .await?
-                .map(|entry| entry.term)
-                .unwrap_or(0)
-        } else {
-            0
-        })
+        Ok(self.term(index).await?.unwrap_or(0))
+    }
+
+    /// Returns the first index in `start..=current_index()` whose entry has
+    /// a term greater than `term`, or `current_index() + 1` if there is
+    /// none. `start` must not precede the snapshot's last included index.
+    ///
+    /// Terms never decrease along the log, so this is a binary search.
+    async fn first_index_after_term(
+        &mut self,
+        term: u64,
+        start: u64,
+    ) -> Result<u64, <Self as Storage>::Error> {
+        let (mut low, mut high) = (start, self.current_index() + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.term(mid).await?.unwrap_or(0) > term {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Returns the term of the entry at `index`, or `None` if it is unknown
+    /// because the entry has been replaced by the snapshot or doesn't exist.
+    async fn term(&mut self, index: u64) -> Result<Option<u64>, <Self as Storage>::Error> {
+        let snapshot_metadata = self.snapshot_metadata();
+        if index == snapshot_metadata.last_included_index {
+            return Ok(Some(snapshot_metadata.last_included_term));
+        }
+        Ok(self.entry(index).await?.map(|entry| entry.term))
     }
 }
 
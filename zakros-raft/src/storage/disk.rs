@@ -1,25 +1,32 @@
 use super::Storage;
-use crate::{Command, Entry, Metadata};
+use crate::{Command, Entry, Metadata, SnapshotMetadata};
 use bincode::Options;
-use byteorder::{WriteBytesExt, LE};
-use bytes::{Buf, BufMut, BytesMut};
-use futures::{SinkExt, StreamExt};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Future, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{io::SeekFrom, marker::PhantomData, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
 };
-use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
-
-// TODO: checksum
-// TODO: in-memory cache for recent entries
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
 #[derive(Debug, thiserror::Error)]
 pub enum DiskStorageError {
     #[error("index is too large")]
     IndexTooLarge,
 
+    #[error("log entry is corrupted")]
+    Corrupted,
+
+    #[error("log is in the unsupported single-file format")]
+    LegacyLog,
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -27,60 +34,270 @@ pub enum DiskStorageError {
     Bincode(#[from] Box<bincode::ErrorKind>),
 }
 
+/// Stores the snapshot, the metadata, and the log in a directory.
+///
+/// The log is split into segment files named after the index of their first
+/// entry. New entries are appended to the last segment, which is sealed
+/// once it grows beyond the segment size. Sealed segments have a sidecar
+/// index file with the offsets of their entries, so only the last segment
+/// has to be scanned on startup.
+///
+/// The most recently appended entries are also kept in memory so that
+/// replicating them to followers doesn't read the files.
 pub struct DiskStorage<C> {
     dir_path: PathBuf,
     dir: Option<File>,
-    framed: Framed<File, EntryCodec<C>>,
-    offsets: Vec<u64>,
-    current_offset: u64,
+
+    /// segments in ascending order of indices. Never empty.
+    segments: Vec<Segment<C>>,
+
+    segment_size: u64,
+
+    /// recently appended entries and their serialized sizes, which end at
+    /// the last entry of the log
+    cache: VecDeque<(Entry<C>, u64)>,
+
+    cache_size: usize,
+
+    /// whether segment files were created since the directory was synced
+    dir_dirty: bool,
+
+    snapshot_metadata: SnapshotMetadata,
 }
 
-impl<C> DiskStorage<C> {
-    pub async fn new(dir_path: impl Into<PathBuf>) -> Result<Self, DiskStorageError> {
-        let dir_path = dir_path.into();
+pub struct DiskStorageBuilder<C> {
+    dir_path: PathBuf,
+    segment_size: u64,
+    cache_size: usize,
+    phantom: PhantomData<C>,
+}
+
+impl<C> DiskStorageBuilder<C>
+where
+    for<'de> C: Serialize + Deserialize<'de>,
+{
+    /// Size in bytes at which a segment is sealed and a new one is started.
+    pub fn segment_size(&mut self, segment_size: u64) -> &mut Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Maximum number of recent entries kept in memory.
+    pub fn cache_size(&mut self, cache_size: usize) -> &mut Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    pub async fn build(&self) -> Result<DiskStorage<C>, DiskStorageError> {
+        let dir_path = self.dir_path.clone();
         tokio::fs::create_dir_all(&dir_path).await?;
 
+        // Starting without the entries of a log written before it was split
+        // into segments would keep the term and vote but lose acknowledged
+        // entries.
+        if tokio::fs::try_exists(dir_path.join("log")).await? {
+            return Err(DiskStorageError::LegacyLog);
+        }
+
         // Some environments (e.g. Windows) can't open directories
         let dir = File::open(&dir_path).await.ok();
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(dir_path.join("log"))
-            .await?;
+        let snapshot_metadata = match File::open(dir_path.join("snapshot")).await {
+            Ok(mut file) => {
+                let metadata_size = file.read_u64_le().await?;
+                let mut bytes = vec![0; metadata_size.try_into().unwrap()];
+                file.read_exact(&mut bytes).await?;
+                bincode::DefaultOptions::new().deserialize(&bytes)?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SnapshotMetadata::default(),
+            Err(e) => return Err(e.into()),
+        };
 
-        // TODO: handle partially written entries by truncating them
-        let mut size_reader = FramedRead::new(&mut file, SizeDecoder::default());
-        let mut offsets = Vec::new();
-        let mut current_offset = 0;
-        while let Some(size) = size_reader.next().await {
-            offsets.push(current_offset);
-            current_offset += size? + HEADER_SIZE as u64;
+        let mut first_indices = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(&dir_path).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let first_index = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|name| name.parse::<u64>().ok());
+            if let Some(first_index) = first_index {
+                first_indices.push(first_index);
+            }
         }
+        first_indices.sort_unstable();
 
-        let framed = Framed::new(file, EntryCodec::default());
+        let mut segments: Vec<Segment<C>> = Vec::with_capacity(first_indices.len());
+        for (i, &first_index) in first_indices.iter().enumerate() {
+            let is_last = i + 1 == first_indices.len();
+            let segment = Segment::open(&dir_path, first_index, is_last).await?;
+            if let Some(prev) = segments.last() {
+                if prev.last_index() + 1 != first_index {
+                    return Err(DiskStorageError::Corrupted);
+                }
+            }
+            segments.push(segment);
+        }
 
-        Ok(Self {
+        let mut storage = DiskStorage {
             dir_path,
             dir,
-            framed,
-            offsets,
-            current_offset,
-        })
+            segments,
+            segment_size: self.segment_size,
+            cache: VecDeque::new(),
+            cache_size: self.cache_size,
+            dir_dirty: false,
+            snapshot_metadata,
+        };
+
+        // We may have crashed after persisting the snapshot and before
+        // compacting the log.
+        let snapshot_index = storage.snapshot_metadata.last_included_index;
+        let continues_from_snapshot = match storage.segments.first() {
+            Some(first)
+                if first.first_index <= snapshot_index + 1
+                    && storage.last_index() >= snapshot_index =>
+            {
+                if first.first_index <= snapshot_index {
+                    let (seg, pos) = storage.locate(snapshot_index).unwrap();
+                    let mut entries = Vec::with_capacity(1);
+                    storage.segments[seg]
+                        .read_entries(pos, 1, &mut entries)
+                        .await?;
+                    entries.first().map(|entry| entry.term)
+                        == Some(storage.snapshot_metadata.last_included_term)
+                } else {
+                    true
+                }
+            }
+            _ => false,
+        };
+        if continues_from_snapshot {
+            storage.discard_segments_before(snapshot_index + 1).await?;
+        } else {
+            storage.reset().await?;
+        }
+
+        Ok(storage)
     }
+}
 
-    fn file(&mut self) -> &mut File {
-        self.framed.get_mut()
+impl<C> DiskStorage<C> {
+    pub async fn new(dir_path: impl Into<PathBuf>) -> Result<Self, DiskStorageError>
+    where
+        for<'de> C: Serialize + Deserialize<'de>,
+    {
+        Self::builder(dir_path).build().await
+    }
+
+    pub fn builder(dir_path: impl Into<PathBuf>) -> DiskStorageBuilder<C> {
+        DiskStorageBuilder {
+            dir_path: dir_path.into(),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            cache_size: DEFAULT_CACHE_SIZE,
+            phantom: PhantomData,
+        }
+    }
+
+    fn active_segment(&mut self) -> &mut Segment<C> {
+        self.segments.last_mut().unwrap()
+    }
+
+    /// Returns the index of the last entry in the log, including the entries
+    /// replaced by the snapshot that are still in the segments.
+    fn last_index(&self) -> u64 {
+        self.segments
+            .last()
+            .map(Segment::last_index)
+            .unwrap_or(self.snapshot_metadata.last_included_index)
+    }
+
+    /// Returns the position of the segment containing the entry at `index`
+    /// and the position of the entry in the segment.
+    fn locate(&self, index: u64) -> Option<(usize, usize)> {
+        let seg = self
+            .segments
+            .partition_point(|segment| segment.first_index <= index)
+            .checked_sub(1)?;
+        let segment = &self.segments[seg];
+        let pos = usize::try_from(index - segment.first_index).ok()?;
+        (pos < segment.offsets.len()).then_some((seg, pos))
+    }
+
+    /// Returns the position of the entry at `index` in the cache.
+    fn cache_position(&self, index: u64) -> Option<usize> {
+        let first_cached_index = self.last_index() + 1 - self.cache.len() as u64;
+        let pos = index.checked_sub(first_cached_index)?;
+        let pos = usize::try_from(pos).ok()?;
+        (pos < self.cache.len()).then_some(pos)
+    }
+
+    async fn sync_dir(&self) -> Result<(), DiskStorageError> {
+        if let Some(dir) = &self.dir {
+            dir.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the segments that only contain entries before `index`.
+    /// The last segment is always kept.
+    async fn discard_segments_before(&mut self, index: u64) -> Result<(), DiskStorageError> {
+        let num_discarded = self.segments[..self.segments.len() - 1]
+            .iter()
+            .take_while(|segment| segment.last_index() < index)
+            .count();
+        let first_indices: Vec<_> = self
+            .segments
+            .drain(..num_discarded)
+            .map(|segment| segment.first_index)
+            .collect();
+        for first_index in first_indices {
+            remove_segment_files(&self.dir_path, first_index).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes all the segments and starts a new log right after
+    /// the snapshot.
+    async fn reset(&mut self) -> Result<(), DiskStorageError> {
+        // The segments are removed from the front so that a crash in the
+        // middle leaves a log that doesn't continue from the snapshot,
+        // which is discarded again on the next startup.
+        self.cache.clear();
+        let first_indices: Vec<_> = self
+            .segments
+            .drain(..)
+            .map(|segment| segment.first_index)
+            .collect();
+        for first_index in first_indices {
+            remove_segment_files(&self.dir_path, first_index).await?;
+        }
+
+        let first_index = self.snapshot_metadata.last_included_index + 1;
+        let file = create_segment_file(&self.dir_path, first_index).await?;
+        self.sync_dir().await?;
+        self.dir_dirty = false;
+        self.segments.push(Segment::new(first_index, file));
+        Ok(())
     }
 }
 
 impl<C: Serialize> DiskStorage<C> {
     async fn flush(&mut self) -> Result<(), DiskStorageError> {
-        if !self.framed.write_buffer().is_empty() {
-            self.file().seek(SeekFrom::End(0)).await?;
-            self.framed.flush().await?;
-        }
+        self.active_segment().flush().await
+    }
+
+    /// Seals the active segment and starts a new one.
+    async fn roll_segment(&mut self) -> Result<(), DiskStorageError> {
+        let first_index = self.last_index() + 1;
+        let active = self.segments.last_mut().unwrap();
+        active.flush().await?;
+        active.file().sync_data().await?;
+        write_index(&self.dir_path, active).await?;
+
+        let file = create_segment_file(&self.dir_path, first_index).await?;
+        self.dir_dirty = true;
+        self.segments.push(Segment::new(first_index, file));
         Ok(())
     }
 }
@@ -105,42 +322,77 @@ where
     }
 
     fn num_entries(&self) -> usize {
-        self.offsets.len()
+        (self.last_index() - self.snapshot_metadata.last_included_index)
+            .try_into()
+            .unwrap()
     }
 
     async fn entry(&mut self, index: u64) -> Result<Option<Entry<Self::Command>>, Self::Error> {
-        // TODO: make index 0-origin
-        let index: usize = if index > 0 {
-            (index - 1)
-                .try_into()
-                .map_err(|_| DiskStorageError::IndexTooLarge)?
-        } else {
+        if index <= self.snapshot_metadata.last_included_index {
             return Ok(None);
-        };
-        let Some(&offset) = self.offsets.get(index) else {
+        }
+        if let Some(pos) = self.cache_position(index) {
+            return Ok(Some(self.cache[pos].0.clone()));
+        }
+        let Some((seg, pos)) = self.locate(index) else {
             return Ok(None);
         };
         self.flush().await?;
-        self.file().seek(SeekFrom::Start(offset)).await?;
-        self.framed.read_buffer_mut().clear();
-        self.framed.next().await.transpose().map_err(Into::into)
+        let mut entries = Vec::with_capacity(1);
+        self.segments[seg]
+            .read_entries(pos, 1, &mut entries)
+            .await?;
+        Ok(entries.pop())
     }
 
-    async fn entries(&mut self, start: u64) -> Result<Vec<Entry<Self::Command>>, Self::Error> {
-        assert!(start > 0);
-        let index: usize = (start - 1)
-            .try_into()
-            .map_err(|_| DiskStorageError::IndexTooLarge)?;
-        let Some(&offset) = self.offsets.get(index) else {
+    async fn entries(
+        &mut self,
+        start: u64,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<Entry<Self::Command>>, Self::Error> {
+        assert!(start > self.snapshot_metadata.last_included_index);
+        if let Some(pos) = self.cache_position(start) {
+            let mut entries = Vec::new();
+            let mut num_bytes = 0;
+            for (entry, size) in self.cache.range(pos..).take(max_entries) {
+                if !entries.is_empty() && num_bytes + size > max_bytes {
+                    break;
+                }
+                entries.push(entry.clone());
+                num_bytes += size;
+            }
+            return Ok(entries);
+        }
+        let Some((mut seg, mut pos)) = self.locate(start) else {
             return Ok(Vec::new());
         };
-        let num_entries_to_read = self.offsets.len() - index;
+
         self.flush().await?;
-        self.file().seek(SeekFrom::Start(offset)).await?;
-        self.framed.read_buffer_mut().clear();
-        let mut entries = Vec::with_capacity(num_entries_to_read);
-        while let Some(entry) = self.framed.next().await {
-            entries.push(entry?);
+        let mut entries = Vec::new();
+        let mut num_bytes = 0;
+        while let Some(segment) = self.segments.get_mut(seg) {
+            let mut num_entries_to_read = 0;
+            for i in pos..segment.offsets.len() {
+                if entries.len() + num_entries_to_read >= max_entries {
+                    break;
+                }
+                let size = segment.entry_size(i);
+                let is_first = entries.is_empty() && num_entries_to_read == 0;
+                if !is_first && num_bytes + size > max_bytes {
+                    break;
+                }
+                num_entries_to_read += 1;
+                num_bytes += size;
+            }
+            segment
+                .read_entries(pos, num_entries_to_read, &mut entries)
+                .await?;
+            if pos + num_entries_to_read < segment.offsets.len() {
+                break;
+            }
+            seg += 1;
+            pos = 0;
         }
         Ok(entries)
     }
@@ -149,36 +401,141 @@ where
         &mut self,
         entries: &[Entry<Self::Command>],
     ) -> Result<(), Self::Error> {
-        self.file().seek(SeekFrom::End(0)).await?;
+        self.active_segment().file().seek(SeekFrom::End(0)).await?;
         for entry in entries {
+            let active = self.active_segment();
+            if !active.offsets.is_empty() && active.end_offset >= self.segment_size {
+                self.roll_segment().await?;
+            }
             let size = bincode::DefaultOptions::new().serialized_size(entry)?;
-            let offset = self.current_offset;
-            self.framed.feed(EncoderItem { inner: entry, size }).await?;
-            self.current_offset += size + HEADER_SIZE as u64;
-            self.offsets.push(offset);
-        }
+            let active = self.active_segment();
+            let offset = active.end_offset;
+            active
+                .framed
+                .feed(EncoderItem { inner: entry, size })
+                .await?;
+            active.end_offset += size + ENTRY_HEADER_SIZE as u64;
+            active.offsets.push(offset);
 
-        // TODO: to improve throughput, do not immediately flush, and instead
-        //       call persist_entries() periodically or in idle moments
-        self.persist_entries().await?;
+            if self.cache_size > 0 {
+                if self.cache.len() >= self.cache_size {
+                    self.cache.pop_front();
+                }
+                self.cache.push_back((entry.clone(), size));
+            }
+        }
         Ok(())
     }
 
     async fn truncate_entries(&mut self, index: u64) -> Result<(), Self::Error> {
-        assert!(index > 0);
-        let index: usize = index
-            .try_into()
-            .map_err(|_| DiskStorageError::IndexTooLarge)?;
-        let Some(&offset) = self.offsets.get(index) else {
+        assert!(index > self.snapshot_metadata.last_included_index);
+        let Some((seg, pos)) = self.locate(index) else {
             return Ok(());
         };
         self.flush().await?;
-        self.file().set_len(offset).await?;
-        self.offsets.truncate(index);
+
+        let num_removed = self.last_index() - index + 1;
+        let num_cached = (self.cache.len() as u64).saturating_sub(num_removed);
+        self.cache.truncate(num_cached as usize);
+
+        // The later segments are removed from the back so that a crash in
+        // the middle leaves a contiguous log.
+        let first_indices: Vec<_> = self
+            .segments
+            .drain(seg + 1..)
+            .map(|segment| segment.first_index)
+            .collect();
+        for &first_index in first_indices.iter().rev() {
+            remove_segment_files(&self.dir_path, first_index).await?;
+        }
+
+        // The segment becomes the active one again.
+        let first_index = self.segments[seg].first_index;
+        remove_file_if_exists(&index_path(&self.dir_path, first_index)).await?;
+        self.sync_dir().await?;
+
+        let active = self.active_segment();
+        let offset = active.offsets[pos];
+        active.file().set_len(offset).await?;
+        active.offsets.truncate(pos);
+        active.end_offset = offset;
         self.persist_entries().await?;
         Ok(())
     }
 
+    fn snapshot_metadata(&self) -> &SnapshotMetadata {
+        &self.snapshot_metadata
+    }
+
+    async fn read_snapshot(&mut self, offset: u64, len: usize) -> Result<Bytes, Self::Error> {
+        let metadata_size =
+            bincode::DefaultOptions::new().serialized_size(&self.snapshot_metadata)?;
+        let mut file = File::open(self.dir_path.join("snapshot")).await?;
+        file.seek(SeekFrom::Start(
+            SNAPSHOT_HEADER_SIZE as u64 + metadata_size + offset,
+        ))
+        .await?;
+        let mut buf = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    fn write_snapshot(
+        &self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static {
+        let tmp_filename = self.dir_path.join("snapshot.tmp");
+        async move {
+            let bytes = bincode::DefaultOptions::new().serialize(&metadata)?;
+            // A write that was abandoned may still be in flight on the old
+            // file, so the file is replaced rather than truncated
+            match tokio::fs::remove_file(&tmp_filename).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            let mut tmp_file = File::create(&tmp_filename).await?;
+            tmp_file.write_u64_le(bytes.len() as u64).await?;
+            tmp_file.write_all(&bytes).await?;
+            tmp_file.write_all(&data).await?;
+            tmp_file.sync_data().await?;
+            Ok(())
+        }
+    }
+
+    async fn commit_snapshot(
+        &mut self,
+        metadata: SnapshotMetadata,
+        _data: Bytes,
+    ) -> Result<(), Self::Error> {
+        tokio::fs::rename(
+            self.dir_path.join("snapshot.tmp"),
+            self.dir_path.join("snapshot"),
+        )
+        .await?;
+        self.sync_dir().await?;
+
+        let index = metadata.last_included_index;
+        let matches = if index > self.snapshot_metadata.last_included_index {
+            self.entry(index)
+                .await?
+                .map(|entry| entry.term == metadata.last_included_term)
+                .unwrap_or(false)
+        } else {
+            index == self.snapshot_metadata.last_included_index
+        };
+        self.snapshot_metadata = metadata;
+        if matches {
+            while self.cache.len() as u64 > self.last_index() - index {
+                self.cache.pop_front();
+            }
+            self.discard_segments_before(index + 1).await
+        } else {
+            self.reset().await
+        }
+    }
+
     async fn persist_metadata(&mut self, metadata: &Metadata) -> Result<(), Self::Error> {
         let bytes = bincode::DefaultOptions::new().serialize(metadata)?;
         let tmp_filename = self.dir_path.join("metadata.tmp");
@@ -188,34 +545,335 @@ where
             tmp_file.sync_data().await?;
         }
         tokio::fs::rename(tmp_filename, self.dir_path.join("metadata")).await?;
-        if let Some(dir) = &self.dir {
-            dir.sync_all().await?;
-        }
-        Ok(())
+        self.sync_dir().await
     }
 
     async fn persist_entries(&mut self) -> Result<(), Self::Error> {
         self.flush().await?;
-        self.file().sync_data().await?;
+        self.active_segment().file().sync_data().await?;
+        if self.dir_dirty {
+            self.sync_dir().await?;
+            self.dir_dirty = false;
+        }
+        Ok(())
+    }
+}
+
+struct Segment<C> {
+    /// index of the first entry in the segment
+    first_index: u64,
+
+    /// offsets of the entries in the segment file
+    offsets: Vec<u64>,
+
+    /// end of the last entry
+    end_offset: u64,
+
+    framed: Framed<File, EntryCodec<C>>,
+}
+
+impl<C> Segment<C> {
+    fn new(first_index: u64, file: File) -> Self {
+        Self {
+            first_index,
+            offsets: Vec::new(),
+            end_offset: 0,
+            framed: Framed::new(file, EntryCodec::default()),
+        }
+    }
+
+    /// Opens an existing segment file.
+    ///
+    /// The last segment may have a partially written entry at the end, and
+    /// is appended to, so its index is discarded and the file is scanned.
+    async fn open(
+        dir_path: &Path,
+        first_index: u64,
+        is_last: bool,
+    ) -> Result<Self, DiskStorageError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(segment_path(dir_path, first_index))
+            .await?;
+        let index_path = index_path(dir_path, first_index);
+        let (offsets, end_offset) = if is_last {
+            remove_file_if_exists(&index_path).await?;
+            scan_log(&mut file, true).await?
+        } else {
+            let file_len = file.metadata().await?.len();
+            match read_index(&index_path, file_len).await? {
+                Some(offsets) => (offsets, file_len),
+                None => scan_log(&mut file, false).await?,
+            }
+        };
+        Ok(Self {
+            first_index,
+            offsets,
+            end_offset,
+            framed: Framed::new(file, EntryCodec::default()),
+        })
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.framed.get_mut()
+    }
+
+    fn last_index(&self) -> u64 {
+        self.first_index + self.offsets.len() as u64 - 1
+    }
+
+    /// Returns the serialized size of the `pos`-th entry in the segment.
+    fn entry_size(&self, pos: usize) -> u64 {
+        let next_offset = self
+            .offsets
+            .get(pos + 1)
+            .copied()
+            .unwrap_or(self.end_offset);
+        next_offset - self.offsets[pos] - ENTRY_HEADER_SIZE as u64
+    }
+}
+
+impl<C: Serialize> Segment<C> {
+    async fn flush(&mut self) -> Result<(), DiskStorageError> {
+        if !self.framed.write_buffer().is_empty() {
+            self.file().seek(SeekFrom::End(0)).await?;
+            self.framed.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<C> Segment<C>
+where
+    for<'de> C: Deserialize<'de>,
+{
+    /// Reads `num_entries` entries starting at the `pos`-th entry into
+    /// `entries`.
+    ///
+    /// The write buffer has to be flushed before calling this.
+    async fn read_entries(
+        &mut self,
+        pos: usize,
+        num_entries: usize,
+        entries: &mut Vec<Entry<C>>,
+    ) -> Result<(), DiskStorageError> {
+        if num_entries == 0 {
+            return Ok(());
+        }
+        let offset = self.offsets[pos];
+        self.file().seek(SeekFrom::Start(offset)).await?;
+        self.framed.read_buffer_mut().clear();
+        for _ in 0..num_entries {
+            match self.framed.next().await {
+                Some(entry) => entries.push(entry?),
+                None => break,
+            }
+        }
         Ok(())
     }
 }
 
+fn segment_path(dir_path: &Path, first_index: u64) -> PathBuf {
+    dir_path.join(format!("{first_index:020}.log"))
+}
+
+fn index_path(dir_path: &Path, first_index: u64) -> PathBuf {
+    dir_path.join(format!("{first_index:020}.index"))
+}
+
+async fn create_segment_file(dir_path: &Path, first_index: u64) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(segment_path(dir_path, first_index))
+        .await
+}
+
+async fn remove_segment_files(dir_path: &Path, first_index: u64) -> std::io::Result<()> {
+    remove_file_if_exists(&index_path(dir_path, first_index)).await?;
+    remove_file_if_exists(&segment_path(dir_path, first_index)).await
+}
+
+async fn remove_file_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the offsets of the entries in the sealed segment, followed by
+/// a CRC-32 checksum of them.
+async fn write_index<C>(dir_path: &Path, segment: &Segment<C>) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(segment.offsets.len() * 8 + INDEX_FOOTER_SIZE);
+    for offset in &segment.offsets {
+        bytes.put_u64_le(*offset);
+    }
+    bytes.put_u32_le(crc32fast::hash(&bytes));
+
+    let path = index_path(dir_path, segment.first_index);
+    let tmp_path = path.with_extension("index.tmp");
+    {
+        let mut tmp_file = File::create(&tmp_path).await?;
+        tmp_file.write_all(&bytes).await?;
+        tmp_file.sync_data().await?;
+    }
+    tokio::fs::rename(tmp_path, path).await
+}
+
+/// Reads the offsets of the entries from the index file of a segment.
+/// Returns `None` if the index is missing or invalid, in which case
+/// the segment has to be scanned instead.
+async fn read_index(path: &Path, file_len: u64) -> Result<Option<Vec<u64>>, DiskStorageError> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(body_len) = bytes.len().checked_sub(INDEX_FOOTER_SIZE) else {
+        return Ok(None);
+    };
+    let (mut body, mut footer) = bytes.split_at(body_len);
+    if body.len() % 8 != 0 || crc32fast::hash(body) != footer.get_u32_le() {
+        tracing::warn!(?path, "ignoring invalid segment index");
+        return Ok(None);
+    }
+    let mut offsets = Vec::with_capacity(body.len() / 8);
+    while body.has_remaining() {
+        offsets.push(body.get_u64_le());
+    }
+    let is_valid = offsets.first().map_or(file_len == 0, |&offset| offset == 0)
+        && offsets.windows(2).all(|w| w[0] < w[1])
+        && offsets.last().is_none_or(|&offset| offset < file_len);
+    if !is_valid {
+        tracing::warn!(?path, "ignoring invalid segment index");
+        return Ok(None);
+    }
+    Ok(Some(offsets))
+}
+
+/// Reads the headers of all the entries in the segment file, and returns
+/// the offsets of the entries and the end of the last entry.
+///
+/// An entry at the end of the file that is partially written or fails
+/// the checksum, which a crash in the middle of a write leaves behind, is
+/// truncated if `truncate_tail` is true. Such an entry in the middle of
+/// the file, or at the end of a sealed segment, is an error. A header that
+/// fails its checksum is only taken for a partial write if nothing but the
+/// zeros of unwritten blocks follows it, as its size can't be trusted to
+/// find the entries after it.
+async fn scan_log(
+    file: &mut File,
+    truncate_tail: bool,
+) -> Result<(Vec<u64>, u64), DiskStorageError> {
+    let file_len = file.metadata().await?.len();
+    let mut reader = BufReader::new(&mut *file);
+    reader.seek(SeekFrom::Start(0)).await?;
+
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    let mut header = [0; ENTRY_HEADER_SIZE];
+    let mut payload = Vec::new();
+    while offset < file_len {
+        let payload_offset = offset + ENTRY_HEADER_SIZE as u64;
+        if payload_offset > file_len {
+            break;
+        }
+        reader.read_exact(&mut header).await?;
+        let Some((size, checksum)) = decode_header(&header) else {
+            if !is_zeroed(&mut reader, file_len - payload_offset).await? {
+                return Err(DiskStorageError::Corrupted);
+            }
+            break;
+        };
+        // The header is intact, so an entry overrunning the file is the
+        // last one
+        let Some(end) = payload_offset
+            .checked_add(size)
+            .filter(|&end| end <= file_len)
+        else {
+            break;
+        };
+        payload.resize(size.try_into().unwrap(), 0);
+        reader.read_exact(&mut payload).await?;
+        if crc32fast::hash(&payload) != checksum {
+            if end < file_len {
+                return Err(DiskStorageError::Corrupted);
+            }
+            break;
+        }
+        offsets.push(offset);
+        offset = end;
+    }
+
+    if offset < file_len {
+        if !truncate_tail {
+            return Err(DiskStorageError::Corrupted);
+        }
+        tracing::warn!(offset, "truncating partially written log entry");
+        file.set_len(offset).await?;
+        file.sync_data().await?;
+    }
+    Ok((offsets, offset))
+}
+
+/// Returns true if the next `len` bytes of the reader are all zeros.
+async fn is_zeroed(reader: &mut BufReader<&mut File>, len: u64) -> std::io::Result<bool> {
+    let mut buf = [0; 8192];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        reader.read_exact(&mut buf[..n]).await?;
+        if buf[..n].iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+        remaining -= n as u64;
+    }
+    Ok(true)
+}
+
+/// Returns the size and checksum of the payload in the entry header, or
+/// `None` if the header fails its own checksum.
+fn decode_header(mut header: &[u8]) -> Option<(u64, u32)> {
+    let header_checksum = crc32fast::hash(&header[..ENTRY_HEADER_SIZE - 4]);
+    let size = header.get_u64_le();
+    let checksum = header.get_u32_le();
+    (header.get_u32_le() == header_checksum).then_some((size, checksum))
+}
+
 struct EntryCodec<C> {
-    decoder: InnerDecoder,
+    /// size and checksum of the entry being decoded
+    header: Option<(usize, u32)>,
+
     phantom: PhantomData<C>,
 }
 
 impl<C> Default for EntryCodec<C> {
     fn default() -> Self {
         Self {
-            decoder: Default::default(),
+            header: None,
             phantom: Default::default(),
         }
     }
 }
 
-const HEADER_SIZE: usize = std::mem::size_of::<u64>();
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// enough to cover `max_in_flight_appends` full AppendEntries RPCs with
+/// the default configuration
+const DEFAULT_CACHE_SIZE: usize = 8192;
+
+/// size and CRC-32 checksum of the entry, followed by a CRC-32 checksum of
+/// them
+const ENTRY_HEADER_SIZE: usize = std::mem::size_of::<u64>() + 2 * std::mem::size_of::<u32>();
+
+/// CRC-32 checksum of the offsets in the index file
+const INDEX_FOOTER_SIZE: usize = std::mem::size_of::<u32>();
+
+/// size of the snapshot metadata
+const SNAPSHOT_HEADER_SIZE: usize = std::mem::size_of::<u64>();
 
 struct EncoderItem<'a, C> {
     inner: &'a Entry<C>,
@@ -226,12 +884,22 @@ impl<C> Encoder<EncoderItem<'_, C>> for EntryCodec<C>
 where
     C: Serialize,
 {
-    type Error = Box<bincode::ErrorKind>;
+    type Error = DiskStorageError;
 
     fn encode(&mut self, item: EncoderItem<'_, C>, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut writer = dst.writer();
-        writer.write_u64::<LE>(item.size)?;
-        bincode::DefaultOptions::new().serialize_into(writer, item.inner)?;
+        dst.reserve(ENTRY_HEADER_SIZE + usize::try_from(item.size).unwrap());
+        let header_offset = dst.len();
+        dst.put_u64_le(item.size);
+        dst.put_u64_le(0);
+        let payload_offset = dst.len();
+        bincode::DefaultOptions::new().serialize_into(dst.writer(), item.inner)?;
+
+        let checksum = crc32fast::hash(&dst[payload_offset..]);
+        let checksum_offset = header_offset + std::mem::size_of::<u64>();
+        let header_checksum_offset = payload_offset - std::mem::size_of::<u32>();
+        dst[checksum_offset..header_checksum_offset].copy_from_slice(&checksum.to_le_bytes());
+        let header_checksum = crc32fast::hash(&dst[header_offset..header_checksum_offset]);
+        dst[header_checksum_offset..payload_offset].copy_from_slice(&header_checksum.to_le_bytes());
         Ok(())
     }
 }
@@ -241,55 +909,256 @@ where
     for<'de> C: Deserialize<'de>,
 {
     type Item = Entry<C>;
-    type Error = Box<bincode::ErrorKind>;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.decoder
-            .decode(src, |s| bincode::DefaultOptions::new().deserialize_from(s))
-    }
-}
-
-#[derive(Default)]
-struct SizeDecoder {
-    inner: InnerDecoder,
-}
-
-impl Decoder for SizeDecoder {
-    type Item = u64;
-    type Error = std::io::Error;
+    type Error = DiskStorageError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.inner.decode(src, |s| Ok(s.len() as u64))
-    }
-}
-
-#[derive(Default)]
-struct InnerDecoder {
-    size: Option<usize>,
-}
-
-impl InnerDecoder {
-    fn decode<T, E, F>(&mut self, src: &mut BytesMut, f: F) -> Result<Option<T>, E>
-    where
-        F: Fn(&[u8]) -> Result<T, E>,
-    {
-        let size = match self.size {
-            Some(size) => size,
+        let (size, checksum) = match self.header {
+            Some(header) => header,
             None => {
-                if src.len() < HEADER_SIZE {
+                if src.len() < ENTRY_HEADER_SIZE {
                     return Ok(None);
                 }
-                src.get_u64_le() as usize
+                let (size, checksum) =
+                    decode_header(&src[..ENTRY_HEADER_SIZE]).ok_or(DiskStorageError::Corrupted)?;
+                src.advance(ENTRY_HEADER_SIZE);
+                (size.try_into().unwrap(), checksum)
             }
         };
         if src.len() < size {
-            self.size = Some(size);
+            self.header = Some((size, checksum));
             src.reserve(size);
             return Ok(None);
         }
-        let decoded = f(&src[..size])?;
-        src.advance(size);
-        self.size = None;
-        Ok(Some(decoded))
+        self.header = None;
+        let payload = src.split_to(size);
+        if crc32fast::hash(&payload) != checksum {
+            return Err(DiskStorageError::Corrupted);
+        }
+        Ok(Some(bincode::DefaultOptions::new().deserialize(&payload)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segment_path, DiskStorage, DiskStorageError, Storage};
+    use crate::{Command, Entry, EntryKind, SnapshotMetadata};
+    use bytes::Bytes;
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct TestCommand(u64);
+
+    impl Command for TestCommand {
+        type Output = ();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("zakros-raft-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn entries(range: std::ops::Range<u64>) -> Vec<Entry<TestCommand>> {
+        range
+            .map(|i| Entry {
+                kind: EntryKind::Command(TestCommand(i)),
+                term: 1,
+                timestamp: i,
+                seed: i,
+            })
+            .collect()
+    }
+
+    async fn write_log(dir: &PathBuf, num_entries: u64) {
+        let mut storage = DiskStorage::new(dir).await.unwrap();
+        storage
+            .append_entries(&entries(0..num_entries))
+            .await
+            .unwrap();
+        storage.persist_entries().await.unwrap();
+    }
+
+    fn num_segments(dir: &PathBuf) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "log")
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_truncated() {
+        let dir = temp_dir("torn-tail");
+        write_log(&dir, 3).await;
+
+        let log_path = segment_path(&dir, 1);
+        let len = std::fs::metadata(&log_path).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap();
+        file.set_len(len - 1).unwrap();
+
+        let mut storage = DiskStorage::<TestCommand>::new(&dir).await.unwrap();
+        assert_eq!(storage.num_entries(), 2);
+        let entries = storage.entries(1, usize::MAX, u64::MAX).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corruption_in_the_middle_is_detected() {
+        let dir = temp_dir("corruption");
+        write_log(&dir, 3).await;
+
+        let log_path = segment_path(&dir, 1);
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        bytes[super::ENTRY_HEADER_SIZE] ^= 0xff;
+        std::fs::write(&log_path, bytes).unwrap();
+
+        let result = DiskStorage::<TestCommand>::new(&dir).await;
+        assert!(matches!(result, Err(DiskStorageError::Corrupted)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_size_is_detected() {
+        let dir = temp_dir("corrupted-size");
+        write_log(&dir, 3).await;
+
+        // The size of the first entry now overruns the file
+        let log_path = segment_path(&dir, 1);
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        bytes[std::mem::size_of::<u64>() - 1] ^= 0x80;
+        std::fs::write(&log_path, bytes).unwrap();
+
+        let result = DiskStorage::<TestCommand>::new(&dir).await;
+        assert!(matches!(result, Err(DiskStorageError::Corrupted)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn zeroed_tail_is_truncated() {
+        let dir = temp_dir("zeroed-tail");
+        write_log(&dir, 3).await;
+
+        // Blocks allocated for a write that never made it to the disk
+        let log_path = segment_path(&dir, 1);
+        let len = std::fs::metadata(&log_path).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap();
+        file.set_len(len + 100).unwrap();
+
+        let storage = DiskStorage::<TestCommand>::new(&dir).await.unwrap();
+        assert_eq!(storage.num_entries(), 3);
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), len);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn legacy_log_is_rejected() {
+        let dir = temp_dir("legacy-log");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("log"), 1u64.to_le_bytes()).unwrap();
+
+        let result = DiskStorage::<TestCommand>::new(&dir).await;
+        assert!(matches!(result, Err(DiskStorageError::LegacyLog)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn written_snapshot_replaces_current_on_commit() {
+        let dir = temp_dir("written-snapshot");
+        write_log(&dir, 10).await;
+        let mut storage = DiskStorage::<TestCommand>::new(&dir).await.unwrap();
+        let snapshot = |index, data: &'static [u8]| SnapshotMetadata {
+            last_included_index: index,
+            last_included_term: 1,
+            size: data.len() as u64,
+            ..Default::default()
+        };
+        storage
+            .install_snapshot(snapshot(3, b"old"), Bytes::from_static(b"old"))
+            .await
+            .unwrap();
+
+        let write = storage.write_snapshot(snapshot(8, b"new"), Bytes::from_static(b"new"));
+        storage.append_entries(&entries(10..12)).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(storage.snapshot_metadata().last_included_index, 3);
+        assert_eq!(storage.read_snapshot(0, 3).await.unwrap(), "old");
+        assert_eq!(storage.num_entries(), 9);
+
+        storage
+            .commit_snapshot(snapshot(8, b"new"), Bytes::from_static(b"new"))
+            .await
+            .unwrap();
+        assert_eq!(storage.num_entries(), 4);
+
+        let mut storage = DiskStorage::<TestCommand>::new(&dir).await.unwrap();
+        assert_eq!(storage.snapshot_metadata().last_included_index, 8);
+        assert_eq!(storage.read_snapshot(0, 3).await.unwrap(), "new");
+        assert_eq!(storage.num_entries(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn segments_are_rolled_and_removed() {
+        let dir = temp_dir("segments");
+        let mut storage = DiskStorage::<TestCommand>::builder(&dir)
+            .segment_size(64)
+            .cache_size(8)
+            .build()
+            .await
+            .unwrap();
+        storage.append_entries(&entries(0..40)).await.unwrap();
+        storage.persist_entries().await.unwrap();
+        let num_rolled = num_segments(&dir);
+        assert!(num_rolled > 1);
+
+        let mut storage = DiskStorage::<TestCommand>::builder(&dir)
+            .segment_size(64)
+            .cache_size(8)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(storage.num_entries(), 40);
+        let read = storage.entries(1, usize::MAX, u64::MAX).await.unwrap();
+        assert!(read
+            .iter()
+            .zip(0..)
+            .all(|(entry, i)| matches!(entry.kind, EntryKind::Command(TestCommand(x)) if x == i)));
+        assert_eq!(read.len(), 40);
+
+        storage.truncate_entries(31).await.unwrap();
+        storage.append_entries(&entries(100..105)).await.unwrap();
+        storage.persist_entries().await.unwrap();
+        assert_eq!(storage.num_entries(), 35);
+        let entry = storage.entry(31).await.unwrap().unwrap();
+        assert!(matches!(entry.kind, EntryKind::Command(TestCommand(100))));
+
+        let metadata = SnapshotMetadata {
+            last_included_index: 20,
+            last_included_term: 1,
+            ..Default::default()
+        };
+        storage
+            .install_snapshot(metadata, Bytes::new())
+            .await
+            .unwrap();
+        assert_eq!(storage.num_entries(), 15);
+        assert!(num_segments(&dir) < num_rolled);
+
+        let mut storage = DiskStorage::<TestCommand>::new(&dir).await.unwrap();
+        assert_eq!(storage.num_entries(), 15);
+        let read = storage.entries(21, usize::MAX, u64::MAX).await.unwrap();
+        assert_eq!(read.len(), 15);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,27 +1,54 @@
 use super::{Entry, Metadata, Storage};
-use crate::Command;
+use crate::{Command, SnapshotMetadata};
+use bincode::Options;
+use bytes::Bytes;
+use futures::Future;
+use serde::Serialize;
 
 #[derive(Debug, thiserror::Error)]
 pub enum MemoryStorageError {
     #[error("index is too large")]
     IndexTooLarge,
+
+    #[error(transparent)]
+    Bincode(#[from] Box<bincode::ErrorKind>),
 }
 
-pub struct MemoryStorage<C>(Vec<Entry<C>>);
+pub struct MemoryStorage<C> {
+    entries: Vec<Entry<C>>,
+    snapshot_metadata: SnapshotMetadata,
+    snapshot_data: Bytes,
+}
 
 impl<C> MemoryStorage<C> {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Converts 1-origin index of the entry into index of `self.entries`.
+    /// Returns `None` if the entry has been replaced by the snapshot.
+    fn position(&self, index: u64) -> Result<Option<usize>, MemoryStorageError> {
+        if index <= self.snapshot_metadata.last_included_index {
+            return Ok(None);
+        }
+        let pos = index - self.snapshot_metadata.last_included_index - 1;
+        pos.try_into()
+            .map(Some)
+            .map_err(|_| MemoryStorageError::IndexTooLarge)
+    }
 }
 
 impl<C> Default for MemoryStorage<C> {
     fn default() -> Self {
-        Self(Default::default())
+        Self {
+            entries: Default::default(),
+            snapshot_metadata: Default::default(),
+            snapshot_data: Default::default(),
+        }
     }
 }
 
-impl<C: Command> Storage for MemoryStorage<C> {
+impl<C: Command + Serialize> Storage for MemoryStorage<C> {
     type Command = C;
     type Error = MemoryStorageError;
 
@@ -30,43 +57,95 @@ impl<C: Command> Storage for MemoryStorage<C> {
     }
 
     fn num_entries(&self) -> usize {
-        self.0.len()
+        self.entries.len()
     }
 
     async fn entry(&mut self, index: u64) -> Result<Option<Entry<Self::Command>>, Self::Error> {
-        Ok(if index > 0 {
-            let index = TryInto::<usize>::try_into(index)
-                .map_err(|_| MemoryStorageError::IndexTooLarge)?
-                - 1;
-            self.0.get(index).cloned()
-        } else {
-            None
+        Ok(match self.position(index)? {
+            Some(pos) => self.entries.get(pos).cloned(),
+            None => None,
         })
     }
 
-    async fn entries(&mut self, start: u64) -> Result<Vec<Entry<Self::Command>>, Self::Error> {
-        assert!(start > 0);
-        let start =
-            TryInto::<usize>::try_into(start).map_err(|_| MemoryStorageError::IndexTooLarge)? - 1;
-        if start >= self.0.len() {
-            return Ok(Vec::new());
+    async fn entries(
+        &mut self,
+        start: u64,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<Entry<Self::Command>>, Self::Error> {
+        assert!(start > self.snapshot_metadata.last_included_index);
+        let start = self.position(start)?.unwrap();
+        let mut entries = Vec::new();
+        let mut num_bytes = 0;
+        for entry in self.entries.iter().skip(start).take(max_entries) {
+            let size = bincode::DefaultOptions::new().serialized_size(entry)?;
+            if !entries.is_empty() && num_bytes + size > max_bytes {
+                break;
+            }
+            entries.push(entry.clone());
+            num_bytes += size;
         }
-        Ok(self.0[start..].to_vec())
+        Ok(entries)
     }
 
     async fn append_entries(
         &mut self,
         entries: &[Entry<Self::Command>],
     ) -> Result<(), Self::Error> {
-        self.0.extend_from_slice(entries);
+        self.entries.extend_from_slice(entries);
         Ok(())
     }
 
     async fn truncate_entries(&mut self, index: u64) -> Result<(), Self::Error> {
-        assert!(index > 0);
-        let index =
-            TryInto::<usize>::try_into(index).map_err(|_| MemoryStorageError::IndexTooLarge)? - 1;
-        self.0.truncate(index);
+        assert!(index > self.snapshot_metadata.last_included_index);
+        let index = self.position(index)?.unwrap();
+        self.entries.truncate(index);
+        Ok(())
+    }
+
+    fn snapshot_metadata(&self) -> &SnapshotMetadata {
+        &self.snapshot_metadata
+    }
+
+    async fn read_snapshot(&mut self, offset: u64, len: usize) -> Result<Bytes, Self::Error> {
+        let start = offset
+            .try_into()
+            .map_err(|_| MemoryStorageError::IndexTooLarge)?;
+        let start = self.snapshot_data.len().min(start);
+        let end = self.snapshot_data.len().min(start.saturating_add(len));
+        Ok(self.snapshot_data.slice(start..end))
+    }
+
+    fn write_snapshot(
+        &self,
+        _metadata: SnapshotMetadata,
+        _data: Bytes,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static {
+        futures::future::ready(Ok(()))
+    }
+
+    async fn commit_snapshot(
+        &mut self,
+        metadata: SnapshotMetadata,
+        data: Bytes,
+    ) -> Result<(), Self::Error> {
+        let index = metadata.last_included_index;
+        let matches = match self.position(index)? {
+            Some(pos) => self
+                .entries
+                .get(pos)
+                .map(|entry| entry.term == metadata.last_included_term)
+                .unwrap_or(false),
+            None => index == self.snapshot_metadata.last_included_index,
+        };
+        if matches {
+            let num_discarded = self.position(index)?.map(|pos| pos + 1).unwrap_or(0);
+            self.entries.drain(..num_discarded);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot_metadata = metadata;
+        self.snapshot_data = data;
         Ok(())
     }
 
@@ -72,6 +72,18 @@ impl RedisCommand {
             Self::Transaction(command) => command.arity(),
         }
     }
+
+    /// Returns the keys in the arguments of the command.
+    pub fn keys<'a>(&self, args: &'a [Bytes]) -> impl Iterator<Item = &'a Bytes> {
+        let spec = match self {
+            Self::Write(command) => command.key_spec(),
+            Self::Read(command) => command.key_spec(),
+            Self::Stateless(command) => command.key_spec(),
+            Self::System(command) => command.key_spec(),
+            Self::Transaction(command) => command.key_spec(),
+        };
+        spec.keys(args)
+    }
 }
 
 pub enum Arity {
@@ -79,6 +91,54 @@ pub enum Arity {
     AtLeast(usize),
 }
 
+/// Positions of the keys in the arguments
+#[derive(Clone, Copy)]
+enum KeySpec {
+    None,
+
+    /// Every `step`-th argument from `first` to `last` is a key. Negative
+    /// `last` counts from the end.
+    Range {
+        first: usize,
+        last: isize,
+        step: usize,
+    },
+}
+
+impl KeySpec {
+    /// The first argument is the key.
+    const SINGLE: Self = Self::Range {
+        first: 0,
+        last: 0,
+        step: 1,
+    };
+
+    /// All the arguments are keys.
+    const ALL: Self = Self::Range {
+        first: 0,
+        last: -1,
+        step: 1,
+    };
+
+    fn keys(self, args: &[Bytes]) -> impl Iterator<Item = &Bytes> {
+        let (first, end, step) = match self {
+            Self::None => (0, 0, 1),
+            Self::Range { first, last, step } => {
+                let end = if last < 0 {
+                    args.len().saturating_sub(last.unsigned_abs() - 1)
+                } else {
+                    args.len().min(last as usize + 1)
+                };
+                (first, end, step)
+            }
+        };
+        args.get(first..end)
+            .unwrap_or_default()
+            .iter()
+            .step_by(step)
+    }
+}
+
 macro_rules! commands {
     ($kind:ident, $($id:ident,)*) => {
         #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -109,6 +169,12 @@ macro_rules! commands {
                     $(Self::$id => $id::ARITY,)*
                 }
             }
+
+            const fn key_spec(&self) -> KeySpec {
+                match self {
+                    $(Self::$id => $id::KEYS,)*
+                }
+            }
         }
 
         $(enum $id {})*
@@ -183,9 +249,12 @@ write_commands! {
     Decr,
     DecrBy,
     Del,
+    Expire,
+    ExpireAt,
     FlushAll,
     FlushDb,
     GetDel,
+    GetEx,
     GetSet,
     HDel,
     HIncrBy,
@@ -201,9 +270,13 @@ write_commands! {
     LTrim,
     MSet,
     MSetNx,
+    Persist,
+    PExpire,
+    PExpireAt,
     PfAdd,
     PfCount,
     PfMerge,
+    PSetEx,
     Rename,
     RenameNx,
     RPop,
@@ -214,6 +287,7 @@ write_commands! {
     SDiffStore,
     Set,
     SetBit,
+    SetEx,
     SetNx,
     SetRange,
     SInterStore,
@@ -243,6 +317,7 @@ read_commands! {
     LLen,
     LRange,
     MGet,
+    PTtl,
     SCard,
     SDiff,
     SInter,
@@ -252,6 +327,7 @@ read_commands! {
     StrLen,
     SubStr,
     SUnion,
+    Ttl,
     Type,
 }
 
@@ -262,6 +338,7 @@ stateless_commands! {
 }
 
 system_commands! {
+    Client,
     Config,
     Cluster,
     Debug,
@@ -290,6 +367,7 @@ transaction_commands! {
 trait CommandSpec {
     const NAME: &'static str;
     const ARITY: Arity;
+    const KEYS: KeySpec = KeySpec::None;
 }
 
 trait WriteCommandHandler: CommandSpec {
@@ -303,3 +381,23 @@ trait ReadCommandHandler: CommandSpec {
 trait StatelessCommandHandler: CommandSpec {
     fn call(args: &[Bytes]) -> RedisResult;
 }
+
+/// Expiration time given to a command
+#[derive(Clone, Copy)]
+struct ExpireTime {
+    time: i64,
+    unit_ms: i64,
+    is_relative: bool,
+}
+
+impl ExpireTime {
+    /// Returns the time in milliseconds since the UNIX epoch, or `None` if
+    /// it overflows. Times before the epoch are clamped to the epoch.
+    fn resolve(self, now: u64) -> Option<u64> {
+        let mut time = self.time.checked_mul(self.unit_ms)?;
+        if self.is_relative {
+            time = time.checked_add(now.try_into().ok()?)?;
+        }
+        Some(time.max(0) as u64)
+    }
+}
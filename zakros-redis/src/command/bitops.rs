@@ -1,15 +1,16 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     lockable::{ReadLockable, RwLockable},
     BytesExt, Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::{BufMut, Bytes};
-use std::collections::hash_map::Entry;
 
 impl CommandSpec for command::BitCount {
     const NAME: &'static str = "BITCOUNT";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::BitCount {
@@ -87,6 +88,11 @@ impl ReadCommandHandler for command::BitCount {
 impl CommandSpec for command::BitOp {
     const NAME: &'static str = "BITOP";
     const ARITY: Arity = Arity::AtLeast(3);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 1,
+        last: -1,
+        step: 1,
+    };
 }
 
 impl WriteCommandHandler for command::BitOp {
@@ -158,6 +164,7 @@ impl WriteCommandHandler for command::BitOp {
 impl CommandSpec for command::GetBit {
     const NAME: &'static str = "GETBIT";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::GetBit {
@@ -184,6 +191,7 @@ impl ReadCommandHandler for command::GetBit {
 impl CommandSpec for command::SetBit {
     const NAME: &'static str = "SETBIT";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::SetBit {
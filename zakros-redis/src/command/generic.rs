@@ -1,15 +1,16 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, ExpireTime, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
     lockable::{ReadLockable, RwLockable},
     resp::Value,
-    Dictionary, Object, RedisResult, ResponseError,
+    BytesExt, Dictionary, Object, RedisResult, ResponseError,
 };
 use bytes::Bytes;
 
 impl CommandSpec for command::Del {
     const NAME: &'static str = "DEL";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::Del {
@@ -31,6 +32,7 @@ impl WriteCommandHandler for command::Del {
 impl CommandSpec for command::Exists {
     const NAME: &'static str = "EXISTS";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl ReadCommandHandler for command::Exists {
@@ -49,6 +51,42 @@ impl ReadCommandHandler for command::Exists {
     }
 }
 
+impl CommandSpec for command::Expire {
+    const NAME: &'static str = "EXPIRE";
+    const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::Expire {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        expire(
+            dict,
+            args,
+            1000,
+            true,
+            "invalid expire time in 'expire' command",
+        )
+    }
+}
+
+impl CommandSpec for command::ExpireAt {
+    const NAME: &'static str = "EXPIREAT";
+    const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::ExpireAt {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        expire(
+            dict,
+            args,
+            1000,
+            false,
+            "invalid expire time in 'expireat' command",
+        )
+    }
+}
+
 impl CommandSpec for command::Keys {
     const NAME: &'static str = "KEYS";
     const ARITY: Arity = Arity::Fixed(1);
@@ -69,9 +107,77 @@ impl ReadCommandHandler for command::Keys {
     }
 }
 
+impl CommandSpec for command::Persist {
+    const NAME: &'static str = "PERSIST";
+    const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::Persist {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        let [key] = args else {
+            return Err(ResponseError::WrongArity.into());
+        };
+        Ok((dict.write().persist(key) as i64).into())
+    }
+}
+
+impl CommandSpec for command::PExpire {
+    const NAME: &'static str = "PEXPIRE";
+    const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::PExpire {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        expire(
+            dict,
+            args,
+            1,
+            true,
+            "invalid expire time in 'pexpire' command",
+        )
+    }
+}
+
+impl CommandSpec for command::PExpireAt {
+    const NAME: &'static str = "PEXPIREAT";
+    const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::PExpireAt {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        expire(
+            dict,
+            args,
+            1,
+            false,
+            "invalid expire time in 'pexpireat' command",
+        )
+    }
+}
+
+impl CommandSpec for command::PTtl {
+    const NAME: &'static str = "PTTL";
+    const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl ReadCommandHandler for command::PTtl {
+    fn call<'a, D: ReadLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        ttl(dict, args, 1)
+    }
+}
+
 impl CommandSpec for command::Rename {
     const NAME: &'static str = "RENAME";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: 1,
+        step: 1,
+    };
 }
 
 impl WriteCommandHandler for command::Rename {
@@ -79,13 +185,10 @@ impl WriteCommandHandler for command::Rename {
         let [key, new_key] = args else {
             return Err(ResponseError::WrongArity.into());
         };
-        let mut dict = dict.write();
-        match dict.remove(key) {
-            Some(value) => {
-                dict.insert(new_key.clone(), value);
-                Ok(Value::ok())
-            }
-            None => Err(ResponseError::NoKey.into()),
+        if move_key(&mut dict.write(), key, new_key) {
+            Ok(Value::ok())
+        } else {
+            Err(ResponseError::NoKey.into())
         }
     }
 }
@@ -93,6 +196,11 @@ impl WriteCommandHandler for command::Rename {
 impl CommandSpec for command::RenameNx {
     const NAME: &'static str = "RENAMENX";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: 1,
+        step: 1,
+    };
 }
 
 impl WriteCommandHandler for command::RenameNx {
@@ -107,15 +215,27 @@ impl WriteCommandHandler for command::RenameNx {
         if dict.contains_key(new_key) {
             return Ok(0.into());
         }
-        let value = dict.remove(key).unwrap();
-        dict.insert(new_key.clone(), value);
+        move_key(&mut dict, key, new_key);
         Ok(1.into())
     }
 }
 
+impl CommandSpec for command::Ttl {
+    const NAME: &'static str = "TTL";
+    const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl ReadCommandHandler for command::Ttl {
+    fn call<'a, D: ReadLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        ttl(dict, args, 1000)
+    }
+}
+
 impl CommandSpec for command::Type {
     const NAME: &'static str = "TYPE";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::Type {
@@ -137,6 +257,7 @@ impl ReadCommandHandler for command::Type {
 impl CommandSpec for command::Unlink {
     const NAME: &'static str = "UNLINK";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::Unlink {
@@ -158,3 +279,100 @@ impl WriteCommandHandler for command::Unlink {
         Ok((num_unlinked as i64).into())
     }
 }
+
+fn expire<'a, D: RwLockable<'a, Dictionary>>(
+    dict: &'a D,
+    args: &[Bytes],
+    unit_ms: i64,
+    is_relative: bool,
+    invalid_time_error: &'static str,
+) -> RedisResult {
+    let [key, time, options @ ..] = args else {
+        return Err(ResponseError::WrongArity.into());
+    };
+    let time = time.to_i64()?;
+    let mut nx = false;
+    let mut xx = false;
+    let mut gt = false;
+    let mut lt = false;
+    for option in options {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GT" => gt = true,
+            b"LT" => lt = true,
+            _ => return Err(ResponseError::SyntaxError.into()),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(ResponseError::Other(
+            "NX and XX, GT or LT options at the same time are not compatible",
+        )
+        .into());
+    }
+    if gt && lt {
+        return Err(
+            ResponseError::Other("GT and LT options at the same time are not compatible").into(),
+        );
+    }
+
+    let mut dict = dict.write();
+    let expire_time = ExpireTime {
+        time,
+        unit_ms,
+        is_relative,
+    };
+    let Some(time) = expire_time.resolve(dict.now()) else {
+        return Err(ResponseError::Other(invalid_time_error).into());
+    };
+    if !dict.contains_key(key) {
+        return Ok(0.into());
+    }
+
+    // A key without an expiration time is treated as having an infinite TTL
+    let is_allowed = match dict.expire_at(key) {
+        Some(current) => !nx && (!gt || time > current) && (!lt || time < current),
+        None => !xx && !gt,
+    };
+    if !is_allowed {
+        return Ok(0.into());
+    }
+    dict.set_expire_at(key, time);
+    Ok(1.into())
+}
+
+fn ttl<'a, D: ReadLockable<'a, Dictionary>>(
+    dict: &'a D,
+    args: &[Bytes],
+    unit_ms: u64,
+) -> RedisResult {
+    let [key] = args else {
+        return Err(ResponseError::WrongArity.into());
+    };
+    let dict = dict.read();
+    if !dict.contains_key(key) {
+        return Ok((-2).into());
+    }
+    let ttl = match dict.expire_at(key) {
+        Some(time) => {
+            let ttl_ms = time.saturating_sub(dict.now());
+            ((ttl_ms + unit_ms / 2) / unit_ms) as i64
+        }
+        None => -1,
+    };
+    Ok(ttl.into())
+}
+
+/// Moves the value and its expiration time from `key` to `new_key`, and
+/// returns whether `key` existed.
+fn move_key(dict: &mut Dictionary, key: &[u8], new_key: &Bytes) -> bool {
+    let expire_at = dict.expire_at(key);
+    let Some(value) = dict.remove(key) else {
+        return false;
+    };
+    dict.insert(new_key.clone(), value);
+    if let Some(time) = expire_at {
+        dict.set_expire_at(new_key, time);
+    }
+    true
+}
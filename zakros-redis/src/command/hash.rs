@@ -1,16 +1,18 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     lockable::{ReadLockable, RwLockable},
     resp::Value,
     BytesExt, Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::Bytes;
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map, HashMap};
 
 impl CommandSpec for command::HDel {
     const NAME: &'static str = "HDEL";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::HDel {
@@ -45,6 +47,7 @@ impl WriteCommandHandler for command::HDel {
 impl CommandSpec for command::HExists {
     const NAME: &'static str = "HEXISTS";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HExists {
@@ -63,6 +66,7 @@ impl ReadCommandHandler for command::HExists {
 impl CommandSpec for command::HGet {
     const NAME: &'static str = "HGET";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HGet {
@@ -84,6 +88,7 @@ impl ReadCommandHandler for command::HGet {
 impl CommandSpec for command::HGetAll {
     const NAME: &'static str = "HGETALL";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HGetAll {
@@ -102,6 +107,7 @@ impl ReadCommandHandler for command::HGetAll {
 impl CommandSpec for command::HIncrBy {
     const NAME: &'static str = "HINCRBY";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::HIncrBy {
@@ -116,7 +122,7 @@ impl WriteCommandHandler for command::HIncrBy {
                     return Err(RedisError::WrongType);
                 };
                 match hash.entry(field.clone()) {
-                    Entry::Occupied(mut field_entry) => {
+                    hash_map::Entry::Occupied(mut field_entry) => {
                         let value = field_entry.get().to_i64().map_err(|_| {
                             RedisError::from(ResponseError::Other("hash value is not an integer"))
                         })?;
@@ -128,7 +134,7 @@ impl WriteCommandHandler for command::HIncrBy {
                         field_entry.insert(new_value.to_string().into_bytes().into());
                         Ok(new_value.into())
                     }
-                    Entry::Vacant(field_entry) => {
+                    hash_map::Entry::Vacant(field_entry) => {
                         field_entry.insert(increment.to_string().into_bytes().into());
                         Ok(increment.into())
                     }
@@ -148,6 +154,7 @@ impl WriteCommandHandler for command::HIncrBy {
 impl CommandSpec for command::HKeys {
     const NAME: &'static str = "HKEYS";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HKeys {
@@ -164,6 +171,7 @@ impl ReadCommandHandler for command::HKeys {
 impl CommandSpec for command::HLen {
     const NAME: &'static str = "HLEN";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HLen {
@@ -175,6 +183,7 @@ impl ReadCommandHandler for command::HLen {
 impl CommandSpec for command::HMGet {
     const NAME: &'static str = "HMGET";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HMGet {
@@ -205,6 +214,7 @@ impl ReadCommandHandler for command::HMGet {
 impl CommandSpec for command::HMSet {
     const NAME: &'static str = "HMSET";
     const ARITY: Arity = Arity::AtLeast(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::HMSet {
@@ -217,6 +227,7 @@ impl WriteCommandHandler for command::HMSet {
 impl CommandSpec for command::HStrLen {
     const NAME: &'static str = "HSTRLEN";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HStrLen {
@@ -238,6 +249,7 @@ impl ReadCommandHandler for command::HStrLen {
 impl CommandSpec for command::HSet {
     const NAME: &'static str = "HSET";
     const ARITY: Arity = Arity::AtLeast(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::HSet {
@@ -276,6 +288,7 @@ impl WriteCommandHandler for command::HSet {
 impl CommandSpec for command::HSetNx {
     const NAME: &'static str = "HSETNX";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::HSetNx {
@@ -289,8 +302,8 @@ impl WriteCommandHandler for command::HSetNx {
                     return Err(RedisError::WrongType);
                 };
                 match hash.entry(field.clone()) {
-                    Entry::Occupied(_) => false,
-                    Entry::Vacant(entry) => {
+                    hash_map::Entry::Occupied(_) => false,
+                    hash_map::Entry::Vacant(entry) => {
                         entry.insert(value.clone());
                         true
                     }
@@ -308,6 +321,7 @@ impl WriteCommandHandler for command::HSetNx {
 impl CommandSpec for command::HVals {
     const NAME: &'static str = "HVALS";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::HVals {
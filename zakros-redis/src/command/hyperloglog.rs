@@ -1,17 +1,18 @@
-use super::{Arity, CommandSpec, WriteCommandHandler};
+use super::{Arity, CommandSpec, KeySpec, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     hyperloglog::{DenseHyperLogLog, RawHyperLogLog},
     lockable::RwLockable,
     resp::Value,
     Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::Bytes;
-use std::collections::hash_map::Entry;
 
 impl CommandSpec for command::PfAdd {
     const NAME: &'static str = "PFADD";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::PfAdd {
@@ -46,6 +47,7 @@ impl WriteCommandHandler for command::PfAdd {
 impl CommandSpec for command::PfCount {
     const NAME: &'static str = "PFCOUNT";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::PfCount {
@@ -84,6 +86,7 @@ impl WriteCommandHandler for command::PfCount {
 impl CommandSpec for command::PfMerge {
     const NAME: &'static str = "PFMERGE";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::PfMerge {
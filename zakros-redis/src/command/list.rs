@@ -1,16 +1,18 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     lockable::{ReadLockable, RwLockable},
     resp::Value,
     BytesExt, Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::Bytes;
-use std::collections::{hash_map::Entry, VecDeque};
+use std::collections::VecDeque;
 
 impl CommandSpec for command::LIndex {
     const NAME: &'static str = "LINDEX";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::LIndex {
@@ -40,6 +42,7 @@ impl ReadCommandHandler for command::LIndex {
 impl CommandSpec for command::LLen {
     const NAME: &'static str = "LLEN";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::LLen {
@@ -58,6 +61,7 @@ impl ReadCommandHandler for command::LLen {
 impl CommandSpec for command::LPop {
     const NAME: &'static str = "LPOP";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::LPop {
@@ -69,6 +73,7 @@ impl WriteCommandHandler for command::LPop {
 impl CommandSpec for command::LPush {
     const NAME: &'static str = "LPUSH";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::LPush {
@@ -80,6 +85,7 @@ impl WriteCommandHandler for command::LPush {
 impl CommandSpec for command::LPushX {
     const NAME: &'static str = "LPUSHX";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::LPushX {
@@ -91,6 +97,7 @@ impl WriteCommandHandler for command::LPushX {
 impl CommandSpec for command::LRange {
     const NAME: &'static str = "LRANGE";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::LRange {
@@ -130,6 +137,7 @@ impl ReadCommandHandler for command::LRange {
 impl CommandSpec for command::LSet {
     const NAME: &'static str = "LSET";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::LSet {
@@ -160,6 +168,7 @@ impl WriteCommandHandler for command::LSet {
 impl CommandSpec for command::LTrim {
     const NAME: &'static str = "LTRIM";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::LTrim {
@@ -196,6 +205,7 @@ impl WriteCommandHandler for command::LTrim {
 impl CommandSpec for command::RPop {
     const NAME: &'static str = "RPOP";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::RPop {
@@ -207,6 +217,11 @@ impl WriteCommandHandler for command::RPop {
 impl CommandSpec for command::RPopLPush {
     const NAME: &'static str = "RPOPLPUSH";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: 1,
+        step: 1,
+    };
 }
 
 impl WriteCommandHandler for command::RPopLPush {
@@ -250,6 +265,7 @@ impl WriteCommandHandler for command::RPopLPush {
 impl CommandSpec for command::RPush {
     const NAME: &'static str = "RPUSH";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::RPush {
@@ -261,6 +277,7 @@ impl WriteCommandHandler for command::RPush {
 impl CommandSpec for command::RPushX {
     const NAME: &'static str = "RPUSHX";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::RPushX {
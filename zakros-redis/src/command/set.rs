@@ -1,16 +1,18 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     lockable::{ReadLockable, RwLockable},
     resp::Value,
     Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::Bytes;
-use std::collections::{hash_map::Entry, HashSet};
+use std::collections::HashSet;
 
 impl CommandSpec for command::SAdd {
     const NAME: &'static str = "SADD";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::SAdd {
@@ -43,6 +45,7 @@ impl WriteCommandHandler for command::SAdd {
 impl CommandSpec for command::SCard {
     const NAME: &'static str = "SCARD";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::SCard {
@@ -61,6 +64,7 @@ impl ReadCommandHandler for command::SCard {
 impl CommandSpec for command::SDiff {
     const NAME: &'static str = "SDIFF";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl ReadCommandHandler for command::SDiff {
@@ -72,6 +76,7 @@ impl ReadCommandHandler for command::SDiff {
 impl CommandSpec for command::SDiffStore {
     const NAME: &'static str = "SDIFFSTORE";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::SDiffStore {
@@ -83,6 +88,7 @@ impl WriteCommandHandler for command::SDiffStore {
 impl CommandSpec for command::SInter {
     const NAME: &'static str = "SINTER";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl ReadCommandHandler for command::SInter {
@@ -94,6 +100,7 @@ impl ReadCommandHandler for command::SInter {
 impl CommandSpec for command::SInterStore {
     const NAME: &'static str = "SINTERSTORE";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::SInterStore {
@@ -105,6 +112,7 @@ impl WriteCommandHandler for command::SInterStore {
 impl CommandSpec for command::SIsMember {
     const NAME: &'static str = "SISMEMBER";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::SIsMember {
@@ -123,6 +131,7 @@ impl ReadCommandHandler for command::SIsMember {
 impl CommandSpec for command::SMembers {
     const NAME: &'static str = "SMEMBERS";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::SMembers {
@@ -144,6 +153,7 @@ impl ReadCommandHandler for command::SMembers {
 impl CommandSpec for command::SMIsMember {
     const NAME: &'static str = "SMISMEMBER";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::SMIsMember {
@@ -171,6 +181,11 @@ impl ReadCommandHandler for command::SMIsMember {
 impl CommandSpec for command::SMove {
     const NAME: &'static str = "SMOVE";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: 1,
+        step: 1,
+    };
 }
 
 impl WriteCommandHandler for command::SMove {
@@ -214,6 +229,7 @@ impl WriteCommandHandler for command::SMove {
 impl CommandSpec for command::SRem {
     const NAME: &'static str = "SREM";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::SRem {
@@ -248,6 +264,7 @@ impl WriteCommandHandler for command::SRem {
 impl CommandSpec for command::SUnion {
     const NAME: &'static str = "SUNION";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl ReadCommandHandler for command::SUnion {
@@ -259,6 +276,7 @@ impl ReadCommandHandler for command::SUnion {
 impl CommandSpec for command::SUnionStore {
     const NAME: &'static str = "SUNIONSTORE";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl WriteCommandHandler for command::SUnionStore {
@@ -1,16 +1,17 @@
-use super::{Arity, CommandSpec, ReadCommandHandler, WriteCommandHandler};
+use super::{Arity, CommandSpec, ExpireTime, KeySpec, ReadCommandHandler, WriteCommandHandler};
 use crate::{
     command,
+    dictionary::Entry,
     lockable::{ReadLockable, RwLockable},
     resp::Value,
     BytesExt, Dictionary, Object, RedisError, RedisResult, ResponseError,
 };
 use bytes::Bytes;
-use std::collections::hash_map::Entry;
 
 impl CommandSpec for command::Append {
     const NAME: &'static str = "APPEND";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::Append {
@@ -38,6 +39,7 @@ impl WriteCommandHandler for command::Append {
 impl CommandSpec for command::Decr {
     const NAME: &'static str = "DECR";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::Decr {
@@ -52,6 +54,7 @@ impl WriteCommandHandler for command::Decr {
 impl CommandSpec for command::DecrBy {
     const NAME: &'static str = "DECRBY";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::DecrBy {
@@ -70,6 +73,7 @@ impl WriteCommandHandler for command::DecrBy {
 impl CommandSpec for command::Get {
     const NAME: &'static str = "GET";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::Get {
@@ -88,6 +92,7 @@ impl ReadCommandHandler for command::Get {
 impl CommandSpec for command::GetRange {
     const NAME: &'static str = "GETRANGE";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::GetRange {
@@ -125,6 +130,7 @@ impl ReadCommandHandler for command::GetRange {
 impl CommandSpec for command::GetDel {
     const NAME: &'static str = "GETDEL";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::GetDel {
@@ -145,9 +151,56 @@ impl WriteCommandHandler for command::GetDel {
     }
 }
 
+impl CommandSpec for command::GetEx {
+    const NAME: &'static str = "GETEX";
+    const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::GetEx {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        const INVALID_TIME: &str = "invalid expire time in 'getex' command";
+        let [key, options @ ..] = args else {
+            return Err(ResponseError::WrongArity.into());
+        };
+        let mut expire_time = None;
+        let mut persist = false;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"PERSIST" if expire_time.is_none() => persist = true,
+                option @ (b"EX" | b"PX" | b"EXAT" | b"PXAT")
+                    if expire_time.is_none() && !persist =>
+                {
+                    expire_time = Some(parse_expire_option(option, options.next(), INVALID_TIME)?);
+                }
+                _ => return Err(ResponseError::SyntaxError.into()),
+            }
+        }
+
+        let mut dict = dict.write();
+        let now = dict.now();
+        let expire_at = expire_time
+            .map(|time| time.resolve(now).ok_or(ResponseError::Other(INVALID_TIME)))
+            .transpose()?;
+        let value = match dict.get(key) {
+            Some(Object::String(value)) => Bytes::from(value.clone()),
+            Some(_) => return Err(RedisError::WrongType),
+            None => return Ok(Value::Null),
+        };
+        if let Some(time) = expire_at {
+            dict.set_expire_at(key, time);
+        } else if persist {
+            dict.persist(key);
+        }
+        Ok(value.into())
+    }
+}
+
 impl CommandSpec for command::GetSet {
     const NAME: &'static str = "GETSET";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::GetSet {
@@ -155,25 +208,28 @@ impl WriteCommandHandler for command::GetSet {
         let [key, value] = args else {
             return Err(ResponseError::WrongArity.into());
         };
-        match dict.write().entry(key.clone()) {
+        let mut dict = dict.write();
+        let prev_value = match dict.entry(key.clone()) {
             Entry::Occupied(entry) => {
                 let Object::String(s) = entry.into_mut() else {
                     return Err(RedisError::WrongType);
                 };
-                let prev_value = std::mem::replace(s, value.to_vec());
-                Ok(Bytes::from(prev_value).into())
+                Bytes::from(std::mem::replace(s, value.to_vec())).into()
             }
             Entry::Vacant(entry) => {
                 entry.insert(value.to_vec().into());
-                Ok(Value::Null)
+                Value::Null
             }
-        }
+        };
+        dict.persist(key);
+        Ok(prev_value)
     }
 }
 
 impl CommandSpec for command::Incr {
     const NAME: &'static str = "INCR";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::Incr {
@@ -188,6 +244,7 @@ impl WriteCommandHandler for command::Incr {
 impl CommandSpec for command::IncrBy {
     const NAME: &'static str = "INCRBY";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::IncrBy {
@@ -202,6 +259,7 @@ impl WriteCommandHandler for command::IncrBy {
 impl CommandSpec for command::MGet {
     const NAME: &'static str = "MGET";
     const ARITY: Arity = Arity::AtLeast(1);
+    const KEYS: KeySpec = KeySpec::ALL;
 }
 
 impl ReadCommandHandler for command::MGet {
@@ -226,11 +284,16 @@ impl ReadCommandHandler for command::MGet {
 impl CommandSpec for command::MSet {
     const NAME: &'static str = "MSET";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: -1,
+        step: 2,
+    };
 }
 
 impl WriteCommandHandler for command::MSet {
     fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
-        if args.is_empty() || args.len() % 2 != 0 {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
             return Err(ResponseError::WrongArity.into());
         }
         let mut dict = dict.write();
@@ -245,11 +308,16 @@ impl WriteCommandHandler for command::MSet {
 impl CommandSpec for command::MSetNx {
     const NAME: &'static str = "MSETNX";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::Range {
+        first: 0,
+        last: -1,
+        step: 2,
+    };
 }
 
 impl WriteCommandHandler for command::MSetNx {
     fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
-        if args.is_empty() || args.len() % 2 != 0 {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
             return Err(ResponseError::WrongArity.into());
         }
         let mut dict = dict.write();
@@ -266,63 +334,96 @@ impl WriteCommandHandler for command::MSetNx {
     }
 }
 
+impl CommandSpec for command::PSetEx {
+    const NAME: &'static str = "PSETEX";
+    const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::PSetEx {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        set_ex(dict, args, 1, "invalid expire time in 'psetex' command")
+    }
+}
+
 impl CommandSpec for command::Set {
     const NAME: &'static str = "SET";
     const ARITY: Arity = Arity::AtLeast(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::Set {
     fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        const INVALID_TIME: &str = "invalid expire time in 'set' command";
         let [key, value, options @ ..] = args else {
             return Err(ResponseError::WrongArity.into());
         };
         let mut nx = false;
         let mut xx = false;
         let mut get = false;
-        for option in options {
+        let mut keep_ttl = false;
+        let mut expire_time = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
             match option.to_ascii_uppercase().as_slice() {
                 b"NX" => nx = true,
                 b"XX" => xx = true,
                 b"GET" => get = true,
+                b"KEEPTTL" if expire_time.is_none() => keep_ttl = true,
+                option @ (b"EX" | b"PX" | b"EXAT" | b"PXAT")
+                    if expire_time.is_none() && !keep_ttl =>
+                {
+                    expire_time = Some(parse_expire_option(option, options.next(), INVALID_TIME)?);
+                }
                 _ => return Err(ResponseError::SyntaxError.into()),
             }
         }
         if nx && xx {
             return Err(ResponseError::SyntaxError.into());
         }
-        match dict.write().entry(key.clone()) {
-            Entry::Occupied(mut entry) => {
-                if get {
-                    let Object::String(s) = entry.get_mut() else {
-                        return Err(RedisError::WrongType);
-                    };
-                    let prev_value = if nx {
-                        s.clone()
-                    } else {
-                        std::mem::replace(s, value.to_vec())
-                    };
-                    return Ok(Bytes::from(prev_value).into());
-                }
-                Ok(if !nx {
-                    entry.insert(value.to_vec().into());
-                    Value::ok()
-                } else {
-                    Value::Null
-                })
-            }
-            Entry::Vacant(entry) => {
-                if !xx {
-                    entry.insert(value.to_vec().into());
-                }
-                Ok(if get || xx { Value::Null } else { Value::ok() })
-            }
+
+        let mut dict = dict.write();
+        let now = dict.now();
+        let mut expire_at = expire_time
+            .map(|time| time.resolve(now).ok_or(ResponseError::Other(INVALID_TIME)))
+            .transpose()?;
+        let (exists, prev_value) = match dict.get(key) {
+            Some(Object::String(s)) => (true, get.then(|| Bytes::from(s.clone()))),
+            Some(_) if get => return Err(RedisError::WrongType),
+            Some(_) => (true, None),
+            None => (false, None),
+        };
+        let prev_value = prev_value.map_or(Value::Null, Into::into);
+        if (nx && exists) || (xx && !exists) {
+            return Ok(if get { prev_value } else { Value::Null });
         }
+        if keep_ttl {
+            expire_at = dict.expire_at(key);
+        }
+        dict.insert(key.clone(), value.to_vec().into());
+        if let Some(time) = expire_at {
+            dict.set_expire_at(key, time);
+        }
+        Ok(if get { prev_value } else { Value::ok() })
+    }
+}
+
+impl CommandSpec for command::SetEx {
+    const NAME: &'static str = "SETEX";
+    const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
+}
+
+impl WriteCommandHandler for command::SetEx {
+    fn call<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, args: &[Bytes]) -> RedisResult {
+        set_ex(dict, args, 1000, "invalid expire time in 'setex' command")
     }
 }
 
 impl CommandSpec for command::SetNx {
     const NAME: &'static str = "SETNX";
     const ARITY: Arity = Arity::Fixed(2);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::SetNx {
@@ -342,6 +443,7 @@ impl WriteCommandHandler for command::SetNx {
 impl CommandSpec for command::SetRange {
     const NAME: &'static str = "SETRANGE";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl WriteCommandHandler for command::SetRange {
@@ -386,6 +488,7 @@ impl WriteCommandHandler for command::SetRange {
 impl CommandSpec for command::StrLen {
     const NAME: &'static str = "STRLEN";
     const ARITY: Arity = Arity::Fixed(1);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::StrLen {
@@ -404,6 +507,7 @@ impl ReadCommandHandler for command::StrLen {
 impl CommandSpec for command::SubStr {
     const NAME: &'static str = "SUBSTR";
     const ARITY: Arity = Arity::Fixed(3);
+    const KEYS: KeySpec = KeySpec::SINGLE;
 }
 
 impl ReadCommandHandler for command::SubStr {
@@ -433,3 +537,54 @@ fn incr<'a, D: RwLockable<'a, Dictionary>>(dict: &'a D, key: &Bytes, delta: i64)
         }
     }
 }
+
+fn set_ex<'a, D: RwLockable<'a, Dictionary>>(
+    dict: &'a D,
+    args: &[Bytes],
+    unit_ms: i64,
+    invalid_time_error: &'static str,
+) -> RedisResult {
+    let [key, time, value] = args else {
+        return Err(ResponseError::WrongArity.into());
+    };
+    let time = time.to_i64()?;
+    if time <= 0 {
+        return Err(ResponseError::Other(invalid_time_error).into());
+    }
+    let mut dict = dict.write();
+    let expire_time = ExpireTime {
+        time,
+        unit_ms,
+        is_relative: true,
+    };
+    let Some(expire_at) = expire_time.resolve(dict.now()) else {
+        return Err(ResponseError::Other(invalid_time_error).into());
+    };
+    dict.insert(key.clone(), value.to_vec().into());
+    dict.set_expire_at(key, expire_at);
+    Ok(Value::ok())
+}
+
+/// Parses the EX, PX, EXAT or PXAT option and its argument.
+fn parse_expire_option(
+    option: &[u8],
+    time: Option<&Bytes>,
+    invalid_time_error: &'static str,
+) -> Result<ExpireTime, RedisError> {
+    let (unit_ms, is_relative) = match option {
+        b"EX" => (1000, true),
+        b"PX" => (1, true),
+        b"EXAT" => (1000, false),
+        b"PXAT" => (1, false),
+        _ => unreachable!(),
+    };
+    let time = time.ok_or(ResponseError::SyntaxError)?.to_i64()?;
+    if time <= 0 {
+        return Err(ResponseError::Other(invalid_time_error).into());
+    }
+    Ok(ExpireTime {
+        time,
+        unit_ms,
+        is_relative,
+    })
+}
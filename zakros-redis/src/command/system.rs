@@ -1,6 +1,11 @@
 use super::{Arity, CommandSpec};
 use crate::command;
 
+impl CommandSpec for command::Client {
+    const NAME: &'static str = "CLIENT";
+    const ARITY: Arity = Arity::AtLeast(1);
+}
+
 impl CommandSpec for command::Cluster {
     const NAME: &'static str = "CLUSTER";
     const ARITY: Arity = Arity::AtLeast(1);
@@ -0,0 +1,377 @@
+use crate::{cluster, Object};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map, HashMap, HashSet},
+    time::SystemTime,
+};
+
+/// Keys and their values, with optional expiration times.
+///
+/// An expired key is treated as nonexistent by every method, and is removed
+/// on the first mutable access to it. Times are in milliseconds since the
+/// UNIX epoch.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(from = "Persisted")]
+pub struct Dictionary {
+    objects: HashMap<Bytes, Object>,
+
+    /// expiration times of the keys. A key removed through `entry` leaves
+    /// its expiration time behind, which is ignored because the key no
+    /// longer exists, and is dropped when the key is created again or the
+    /// time passes.
+    expires: HashMap<Bytes, u64>,
+
+    /// time used instead of the system clock
+    #[serde(skip)]
+    time: Option<u64>,
+
+    /// keys in each hash slot
+    #[serde(skip)]
+    slots: SlotIndex,
+}
+
+/// Serialized fields of `Dictionary`, from which the rest is rebuilt
+#[derive(Deserialize)]
+struct Persisted {
+    objects: HashMap<Bytes, Object>,
+    expires: HashMap<Bytes, u64>,
+}
+
+impl From<Persisted> for Dictionary {
+    fn from(persisted: Persisted) -> Self {
+        let mut slots = SlotIndex::default();
+        for key in persisted.objects.keys() {
+            slots.insert(key.clone());
+        }
+        Self {
+            objects: persisted.objects,
+            expires: persisted.expires,
+            time: None,
+            slots,
+        }
+    }
+}
+
+impl Dictionary {
+    /// Returns the current time.
+    ///
+    /// The system clock is clamped to the range of `u64` milliseconds since
+    /// the epoch.
+    pub fn now(&self) -> u64 {
+        self.time.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since_epoch| {
+                    since_epoch.as_millis().try_into().unwrap_or(u64::MAX)
+                })
+        })
+    }
+
+    /// Makes the dictionary use `time` as the current time instead of the
+    /// system clock, or go back to the system clock if `time` is `None`.
+    ///
+    /// Replicas use the time assigned to the command by the leader, so that
+    /// they agree on which keys have expired.
+    pub fn set_time(&mut self, time: Option<u64>) {
+        self.time = time;
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Object> {
+        self.objects.get(key).filter(|_| !self.is_expired(key))
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Object> {
+        self.expire_if_needed(key);
+        self.objects.get_mut(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn entry(&mut self, key: Bytes) -> Entry<'_> {
+        self.expire_if_needed(&key);
+        if !self.objects.contains_key(&key) {
+            self.expires.remove(&key);
+        }
+        let slots = &mut self.slots;
+        match self.objects.entry(key) {
+            hash_map::Entry::Occupied(inner) => Entry::Occupied(OccupiedEntry { inner, slots }),
+            hash_map::Entry::Vacant(inner) => Entry::Vacant(VacantEntry { inner, slots }),
+        }
+    }
+
+    /// Inserts the value, discarding the expiration time of the previous
+    /// value.
+    pub fn insert(&mut self, key: Bytes, value: Object) -> Option<Object> {
+        self.expire_if_needed(&key);
+        self.expires.remove(&key);
+        let prev = self.objects.insert(key.clone(), value);
+        if prev.is_none() {
+            self.slots.insert(key);
+        }
+        prev
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Object> {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    pub fn remove_entry(&mut self, key: &[u8]) -> Option<(Bytes, Object)> {
+        self.expire_if_needed(key);
+        self.expires.remove(key);
+        let removed = self.objects.remove_entry(key);
+        if removed.is_some() {
+            self.slots.remove(key);
+        }
+        removed
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        let now = self.now();
+        self.objects
+            .keys()
+            .filter(move |key| self.expires.get(*key).is_none_or(|time| *time > now))
+    }
+
+    /// Returns the number of keys, including the expired keys that have not
+    /// been removed yet.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.objects.clear();
+        self.expires.clear();
+        self.slots.0.clear();
+    }
+
+    /// Returns the number of keys in the hash slot, including the expired
+    /// keys that have not been removed yet.
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.slots.0.get(&slot).map_or(0, HashSet::len)
+    }
+
+    pub fn keys_in_slot(&self, slot: u16) -> impl Iterator<Item = &Bytes> {
+        self.slots
+            .0
+            .get(&slot)
+            .into_iter()
+            .flatten()
+            .filter(|key| !self.is_expired(key))
+    }
+
+    /// Returns the expiration time of the key, or `None` if the key doesn't
+    /// exist or has no expiration time.
+    pub fn expire_at(&self, key: &[u8]) -> Option<u64> {
+        self.get(key)?;
+        self.expires.get(key).copied()
+    }
+
+    /// Sets the expiration time of an existing key.
+    ///
+    /// The key is removed right away if the time has already passed.
+    pub fn set_expire_at(&mut self, key: &[u8], time: u64) {
+        let Some((key, _)) = self.objects.get_key_value(key) else {
+            return;
+        };
+        let key = key.clone();
+        self.expires.insert(key.clone(), time);
+        self.expire_if_needed(&key);
+    }
+
+    /// Removes the expiration time of the key, and returns whether it had
+    /// one.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        self.expire_if_needed(key);
+        self.objects.contains_key(key) && self.expires.remove(key).is_some()
+    }
+
+    /// Removes the key if it has expired, and returns whether it was removed.
+    pub fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        let now = self.now();
+        match self.expires.get(key) {
+            Some(&time) if time <= now => {
+                self.expires.remove(key);
+                let is_removed = self.objects.remove(key).is_some();
+                if is_removed {
+                    self.slots.remove(key);
+                }
+                is_removed
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns up to `limit` keys that have expired but not been removed
+    /// yet.
+    pub fn expired_keys(&self, limit: usize) -> Vec<Bytes> {
+        let now = self.now();
+        self.expires
+            .iter()
+            .filter(|(_, time)| **time <= now)
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect()
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.expires
+            .get(key)
+            .is_some_and(|time| *time <= self.now())
+    }
+}
+
+/// A view into a key in `Dictionary`, which may be vacant or occupied.
+///
+/// Unlike `hash_map::Entry`, inserting or removing the key through it keeps
+/// the index of the keys in each hash slot up to date.
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+pub struct OccupiedEntry<'a> {
+    inner: hash_map::OccupiedEntry<'a, Bytes, Object>,
+    slots: &'a mut SlotIndex,
+}
+
+impl<'a> OccupiedEntry<'a> {
+    pub fn key(&self) -> &Bytes {
+        self.inner.key()
+    }
+
+    pub fn get(&self) -> &Object {
+        self.inner.get()
+    }
+
+    pub fn get_mut(&mut self) -> &mut Object {
+        self.inner.get_mut()
+    }
+
+    pub fn into_mut(self) -> &'a mut Object {
+        self.inner.into_mut()
+    }
+
+    pub fn insert(&mut self, value: Object) -> Object {
+        self.inner.insert(value)
+    }
+
+    pub fn remove(self) -> Object {
+        let (key, value) = self.inner.remove_entry();
+        self.slots.remove(&key);
+        value
+    }
+}
+
+pub struct VacantEntry<'a> {
+    inner: hash_map::VacantEntry<'a, Bytes, Object>,
+    slots: &'a mut SlotIndex,
+}
+
+impl<'a> VacantEntry<'a> {
+    pub fn key(&self) -> &Bytes {
+        self.inner.key()
+    }
+
+    pub fn insert(self, value: Object) -> &'a mut Object {
+        self.slots.insert(self.inner.key().clone());
+        self.inner.insert(value)
+    }
+}
+
+/// Keys in each hash slot, so that the keys in a slot can be counted and
+/// listed without scanning the whole dictionary
+#[derive(Default)]
+struct SlotIndex(HashMap<u16, HashSet<Bytes>>);
+
+impl SlotIndex {
+    fn insert(&mut self, key: Bytes) {
+        self.0
+            .entry(cluster::key_slot(&key))
+            .or_default()
+            .insert(key);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let hash_map::Entry::Occupied(mut entry) = self.0.entry(cluster::key_slot(key)) else {
+            return;
+        };
+        entry.get_mut().remove(key);
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dictionary, Entry};
+    use crate::{cluster::key_slot, Object};
+    use bytes::Bytes;
+
+    fn object() -> Object {
+        b"value".to_vec().into()
+    }
+
+    #[test]
+    fn expire() {
+        let mut dict = Dictionary::default();
+        dict.set_time(Some(1000));
+        dict.insert(Bytes::from_static(b"a"), object());
+        dict.insert(Bytes::from_static(b"b"), object());
+        dict.set_expire_at(b"a", 2000);
+        assert_eq!(dict.expire_at(b"a"), Some(2000));
+        assert_eq!(dict.expire_at(b"b"), None);
+
+        dict.set_time(Some(2000));
+        assert!(!dict.contains_key(b"a"));
+        assert_eq!(dict.keys().count(), 1);
+        assert_eq!(dict.len(), 2);
+        assert_eq!(dict.expired_keys(10), vec![Bytes::from_static(b"a")]);
+        assert!(dict.expire_if_needed(b"a"));
+        assert_eq!(dict.len(), 1);
+
+        dict.set_expire_at(b"b", 3000);
+        assert!(dict.persist(b"b"));
+        assert!(!dict.persist(b"b"));
+        dict.set_expire_at(b"b", 1500);
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn slot_index() {
+        let mut dict = Dictionary::default();
+        dict.set_time(Some(1000));
+        let keys = [&b"{user}:a"[..], b"{user}:b", b"{user}:c", b"other"];
+        for key in keys {
+            dict.insert(Bytes::from_static(key), object());
+        }
+        let slot = key_slot(b"user");
+        assert_eq!(dict.count_keys_in_slot(slot), 3);
+
+        let Entry::Occupied(entry) = dict.entry(Bytes::from_static(b"{user}:a")) else {
+            panic!("key should exist");
+        };
+        entry.remove();
+        dict.remove(b"{user}:b");
+        assert_eq!(dict.count_keys_in_slot(slot), 1);
+
+        let Entry::Vacant(entry) = dict.entry(Bytes::from_static(b"{user}:d")) else {
+            panic!("key should not exist");
+        };
+        entry.insert(object());
+        dict.set_expire_at(b"{user}:c", 2000);
+        dict.set_time(Some(2000));
+        let keys: Vec<_> = dict.keys_in_slot(slot).collect();
+        assert_eq!(keys, [&Bytes::from_static(b"{user}:d")]);
+        assert!(dict.expire_if_needed(b"{user}:c"));
+        assert_eq!(dict.count_keys_in_slot(slot), 1);
+        assert_eq!(dict.count_keys_in_slot(key_slot(b"other")), 1);
+    }
+}
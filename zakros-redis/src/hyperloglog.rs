@@ -8,7 +8,7 @@ const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
 const HLL_BITS: usize = 6;
 const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
 const HLL_HDR_SIZE: usize = 16;
-const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS + 7) / 8;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
 const HLL_DENSE: u8 = 0;
 
 const HLL_ALPHA_INF: f64 = 0.7213475204444817;
@@ -1,5 +1,7 @@
+pub mod cluster;
 pub mod command;
 pub mod config;
+pub mod dictionary;
 pub mod lockable;
 pub mod pubsub;
 pub mod resp;
@@ -7,9 +9,12 @@ pub mod string;
 
 mod hyperloglog;
 
+pub use dictionary::Dictionary;
+
 use bstr::ByteSlice;
 use bytes::Bytes;
 use resp::{ProtocolError, Value};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     net::SocketAddr,
@@ -29,8 +34,14 @@ pub enum RedisError {
     #[error("MOVED {slot} {addr}")]
     Moved { slot: u16, addr: SocketAddr },
 
+    #[error("CROSSSLOT Keys in request don't hash to the same slot")]
+    CrossSlot,
+
     #[error("CLUSTERDOWN {0}")]
     ClusterDown(String),
+
+    #[error("MISCONF {0}")]
+    Misconf(String),
 }
 
 #[derive(Debug, thiserror::Error, Clone, PartialEq, Eq)]
@@ -74,6 +85,7 @@ pub type RedisResult = Result<Value, RedisError>;
 // TODO: use copy-on-write for string type
 // TODO: use actual list data structure for list type
 
+#[derive(Serialize, Deserialize)]
 pub enum Object {
     String(Vec<u8>),
     List(VecDeque<Bytes>),
@@ -99,8 +111,6 @@ impl From<HashMap<Bytes, Bytes>> for Object {
     }
 }
 
-pub type Dictionary = HashMap<Bytes, Object>;
-
 pub trait BytesExt {
     fn to_i32(&self) -> Result<i32, RedisError>;
     fn to_i64(&self) -> Result<i64, RedisError>;
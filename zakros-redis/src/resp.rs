@@ -11,6 +11,9 @@ pub enum Value {
     BulkString(Bytes),
     Integer(i64),
     Array(Vec<RedisResult>),
+
+    /// RESP-encoded reply, sent as is
+    Raw(Bytes),
 }
 
 impl From<&'static str> for Value {
@@ -56,6 +59,7 @@ impl Debug for Value {
                 }
                 f.write_str(")")
             }
+            Self::Raw(s) => write!(f, "raw({:?})", s.as_bstr()),
         }
     }
 }
@@ -210,6 +214,61 @@ impl Encoder<RedisResult> for RespCodec {
     }
 }
 
+/// Encodes the reply in RESP.
+pub fn to_bytes(value: &RedisResult) -> Bytes {
+    let mut buf = Vec::new();
+    encode(&mut buf, value).unwrap();
+    buf.into()
+}
+
+/// Parses a RESP-encoded reply, e.g. one produced by `to_bytes`.
+///
+/// Simple strings and errors are kept as `Value::Raw` because `Value` only
+/// holds static simple strings. Returns `None` if the reply is malformed.
+pub fn parse_reply(bytes: &Bytes) -> Option<Value> {
+    match parse_reply_at(bytes, 0)? {
+        (value, end) if end == bytes.len() => Some(value),
+        _ => None,
+    }
+}
+
+/// Parses the reply starting at `start`, returning it together with the
+/// position right after it.
+fn parse_reply_at(bytes: &Bytes, start: usize) -> Option<(Value, usize)> {
+    let line_end = start + bytes.get(start..)?.find(b"\r\n")?;
+    let line = bytes.get(start + 1..line_end)?;
+    let next = line_end + 2;
+    let parse_len = || -> Option<i64> { line.to_str().ok()?.parse().ok() };
+    match bytes[start] {
+        b'+' | b'-' => Some((Value::Raw(bytes.slice(start..next)), next)),
+        b':' => Some((Value::Integer(parse_len()?), next)),
+        b'$' => {
+            let Ok(len) = usize::try_from(parse_len()?) else {
+                return Some((Value::Null, next));
+            };
+            let end = next.checked_add(len)?;
+            if bytes.get(end..end.checked_add(2)?)? != b"\r\n" {
+                return None;
+            }
+            Some((Value::BulkString(bytes.slice(next..end)), end + 2))
+        }
+        b'*' => {
+            let Ok(len) = usize::try_from(parse_len()?) else {
+                return Some((Value::Null, next));
+            };
+            let mut values = Vec::new();
+            let mut pos = next;
+            for _ in 0..len {
+                let (value, end) = parse_reply_at(bytes, pos)?;
+                values.push(Ok(value));
+                pos = end;
+            }
+            Some((Value::Array(values), pos))
+        }
+        _ => None,
+    }
+}
+
 fn encode<W: Write>(writer: &mut W, value: &RedisResult) -> std::io::Result<()> {
     match value {
         Ok(Value::Null) => writer.write_all(b"$-1\r\n"),
@@ -233,6 +292,7 @@ fn encode<W: Write>(writer: &mut W, value: &RedisResult) -> std::io::Result<()>
             }
             Ok(())
         }
+        Ok(Value::Raw(s)) => writer.write_all(s),
         Err(err) => {
             write!(writer, "-{}\r\n", err)
         }
@@ -1,18 +1,26 @@
+mod client;
 mod cluster;
 mod debug;
 mod generic;
 mod pubsub;
 mod server;
 
-use crate::{connection::RedisConnection, store::RaftCommand};
+use crate::{
+    connection::RedisConnection,
+    group::{Group, Groups},
+    session::ClientSession,
+    store::RaftCommand,
+    Shared,
+};
 use bytes::Bytes;
-use futures::SinkExt;
-use zakros_raft::RaftError;
+use futures::{future::try_join_all, SinkExt};
+use zakros_raft::{Raft, RaftError};
 use zakros_redis::{
+    cluster::key_slot,
     command::{RedisCommand, SystemCommand},
     pubsub::SubscriberRecvError,
-    resp::Value,
-    RedisError,
+    resp::{self, Value},
+    RedisError, RedisResult,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -36,31 +44,65 @@ pub async fn call(
     args: &[Bytes],
 ) -> Result<(), CommandError> {
     let result = match command {
-        RedisCommand::Write(command) => match &conn.shared.raft {
-            Some(raft) => {
-                raft.write(RaftCommand::SingleWrite((command, args.to_vec())))
-                    .await?
+        RedisCommand::Write(write_command) => {
+            let shared = conn.shared.clone();
+            let raft_command = || RaftCommand::SingleWrite((write_command, args.to_vec()));
+            match route(&shared, command.keys(args))? {
+                Route::Local(group) => write_command.call(&group.store, args),
+                Route::Slot(group, raft, slot) => write(raft, &mut conn.session, raft_command())
+                    .await
+                    .map_err(|err| group.redirect(err, slot))?,
+                Route::AllGroups => {
+                    merge(write_all(&shared.groups, &mut conn.session, raft_command()).await?)
+                }
             }
-            None => command.call(&conn.shared.store, args),
-        },
-        RedisCommand::Read(command) => {
-            match &conn.shared.raft {
-                Some(raft) if !conn.is_readonly => raft.read().await?,
-                _ => (),
+        }
+        RedisCommand::Read(read_command) => {
+            let shared = conn.shared.clone();
+            match route(&shared, command.keys(args))? {
+                Route::Local(group) => read_command.call(&group.store, args),
+                Route::Slot(group, raft, slot) => {
+                    if !conn.is_readonly {
+                        raft.read().await.map_err(|err| group.redirect(err, slot))?;
+                    }
+                    read_command.call(&group.store, args)
+                }
+                Route::AllGroups if conn.is_readonly || shared.config.raft_follower_read => {
+                    let mut results = Vec::new();
+                    for group in shared.groups.iter() {
+                        match &group.raft {
+                            Some(raft) if !conn.is_readonly => raft
+                                .read()
+                                .await
+                                .map_err(|err| group.redirect(err, group.slots.start))?,
+                            _ => (),
+                        }
+                        results.push(read_command.call(&group.store, args));
+                    }
+                    merge(results)
+                }
+                Route::AllGroups => {
+                    let results = shared
+                        .groups
+                        .iter()
+                        .map(|group| group.read_on_leader(read_command, args));
+                    merge(try_join_all(results).await?)
+                }
             }
-            command.call(&conn.shared.store, args)
         }
         RedisCommand::Stateless(command) => command.call(args),
         RedisCommand::System(command) => {
+            use client::*;
             use cluster::*;
             use debug::*;
             use generic::*;
             use pubsub::*;
             use server::*;
             match command {
+                SystemCommand::Client => Ok(client(conn, args).await?),
                 SystemCommand::Cluster => Ok(cluster(conn, args).await?),
                 SystemCommand::Debug => debug(conn, args),
-                SystemCommand::Info => info(conn, args),
+                SystemCommand::Info => Ok(info(conn, args).await?),
                 SystemCommand::PSubscribe => return psubscribe(conn, args).await,
                 SystemCommand::Publish => Ok(publish(conn, args).await?),
                 SystemCommand::PubSub => pubsub(conn, args),
@@ -68,7 +110,7 @@ pub async fn call(
                 SystemCommand::ReadOnly => readonly(conn, args),
                 SystemCommand::ReadWrite => readwrite(conn, args),
                 SystemCommand::Select => select(args),
-                SystemCommand::Shutdown => shutdown(args),
+                SystemCommand::Shutdown => Ok(shutdown(conn, args).await?),
                 SystemCommand::Subscribe => return subscribe(conn, args).await,
                 SystemCommand::Unsubscribe => return unsubscribe(conn, args).await,
                 SystemCommand::Config
@@ -91,10 +133,217 @@ pub async fn exec(
     conn: &mut RedisConnection,
     commands: Vec<(RedisCommand, Vec<Bytes>)>,
 ) -> Result<(), CommandError> {
-    let result = match &conn.shared.raft {
-        Some(raft) => raft.write(RaftCommand::Exec(commands)).await?,
-        None => conn.shared.store.exec(commands),
+    let shared = conn.shared.clone();
+    let keys = commands
+        .iter()
+        .flat_map(|(command, args)| command.keys(args));
+    let result = match route(&shared, keys)? {
+        Route::Local(group) => group.store.exec(commands),
+        Route::Slot(group, raft, slot) => {
+            write(raft, &mut conn.session, RaftCommand::Exec(commands))
+                .await
+                .map_err(|err| group.redirect(err, slot))?
+        }
+        Route::AllGroups => {
+            let command = RaftCommand::Exec(commands);
+            merge_exec(write_all(&shared.groups, &mut conn.session, command).await?)
+        }
     };
     conn.framed.send(result).await?;
     Ok(())
 }
+
+/// Where a command runs
+enum Route<'a> {
+    /// the only group, which has no Raft server as Raft is disabled
+    Local(&'a Group),
+
+    /// the group owning the slot of the keys. The client is redirected to
+    /// the leader of the group when this node can't serve the command.
+    Slot(&'a Group, &'a Raft<RaftCommand>, u16),
+
+    /// every group, through the leader of each group. Commands without keys
+    /// run on all the groups.
+    AllGroups,
+}
+
+/// Returns where a command with the keys runs. The keys must all be in the
+/// same slot.
+fn route<'a, 'b>(
+    shared: &'a Shared,
+    keys: impl Iterator<Item = &'b Bytes>,
+) -> Result<Route<'a>, RedisError> {
+    if !shared.config.raft_enabled {
+        return Ok(Route::Local(&shared.groups[0]));
+    }
+    let mut slot = None;
+    for key in keys {
+        let key_slot = key_slot(key);
+        match slot {
+            None => slot = Some(key_slot),
+            Some(slot) if slot != key_slot => return Err(RedisError::CrossSlot),
+            Some(_) => (),
+        }
+    }
+    let Some(slot) = slot else {
+        return Ok(Route::AllGroups);
+    };
+    let group = shared.groups.by_slot(slot);
+    let raft = group.raft.as_ref().expect("Raft is enabled");
+    Ok(Route::Slot(group, raft, slot))
+}
+
+/// Combines the replies of a command run on several Raft groups.
+///
+/// Only commands without keys run on several groups, and their replies are
+/// either counts, lists of keys, or OK. If a group replies with an error,
+/// the error is the reply.
+fn merge(results: Vec<RedisResult>) -> RedisResult {
+    let mut results = results.into_iter();
+    let mut merged = decode_raw(results.next().expect("command ran on no group")?);
+    for result in results {
+        if is_error(&merged) {
+            break;
+        }
+        match (&mut merged, decode_raw(result?)) {
+            (_, value) if is_error(&value) => merged = value,
+            (Value::Integer(merged), Value::Integer(n)) => *merged += n,
+            (Value::Array(merged), Value::Array(values)) => merged.extend(values),
+            _ => (),
+        }
+    }
+    Ok(merged)
+}
+
+/// Combines the replies of a transaction run on several Raft groups, command
+/// by command.
+fn merge_exec(results: Vec<RedisResult>) -> RedisResult {
+    let mut replies = Vec::with_capacity(results.len());
+    for result in results {
+        match decode_raw(result?) {
+            Value::Array(values) => replies.push(values.into_iter()),
+            value => return Ok(value),
+        }
+    }
+    let num_commands = replies.first().map_or(0, ExactSizeIterator::len);
+    let merged = (0..num_commands)
+        .map(|_| {
+            let results = replies
+                .iter_mut()
+                .map(|values| values.next().expect("groups ran different commands"))
+                .collect();
+            merge(results)
+        })
+        .collect();
+    Ok(Value::Array(merged))
+}
+
+/// Decodes a reply that a client session replayed or another node sent as
+/// RESP, so that it can be merged with the replies of the other groups.
+fn decode_raw(value: Value) -> Value {
+    match &value {
+        Value::Raw(bytes) => resp::parse_reply(bytes).unwrap_or(value),
+        _ => value,
+    }
+}
+
+fn is_error(value: &Value) -> bool {
+    matches!(value, Value::Raw(bytes) if bytes.starts_with(b"-"))
+}
+
+/// Replicates the write in every group through the leader of the group, as a
+/// part of the client session if the connection is bound to one.
+///
+/// The write has the same sequence number in every group, so that the client
+/// can retry it as a whole after it failed in some of the groups.
+async fn write_all(
+    groups: &Groups,
+    session: &mut Option<ClientSession>,
+    command: RaftCommand,
+) -> Result<Vec<RedisResult>, RedisError> {
+    let command = in_session(session.as_ref(), command);
+    let results = groups
+        .iter()
+        .map(|group| group.write_on_leader(command.clone()));
+    let results = try_join_all(results).await?;
+    if let Some(session) = session {
+        session.next_seq += 1;
+    }
+    Ok(results)
+}
+
+/// Replicates the write, as a part of the client session if the connection
+/// is bound to one.
+async fn write(
+    raft: &Raft<RaftCommand>,
+    session: &mut Option<ClientSession>,
+    command: RaftCommand,
+) -> Result<RedisResult, RaftError> {
+    let result = raft.write(in_session(session.as_ref(), command)).await?;
+
+    // The sequence number is consumed only when the outcome of the write is
+    // known, so that the client can safely retry the write on failure.
+    if let Some(session) = session {
+        session.next_seq += 1;
+    }
+    Ok(result)
+}
+
+fn in_session(session: Option<&ClientSession>, command: RaftCommand) -> RaftCommand {
+    match session {
+        Some(session) => RaftCommand::Session {
+            id: session.id,
+            seq: session.next_seq,
+            command: Box::new(command),
+        },
+        None => command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, merge_exec};
+    use bytes::Bytes;
+    use zakros_redis::resp::{self, Value};
+
+    #[test]
+    fn merge_replayed_integers() {
+        let results = vec![
+            Ok(Value::Integer(2)),
+            Ok(Value::Raw(Bytes::from_static(b":3\r\n"))),
+        ];
+        assert_eq!(merge(results), Ok(Value::Integer(5)));
+    }
+
+    #[test]
+    fn merge_forwarded_keys() {
+        let keys = |keys: &[&'static str]| {
+            Value::Array(
+                keys.iter()
+                    .map(|key| Ok(Bytes::from_static(key.as_bytes()).into()))
+                    .collect(),
+            )
+        };
+        let forwarded = resp::to_bytes(&Ok(keys(&["b", "c"])));
+        let results = vec![Ok(keys(&["a"])), Ok(Value::Raw(forwarded))];
+        assert_eq!(merge(results), Ok(keys(&["a", "b", "c"])));
+
+        let err = Bytes::from_static(b"-ERR error\r\n");
+        let results = vec![Ok(keys(&["a"])), Ok(Value::Raw(err.clone()))];
+        assert_eq!(merge(results), Ok(Value::Raw(err)));
+    }
+
+    #[test]
+    fn merge_exec_by_command() {
+        let local = Value::Array(vec![Ok(Value::ok()), Ok(Value::Integer(1))]);
+        let forwarded = resp::to_bytes(&Ok(Value::Array(vec![
+            Ok(Value::ok()),
+            Ok(Value::Integer(2)),
+        ])));
+        let results = vec![Ok(local), Ok(Value::Raw(forwarded))];
+        assert_eq!(
+            merge_exec(results),
+            Ok(Value::Array(vec![Ok(Value::ok()), Ok(Value::Integer(3))]))
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use super::{is_error, merge, CommandError};
+use crate::{connection::RedisConnection, session::ClientSession, store::RaftCommand};
+use bytes::Bytes;
+use futures::future::try_join_all;
+use zakros_redis::{resp::Value, BytesExt, RedisError, ResponseError};
+
+pub async fn client(conn: &mut RedisConnection, args: &[Bytes]) -> Result<Value, CommandError> {
+    let [subcommand, args @ ..] = args else {
+        return Err(RedisError::from(ResponseError::WrongArity).into());
+    };
+    match subcommand.to_ascii_uppercase().as_slice() {
+        b"HELP" => Ok(Value::Array(
+            [
+                "CLIENT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "SESSION REGISTER",
+                "    Register a new session in every Raft group, bind it to the connection, and",
+                "    return its id.",
+                "SESSION <session-id> <seq>",
+                "    Bind the session to the connection. The following writes are numbered",
+                "    from <seq>. A write retried with the same number is not applied twice.",
+                "HELP",
+                "    Print this help.",
+            ]
+            .iter()
+            .map(|s| Ok((*s).into()))
+            .collect(),
+        )),
+        b"SESSION" => {
+            if !conn.shared.config.raft_enabled {
+                return Err(RedisError::from(ResponseError::ClusterDisabled).into());
+            }
+            let session = match args {
+                [register] if register.eq_ignore_ascii_case(b"REGISTER") => {
+                    // The same id is registered in every group, so it is
+                    // chosen here rather than by the state machines. It
+                    // fits in a RESP integer.
+                    let id = rand::random::<u64>() >> 1;
+                    let results = conn
+                        .shared
+                        .groups
+                        .iter()
+                        .map(|group| group.write_on_leader(RaftCommand::RegisterSession(id)));
+                    let reply = merge(try_join_all(results).await?)?;
+                    if is_error(&reply) {
+                        return Ok(reply);
+                    }
+                    conn.session = Some(ClientSession { id, next_seq: 1 });
+                    return Ok((id as i64).into());
+                }
+                [id, seq] => {
+                    let id = id.to_u64()?;
+                    let next_seq = seq.to_u64()?;
+                    if next_seq == 0 {
+                        return Err(RedisError::from(ResponseError::ValueOutOfRange).into());
+                    }
+                    ClientSession { id, next_seq }
+                }
+                [_] => return Err(RedisError::from(ResponseError::SyntaxError).into()),
+                _ => return Err(RedisError::from(ResponseError::WrongArity).into()),
+            };
+            conn.session = Some(session);
+            Ok(Value::ok())
+        }
+        _ => Err(RedisError::from(ResponseError::UnknownSubcommand).into()),
+    }
+}
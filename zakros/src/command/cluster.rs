@@ -1,26 +1,51 @@
 use super::CommandError;
-use crate::connection::RedisConnection;
+use crate::{
+    connection::RedisConnection,
+    group::{ClusterCommand, Groups},
+};
+use bstr::ByteSlice;
 use bytes::Bytes;
+use futures::future::join_all;
 use std::net::SocketAddr;
 use zakros_raft::{NodeId, RaftError};
-use zakros_redis::{resp::Value, RedisError, RedisResult, ResponseError};
+use zakros_redis::{
+    cluster::{key_slot, NUM_SLOTS},
+    resp::Value,
+    BytesExt, RedisError, RedisResult, ResponseError,
+};
 
 pub async fn cluster(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, CommandError> {
-    let [subcommand, _args @ ..] = args else {
+    let [subcommand, args @ ..] = args else {
         return Err(RedisError::from(ResponseError::WrongArity).into());
     };
-    let Some(raft) = &conn.shared.raft else {
+    if !conn.shared.config.raft_enabled {
         return Err(RedisError::from(ResponseError::ClusterDisabled).into());
-    };
+    }
+    let groups = &conn.shared.groups;
     match subcommand.to_ascii_uppercase().as_slice() {
         b"HELP" => Ok(Value::Array(
             [
                 "CLUSTER <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "ADDNODE <node-id> <ip:port>",
+                "    Add a node to the cluster.",
+                "ADDLEARNER <node-id> <ip:port>",
+                "    Add a non-voting node to the cluster.",
+                "REMOVENODE <node-id>",
+                "    Remove a node from the cluster.",
+                "FAILOVER [TO <node-id>]",
+                "    Transfer leadership to the specified node, or to the most up-to-date node.",
+                "COUNTKEYSINSLOT <slot>",
+                "    Return the number of keys in <slot>.",
+                "GETKEYSINSLOT <slot> <count>",
+                "    Return key names stored by current node in a slot.",
+                "KEYSLOT <key>",
+                "    Return the hash slot for <key>.",
                 "MYID",
                 "    Return the node id.",
                 "SLOTS",
                 "    Return information about slots range mappings. Each range is made of:",
                 "    start, end, master and replicas IP addresses, ports and ids",
+                "The membership commands and FAILOVER act on every Raft group through its leader.",
                 "HELP",
                 "    Print this help.",
             ]
@@ -30,28 +55,138 @@ pub async fn cluster(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, Co
         )),
         b"MYID" => Ok(format_node_id(NodeId::from(conn.shared.config.node_id))),
         b"SLOTS" => {
-            const CLUSTER_SLOTS: i64 = 16384;
-            let leader_id = raft
-                .status()
-                .await?
-                .leader_id
-                .ok_or(RaftError::NotLeader { leader_id: None })?;
-            let leader_index = Into::<u64>::into(leader_id) as usize;
-            let addrs = &conn.shared.config.cluster_addrs;
-            let mut responses = vec![Ok(0.into()), Ok((CLUSTER_SLOTS - 1).into())];
-            responses.reserve(addrs.len());
-            responses.push(Ok(format_node(leader_id, addrs[leader_index])));
-            for (i, addr) in addrs.iter().enumerate() {
-                if i != leader_index {
-                    responses.push(Ok(format_node(NodeId::from(i as u64), *addr)));
+            let mut slots = Vec::with_capacity(groups.len());
+            for group in groups.iter() {
+                let raft = group.raft.as_ref().unwrap();
+                let status = raft.status().await?;
+                let leader_id = status
+                    .leader_id
+                    .ok_or(RaftError::NotLeader { leader_id: None })?;
+                let node_addrs = group.node_addrs();
+                let leader_addr = node_addrs
+                    .get(leader_id)
+                    .ok_or(RaftError::NotLeader { leader_id: None })?;
+                let mut responses = vec![
+                    Ok((group.slots.start as i64).into()),
+                    Ok((group.slots.end as i64 - 1).into()),
+                ];
+                responses.reserve(status.nodes.len() + status.learners.len());
+                responses.push(Ok(format_node(leader_id, leader_addr)));
+                for node_id in status.nodes.into_iter().chain(status.learners) {
+                    if node_id == leader_id {
+                        continue;
+                    }
+                    if let Some(addr) = node_addrs.get(node_id) {
+                        responses.push(Ok(format_node(node_id, addr)));
+                    }
                 }
+                slots.push(Ok(Value::Array(responses)));
             }
-            Ok(Value::Array(vec![Ok(Value::Array(responses))]))
+            Ok(Value::Array(slots))
+        }
+        b"KEYSLOT" => {
+            let [key] = args else {
+                return Err(RedisError::from(ResponseError::WrongArity).into());
+            };
+            Ok((key_slot(key) as i64).into())
+        }
+        b"COUNTKEYSINSLOT" => {
+            let [slot] = args else {
+                return Err(RedisError::from(ResponseError::WrongArity).into());
+            };
+            let slot = parse_slot(slot)?
+                .ok_or_else(|| RedisError::from(ResponseError::Other("Invalid slot")))?;
+            let count = groups.by_slot(slot).store.count_keys_in_slot(slot);
+            Ok((count as i64).into())
+        }
+        b"GETKEYSINSLOT" => {
+            let [slot, count] = args else {
+                return Err(RedisError::from(ResponseError::WrongArity).into());
+            };
+            let slot = parse_slot(slot)?;
+            let count = count.to_i64()?;
+            let (Some(slot), Ok(count)) = (slot, usize::try_from(count)) else {
+                return Err(RedisError::from(ResponseError::Other(
+                    "Invalid slot or number of keys",
+                ))
+                .into());
+            };
+            let keys = groups.by_slot(slot).store.keys_in_slot(slot, count);
+            Ok(Value::Array(
+                keys.into_iter().map(|key| Ok(key.into())).collect(),
+            ))
+        }
+        b"FAILOVER" => {
+            let target = match args {
+                [] => None,
+                [to, node_id] if to.eq_ignore_ascii_case(b"TO") => Some(parse_node_id(node_id)?),
+                _ => return Err(RedisError::from(ResponseError::SyntaxError).into()),
+            };
+            Ok(on_every_leader(groups, ClusterCommand::TransferLeadership(target)).await?)
+        }
+        b"ADDNODE" | b"ADDLEARNER" => {
+            let [node_id, addr] = args else {
+                return Err(RedisError::from(ResponseError::WrongArity).into());
+            };
+            let node_id = parse_node_id(node_id)?;
+            let addr = addr
+                .to_str()
+                .ok()
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(|| RedisError::from(ResponseError::Other("Invalid node address")))?;
+            let command = if subcommand.eq_ignore_ascii_case(b"ADDLEARNER") {
+                ClusterCommand::AddLearner(node_id, addr)
+            } else {
+                ClusterCommand::AddNode(node_id, addr)
+            };
+            Ok(on_every_leader(groups, command).await?)
+        }
+        b"REMOVENODE" => {
+            let [node_id] = args else {
+                return Err(RedisError::from(ResponseError::WrongArity).into());
+            };
+            let node_id = parse_node_id(node_id)?;
+            Ok(on_every_leader(groups, ClusterCommand::RemoveNode(node_id)).await?)
         }
         _ => Err(RedisError::from(ResponseError::UnknownSubcommand).into()),
     }
 }
 
+/// Runs the command on the leader of every group.
+///
+/// Fails with `CLUSTERDOWN` naming the slots of the groups that weren't
+/// changed, while the other groups keep the change.
+async fn on_every_leader(groups: &Groups, command: ClusterCommand) -> RedisResult {
+    let results = join_all(groups.iter().map(|group| group.cluster_on_leader(command))).await;
+    let errors: Vec<_> = results
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|err| match err {
+            RedisError::ClusterDown(message) => message,
+            err => err.to_string(),
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(RedisError::ClusterDown(errors.join("; ")));
+    }
+    Ok(Value::ok())
+}
+
+fn parse_node_id(bytes: &[u8]) -> Result<NodeId, RedisError> {
+    bytes
+        .to_str()
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(NodeId::from)
+        .ok_or_else(|| ResponseError::Other("Invalid node id").into())
+}
+
+/// Parses a slot, returning `None` if it is out of range.
+fn parse_slot(bytes: &[u8]) -> Result<Option<u16>, RedisError> {
+    let slot = bytes.to_i64()?;
+    Ok(u16::try_from(slot).ok().filter(|slot| *slot < NUM_SLOTS))
+}
+
 fn format_node_id(node_id: NodeId) -> Value {
     Bytes::from(format!("{:0>40x}", Into::<u64>::into(node_id)).into_bytes()).into()
 }
@@ -68,7 +203,7 @@ pub fn readonly(conn: &mut RedisConnection, args: &[Bytes]) -> RedisResult {
     if !args.is_empty() {
         return Err(ResponseError::WrongArity.into());
     }
-    if conn.shared.raft.is_none() {
+    if !conn.shared.config.raft_enabled {
         return Err(ResponseError::ClusterDisabled.into());
     }
     conn.is_readonly = true;
@@ -79,7 +214,7 @@ pub fn readwrite(conn: &mut RedisConnection, args: &[Bytes]) -> RedisResult {
     if !args.is_empty() {
         return Err(ResponseError::WrongArity.into());
     }
-    if conn.shared.raft.is_none() {
+    if !conn.shared.config.raft_enabled {
         return Err(ResponseError::ClusterDisabled.into());
     }
     conn.is_readonly = false;
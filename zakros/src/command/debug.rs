@@ -1,8 +1,11 @@
 use crate::connection::RedisConnection;
 use bstr::ByteSlice;
 use bytes::Bytes;
-use std::{collections::hash_map::Entry, io::Write};
-use zakros_redis::{lockable::RwLockable, resp::Value, BytesExt, RedisResult, ResponseError};
+use std::io::Write;
+use zakros_redis::{
+    cluster::key_slot, dictionary::Entry, lockable::RwLockable, resp::Value, BytesExt, RedisResult,
+    ResponseError,
+};
 
 pub fn debug(conn: &RedisConnection, args: &[Bytes]) -> RedisResult {
     let [subcommand, args @ ..] = args else {
@@ -50,11 +53,12 @@ pub fn debug(conn: &RedisConnection, args: &[Bytes]) -> RedisResult {
 
             // Redis doesn't propagate DEBUG POPULATE to replicas.
             // Likewise, we don't make it go through Raft.
-            let mut dict = conn.shared.store.write();
             for i in 0..count {
                 let mut key = prefix.to_vec();
                 write!(&mut key, ":{}", i).unwrap();
 
+                let slot = key_slot(&key);
+                let mut dict = conn.shared.groups.by_slot(slot).store.write();
                 let entry = dict.entry(key.into());
                 let Entry::Vacant(entry) = entry else {
                     continue;
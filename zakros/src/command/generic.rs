@@ -1,5 +1,7 @@
+use super::CommandError;
+use crate::connection::RedisConnection;
 use bytes::Bytes;
-use zakros_redis::{resp::Value, BytesExt, RedisResult, ResponseError};
+use zakros_redis::{resp::Value, BytesExt, RedisError, RedisResult, ResponseError};
 
 pub fn select(args: &[Bytes]) -> RedisResult {
     let [index] = args else {
@@ -13,7 +15,27 @@ pub fn select(args: &[Bytes]) -> RedisResult {
     }
 }
 
-pub fn shutdown(_: &[Bytes]) -> RedisResult {
-    // TODO: gracefully shutdown
-    std::process::exit(0)
+pub async fn shutdown(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, CommandError> {
+    let mut save = false;
+    let mut abort = false;
+    for arg in args {
+        match arg.to_ascii_uppercase().as_slice() {
+            b"NOSAVE" => save = false,
+            b"SAVE" => save = true,
+            b"ABORT" => abort = true,
+            _ => return Err(RedisError::from(ResponseError::SyntaxError).into()),
+        }
+    }
+    if abort {
+        if args.len() > 1 {
+            return Err(RedisError::from(ResponseError::SyntaxError).into());
+        }
+        conn.shared.shutdown.abort().await?;
+        return Ok(Value::ok());
+    }
+
+    conn.shared.shutdown.start(save).await?;
+
+    // Close the connection without replying
+    Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into())
 }
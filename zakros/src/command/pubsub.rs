@@ -38,7 +38,7 @@ pub async fn publish(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, Co
         return Err(RedisError::from(ResponseError::WrongArity).into());
     };
 
-    if let Some(raft) = &conn.shared.raft {
+    if let Some(raft) = &conn.shared.groups[0].raft {
         // Make sure we can reach majority of nodes
         raft.read().await?;
     }
@@ -48,11 +48,12 @@ pub async fn publish(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, Co
         payload: message.clone(),
     };
     let num_receivers = conn.shared.publisher.publish(message.clone());
-    for i in 0..conn.shared.config.cluster_addrs.len() as u64 {
-        if i != conn.shared.config.node_id {
-            let rpc_handler = conn.shared.rpc_client.clone();
+    let node_id = NodeId::from(conn.shared.config.node_id);
+    for dest in conn.shared.node_addrs().node_ids() {
+        if dest != node_id {
+            let rpc_handler = conn.shared.groups[0].rpc_client.clone();
             let message = message.clone();
-            tokio::spawn(async move { rpc_handler.publish(NodeId::from(i), message).await });
+            tokio::spawn(async move { rpc_handler.publish(dest, message).await });
         }
     }
     Ok((num_receivers as i64).into())
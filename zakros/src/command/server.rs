@@ -1,3 +1,4 @@
+use super::CommandError;
 use crate::{connection::RedisConnection, Shared};
 use bstr::ByteSlice;
 use bytes::Bytes;
@@ -5,14 +6,16 @@ use std::{
     io::Write,
     time::{Duration, SystemTime},
 };
-use zakros_redis::RedisResult;
+use zakros_raft::{Role, State, Status};
+use zakros_redis::resp::Value;
 
 const SERVER: u8 = 0x1;
 const CLIENTS: u8 = 0x2;
 const CLUSTER: u8 = 0x4;
+const RAFT: u8 = 0x8;
 const ALL: u8 = u8::MAX;
 
-pub fn info(conn: &RedisConnection, args: &[Bytes]) -> RedisResult {
+pub async fn info(conn: &RedisConnection, args: &[Bytes]) -> Result<Value, CommandError> {
     let mut sections;
     if args.is_empty() {
         sections = ALL;
@@ -23,15 +26,33 @@ pub fn info(conn: &RedisConnection, args: &[Bytes]) -> RedisResult {
                 b"server" => sections |= SERVER,
                 b"clients" => sections |= CLIENTS,
                 b"cluster" => sections |= CLUSTER,
+                b"raft" => sections |= RAFT,
                 b"default" | b"all" | b"everything" => sections |= ALL,
                 _ => (),
             }
         }
     }
-    Ok(generate_info_str(&conn.shared, sections).unwrap().into())
+    let mut raft_statuses = Vec::new();
+    if sections & RAFT != 0 {
+        for raft in conn
+            .shared
+            .groups
+            .iter()
+            .filter_map(|group| group.raft.as_ref())
+        {
+            raft_statuses.push(raft.status().await?);
+        }
+    }
+    Ok(generate_info_str(&conn.shared, &raft_statuses, sections)
+        .unwrap()
+        .into())
 }
 
-fn generate_info_str(shared: &Shared, sections: u8) -> std::io::Result<Bytes> {
+fn generate_info_str(
+    shared: &Shared,
+    raft_statuses: &[Status],
+    sections: u8,
+) -> std::io::Result<Bytes> {
     let mut out = Vec::new();
     let mut is_first = true;
     if sections & SERVER != 0 {
@@ -58,6 +79,7 @@ fn generate_info_str(shared: &Shared, sections: u8) -> std::io::Result<Bytes> {
         if !is_first {
             out.write_all(b"\r\n")?;
         }
+        is_first = false;
         out.write_all(b"# Clients\r\n")?;
         write!(
             out,
@@ -70,8 +92,88 @@ fn generate_info_str(shared: &Shared, sections: u8) -> std::io::Result<Bytes> {
         if !is_first {
             out.write_all(b"\r\n")?;
         }
+        is_first = false;
         out.write_all(b"# Cluster\r\n")?;
-        write!(out, "cluster_enabled:{}\r\n", shared.raft.is_some() as u8)?;
+        write!(
+            out,
+            "cluster_enabled:{}\r\n",
+            shared.config.raft_enabled as u8
+        )?;
+    }
+    // The fields describe the first Raft group, followed by a summary of
+    // every group
+    if let Some(status) = raft_statuses.first() {
+        if !is_first {
+            out.write_all(b"\r\n")?;
+        }
+        out.write_all(b"# Raft\r\n")?;
+        write!(out, "raft_state:{}\r\n", state_name(status.state))?;
+        write!(out, "raft_node_id:{}\r\n", u64::from(status.node_id))?;
+        match status.leader_id {
+            Some(leader_id) => write!(out, "raft_leader_id:{}\r\n", u64::from(leader_id))?,
+            None => out.write_all(b"raft_leader_id:\r\n")?,
+        }
+        write!(out, "raft_current_term:{}\r\n", status.term)?;
+        write!(out, "raft_commit_index:{}\r\n", status.commit_index)?;
+        write!(
+            out,
+            "raft_last_applied_index:{}\r\n",
+            status.last_applied_index
+        )?;
+        write!(out, "raft_last_log_index:{}\r\n", status.last_log_index)?;
+        write!(out, "raft_log_entries:{}\r\n", status.num_log_entries)?;
+        write!(out, "raft_voters:{}\r\n", status.nodes.len())?;
+        write!(out, "raft_learners:{}\r\n", status.learners.len())?;
+        write!(out, "raft_peers:{}\r\n", status.peers.len())?;
+        for (i, (node_id, peer)) in status.peers.iter().enumerate() {
+            let role = match peer.role {
+                Role::Voter => "voter",
+                Role::Learner => "learner",
+            };
+            write!(
+                out,
+                "raft_peer{}:id={},role={},next_index={},match_index={},",
+                i,
+                u64::from(*node_id),
+                role,
+                peer.next_index,
+                peer.match_index
+            )?;
+            match peer.since_last_ack {
+                Some(since_last_ack) => write!(out, "last_ack_ms={},", since_last_ack.as_millis())?,
+                None => out.write_all(b"last_ack_ms=-1,")?,
+            }
+            write!(out, "failed_rpcs={}\r\n", peer.num_failed_rpcs)?;
+        }
+        write!(out, "raft_groups:{}\r\n", raft_statuses.len())?;
+        for (i, (group, status)) in shared.groups.iter().zip(raft_statuses).enumerate() {
+            write!(
+                out,
+                "raft_group{}:slots={}-{},state={},",
+                i,
+                group.slots.start,
+                group.slots.end - 1,
+                state_name(status.state)
+            )?;
+            match status.leader_id {
+                Some(leader_id) => write!(out, "leader_id={},", u64::from(leader_id))?,
+                None => out.write_all(b"leader_id=,")?,
+            }
+            write!(
+                out,
+                "term={},commit_index={},last_applied_index={}\r\n",
+                status.term, status.commit_index, status.last_applied_index
+            )?;
+        }
     }
     Ok(out.into())
 }
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::Follower => "follower",
+        State::PreCandidate => "precandidate",
+        State::Candidate => "candidate",
+        State::Leader => "leader",
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     num::NonZeroUsize,
     path::PathBuf,
 };
+use zakros_redis::cluster::NUM_SLOTS;
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
@@ -30,11 +31,29 @@ pub struct Config {
     #[serde(default = "defaults::cluster_addrs")]
     pub cluster_addrs: Vec<SocketAddr>,
 
+    #[serde(default = "defaults::learner_addrs")]
+    pub learner_addrs: Vec<SocketAddr>,
+
     #[serde(default = "defaults::raft_enabled")]
     pub raft_enabled: bool,
 
     #[serde(default = "defaults::raft_storage")]
     pub raft_storage: RaftStorageKind,
+
+    #[serde(default = "defaults::raft_log_cache_size")]
+    pub raft_log_cache_size: usize,
+
+    #[serde(default = "defaults::raft_lease_read")]
+    pub raft_lease_read: bool,
+
+    #[serde(default = "defaults::raft_max_clock_drift")]
+    pub raft_max_clock_drift: u64,
+
+    #[serde(default = "defaults::raft_follower_read")]
+    pub raft_follower_read: bool,
+
+    #[serde(default = "defaults::raft_groups")]
+    pub raft_groups: NonZeroUsize,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize)]
@@ -80,6 +99,10 @@ mod defaults {
         Vec::new()
     }
 
+    pub const fn learner_addrs() -> Vec<SocketAddr> {
+        Vec::new()
+    }
+
     pub const fn raft_enabled() -> bool {
         true
     }
@@ -87,6 +110,26 @@ mod defaults {
     pub const fn raft_storage() -> RaftStorageKind {
         RaftStorageKind::Disk
     }
+
+    pub const fn raft_log_cache_size() -> usize {
+        8192
+    }
+
+    pub const fn raft_lease_read() -> bool {
+        false
+    }
+
+    pub const fn raft_max_clock_drift() -> u64 {
+        100
+    }
+
+    pub const fn raft_follower_read() -> bool {
+        false
+    }
+
+    pub const fn raft_groups() -> NonZeroUsize {
+        NonZeroUsize::MIN
+    }
 }
 
 impl Config {
@@ -115,6 +158,9 @@ impl Config {
         }
 
         let mut config: Self = zakros_redis::config::from_bytes(&bytes)?;
+        if config.raft_groups.get() > NUM_SLOTS as usize {
+            anyhow::bail!("raft-groups must be at most {}", NUM_SLOTS);
+        }
         if config.cluster_addrs.is_empty() {
             config.cluster_addrs.push((config.bind, config.port).into());
         }
@@ -1,5 +1,6 @@
 use crate::{
     command::{self, CommandError},
+    session::ClientSession,
     Shared,
 };
 use bstr::ByteSlice;
@@ -32,6 +33,7 @@ pub struct RedisConnection {
     pub framed: Framed<TcpStream, RespCodec>,
     pub is_readonly: bool,
     pub subscriber: Subscriber,
+    pub session: Option<ClientSession>,
     txn: Transaction,
 }
 
@@ -43,6 +45,7 @@ impl RedisConnection {
             framed: Framed::new(conn, RespCodec::default()),
             is_readonly: false,
             subscriber,
+            session: None,
             txn: Transaction::Inactive,
         }
     }
@@ -77,19 +80,65 @@ impl RedisConnection {
                     }
                     RaftError::NotLeader {
                         leader_id: Some(leader_id),
-                    } => {
-                        let addr = &self.shared.config.cluster_addrs
-                            [Into::<u64>::into(leader_id) as usize];
+                    } => match self.shared.node_addrs().get(leader_id) {
+                        Some(addr) => {
+                            self.framed
+                                .send(Err(RedisError::Moved { slot: 0, addr }))
+                                .await?
+                        }
+                        None => {
+                            self.framed
+                                .send(Err(RedisError::ClusterDown(
+                                    "Unknown leader address".to_owned(),
+                                )))
+                                .await?
+                        }
+                    },
+                    RaftError::MembershipChangeInProgress => {
                         self.framed
-                            .send(Err(RedisError::Moved {
-                                slot: 0,
-                                addr: *addr,
-                            }))
+                            .send(Err(ResponseError::Other(
+                                "another membership change is in progress",
+                            )
+                            .into()))
+                            .await?
+                    }
+                    RaftError::EmptyMembership => {
+                        self.framed
+                            .send(Err(ResponseError::Other(
+                                "cannot remove the last node of the cluster",
+                            )
+                            .into()))
+                            .await?
+                    }
+                    RaftError::NoTransferTarget => {
+                        self.framed
+                            .send(Err(ResponseError::Other(
+                                "no node to transfer leadership to",
+                            )
+                            .into()))
+                            .await?
+                    }
+                    RaftError::LeadershipTransferTimeout => {
+                        self.framed
+                            .send(Err(
+                                ResponseError::Other("leadership transfer timed out").into()
+                            ))
+                            .await?
+                    }
+                    RaftError::Storage(err) => {
+                        self.framed
+                            .send(Err(RedisError::Misconf(format!(
+                                "Errors accessing the Raft log. Commands are disabled \
+                                 until the node is restarted: {err}"
+                            ))))
                             .await?
                     }
                     RaftError::Shutdown => {
-                        // TODO: gracefully shut down server
-                        panic!("Raft server is shut down")
+                        self.framed
+                            .send(Err(RedisError::ClusterDown(
+                                "The node is shutting down".to_owned(),
+                            )))
+                            .await?
                     }
                 },
                 Err(CommandError::SubscriberRecv(SubscriberRecvError::Lagged)) => return Ok(()),
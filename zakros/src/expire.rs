@@ -0,0 +1,53 @@
+//! Active expiration of keys.
+//!
+//! Reads treat expired keys as nonexistent right away, but the keys stay in
+//! memory until they are removed. The leader periodically looks for expired
+//! keys and removes them through Raft, so that every node removes the same
+//! keys at the same point in the log.
+
+use crate::store::{RaftCommand, Store};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use zakros_raft::{Raft, State};
+
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// maximum number of keys removed at once
+const MAX_KEYS_PER_BATCH: usize = 1024;
+
+/// Removes expired keys until the Raft server shuts down.
+pub async fn run(store: Store, raft: Option<Raft<RaftCommand>>) {
+    let mut interval = tokio::time::interval(INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Some(raft) = &raft {
+            match raft.status().await {
+                Ok(status) if status.state == State::Leader => (),
+                Ok(_) => continue,
+                Err(_) => return,
+            }
+        }
+        loop {
+            let keys = store.expired_keys(MAX_KEYS_PER_BATCH);
+            if keys.is_empty() {
+                break;
+            }
+            let is_full = keys.len() == MAX_KEYS_PER_BATCH;
+            match &raft {
+                Some(raft) => {
+                    if let Err(err) = raft.write(RaftCommand::Expire(keys)).await {
+                        tracing::debug!("failed to remove expired keys: {}", err);
+                        break;
+                    }
+                }
+                None => {
+                    store.remove_expired(&keys);
+                }
+            }
+            if !is_full {
+                break;
+            }
+        }
+    }
+}
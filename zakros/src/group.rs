@@ -0,0 +1,306 @@
+//! Raft groups.
+//!
+//! The hash slots are divided evenly among the Raft groups. Each group
+//! replicates the keys in its slots with its own log and state machine, and
+//! elects its own leader, so the leaders of the groups can be spread over
+//! the nodes to scale writes beyond a single leader. Every node is a member
+//! of every group.
+
+use crate::{
+    command::CommandError,
+    rpc::RpcClient,
+    store::{NodeAddrs, RaftCommand, Store},
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, ops::Range, path::Path, sync::Arc};
+use zakros_raft::{NodeId, Raft, RaftError, RaftResult, State};
+use zakros_redis::{
+    cluster::NUM_SLOTS, command::ReadCommand, resp::Value, RedisError, RedisResult,
+};
+
+pub struct Group {
+    /// hash slots owned by the group
+    pub slots: Range<u16>,
+
+    pub store: Store,
+    pub raft: Option<Raft<RaftCommand>>,
+    pub rpc_client: Arc<RpcClient>,
+}
+
+impl Group {
+    pub fn node_addrs(&self) -> &NodeAddrs {
+        self.store.node_addrs()
+    }
+
+    /// Turns `NotLeader` into a redirection of the slot to the leader of
+    /// the group.
+    pub fn redirect(&self, err: RaftError, slot: u16) -> CommandError {
+        if let RaftError::NotLeader {
+            leader_id: Some(leader_id),
+        } = err
+        {
+            if let Some(addr) = self.node_addrs().get(leader_id) {
+                return RedisError::Moved { slot, addr }.into();
+            }
+        }
+        err.into()
+    }
+
+    /// Replicates the write through the leader of the group, forwarding it
+    /// over RPC if another node is the leader.
+    ///
+    /// Fails with `CLUSTERDOWN` if the leader is unknown or unreachable.
+    pub async fn write_on_leader(&self, command: RaftCommand) -> Result<RedisResult, RedisError> {
+        let raft = self.raft();
+        match self.remote_leader().await? {
+            None => raft
+                .write(command)
+                .await
+                .map_err(|err| self.unavailable(err)),
+            Some(leader_id) => self
+                .rpc_client
+                .write(leader_id, command)
+                .await
+                .map(|reply| Ok(Value::Raw(reply)))
+                .map_err(|err| self.unavailable(err)),
+        }
+    }
+
+    /// Runs the read on the leader of the group, forwarding it over RPC if
+    /// another node is the leader.
+    ///
+    /// Fails with `CLUSTERDOWN` if the leader is unknown or unreachable.
+    pub async fn read_on_leader(
+        &self,
+        command: ReadCommand,
+        args: &[Bytes],
+    ) -> Result<RedisResult, RedisError> {
+        match self.remote_leader().await? {
+            None => {
+                self.raft()
+                    .read()
+                    .await
+                    .map_err(|err| self.unavailable(err))?;
+                Ok(command.call(&self.store, args))
+            }
+            Some(leader_id) => self
+                .rpc_client
+                .read(leader_id, command, args.to_vec())
+                .await
+                .map(|reply| Ok(Value::Raw(reply)))
+                .map_err(|err| self.unavailable(err)),
+        }
+    }
+
+    /// Runs the CLUSTER command as the leader of the group, forwarding it
+    /// over RPC if another node is the leader.
+    ///
+    /// Fails with `CLUSTERDOWN` if the leader is unknown or unreachable, or
+    /// the command fails.
+    pub async fn cluster_on_leader(&self, command: ClusterCommand) -> Result<(), RedisError> {
+        let not_changed = |err: &dyn std::fmt::Display| {
+            RedisError::ClusterDown(format!(
+                "Slots {}-{} were not changed: {}",
+                self.slots.start,
+                self.slots.end - 1,
+                err
+            ))
+        };
+        match self.remote_leader().await? {
+            None => command
+                .run(self.raft())
+                .await
+                .map_err(|err| not_changed(&err)),
+            Some(leader_id) => self
+                .rpc_client
+                .cluster(leader_id, command)
+                .await
+                .map_err(|err| not_changed(&err)),
+        }
+    }
+
+    /// Returns the leader of the group, or `None` if this node is the leader.
+    async fn remote_leader(&self) -> Result<Option<NodeId>, RedisError> {
+        let status = self
+            .raft()
+            .status()
+            .await
+            .map_err(|err| self.unavailable(err))?;
+        if status.state == State::Leader {
+            return Ok(None);
+        }
+        match status.leader_id {
+            Some(leader_id) => Ok(Some(leader_id)),
+            None => Err(self.unavailable("No leader")),
+        }
+    }
+
+    fn raft(&self) -> &Raft<RaftCommand> {
+        self.raft
+            .as_ref()
+            .expect("commands are forwarded only when Raft is enabled")
+    }
+
+    fn unavailable(&self, err: impl std::fmt::Display) -> RedisError {
+        RedisError::ClusterDown(format!(
+            "Slots {}-{} are unavailable: {}",
+            self.slots.start,
+            self.slots.end - 1,
+            err
+        ))
+    }
+}
+
+/// CLUSTER command that changes a Raft group, which only its leader can run
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ClusterCommand {
+    AddNode(NodeId, SocketAddr),
+    AddLearner(NodeId, SocketAddr),
+    RemoveNode(NodeId),
+    TransferLeadership(Option<NodeId>),
+}
+
+impl ClusterCommand {
+    /// Runs the command, which fails unless this node is the leader of the
+    /// group.
+    pub async fn run(self, raft: &Raft<RaftCommand>) -> RaftResult<()> {
+        match self {
+            Self::AddNode(node_id, addr) | Self::AddLearner(node_id, addr) => {
+                // Make the address known to all nodes before the node joins
+                raft.write(RaftCommand::SetNodeAddr(node_id, Some(addr)))
+                    .await?
+                    .expect("setting a node address never fails");
+                if let Self::AddLearner(..) = self {
+                    raft.add_learner(node_id).await
+                } else {
+                    raft.add_node(node_id).await
+                }
+            }
+            Self::RemoveNode(node_id) => {
+                let leader_id = raft.status().await?.node_id;
+                raft.remove_node(node_id).await?;
+
+                // A leader that removed itself is no longer the leader
+                if node_id != leader_id {
+                    raft.write(RaftCommand::SetNodeAddr(node_id, None))
+                        .await?
+                        .expect("setting a node address never fails");
+                }
+                Ok(())
+            }
+            Self::TransferLeadership(target) => raft.transfer_leadership(target).await,
+        }
+    }
+}
+
+pub struct Groups(Vec<Group>);
+
+impl Groups {
+    pub fn new(groups: Vec<Group>) -> Self {
+        assert!(!groups.is_empty());
+        Self(groups)
+    }
+
+    /// Returns the group owning the slot.
+    pub fn by_slot(&self, slot: u16) -> &Group {
+        let i = self.0.partition_point(|group| group.slots.end <= slot);
+        &self.0[i]
+    }
+}
+
+impl std::ops::Deref for Groups {
+    type Target = [Group];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Checks that the Raft logs of the node in `dir` were written with the same
+/// slots in each group, and records the slots if the node has no logs yet.
+///
+/// The keys in a log are those of the slots its group owned, so a log can't
+/// be reused after the slots were divided into another number of groups.
+pub fn check_slot_layout(dir: &Path, node_id: u64, num_groups: usize) -> anyhow::Result<()> {
+    let layout = |num_groups| -> String {
+        slot_ranges(num_groups)
+            .map(|slots| format!("{} {}\n", slots.start, slots.end))
+            .collect()
+    };
+    let path = dir.join(format!("node-{}-slots", node_id));
+    let persisted = match std::fs::read_to_string(&path) {
+        Ok(persisted) => persisted,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            // Logs written before the layout was recorded. The first group
+            // has the directory used before the keyspace was split.
+            if dir.join(format!("node-{}", node_id)).exists() {
+                let num_groups = (1..)
+                    .take_while(|i| dir.join(format!("node-{}-group-{}", node_id, i)).exists())
+                    .count();
+                layout(num_groups + 1)
+            } else {
+                std::fs::create_dir_all(dir)?;
+                std::fs::write(&path, layout(num_groups))?;
+                return Ok(());
+            }
+        }
+        Err(err) => return Err(err.into()),
+    };
+    anyhow::ensure!(
+        persisted == layout(num_groups),
+        "the Raft logs in {} were written with {} groups, but raft-groups is {}",
+        dir.display(),
+        persisted.lines().count(),
+        num_groups
+    );
+    Ok(())
+}
+
+/// Divides the hash slots evenly into `num_groups` ranges.
+pub fn slot_ranges(num_groups: usize) -> impl Iterator<Item = Range<u16>> {
+    let boundary = move |i: usize| (i * NUM_SLOTS as usize / num_groups) as u16;
+    (0..num_groups).map(move |i| boundary(i)..boundary(i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_slot_layout, slot_ranges};
+    use zakros_redis::cluster::NUM_SLOTS;
+
+    #[test]
+    fn slot_ranges_cover_all_slots() {
+        for num_groups in [1, 3, 7, NUM_SLOTS as usize] {
+            let ranges: Vec<_> = slot_ranges(num_groups).collect();
+            assert_eq!(ranges.len(), num_groups);
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, NUM_SLOTS);
+            for range in &ranges {
+                assert!(!range.is_empty());
+            }
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+        }
+    }
+
+    #[test]
+    fn slot_layout_must_not_change() {
+        let dir = std::env::temp_dir().join(format!("zakros-slot-layout-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        check_slot_layout(&dir, 0, 3).unwrap();
+        check_slot_layout(&dir, 0, 3).unwrap();
+        assert!(check_slot_layout(&dir, 0, 2).is_err());
+
+        // Logs written before the layout was recorded
+        std::fs::create_dir_all(dir.join("node-1")).unwrap();
+        assert!(check_slot_layout(&dir, 1, 3).is_err());
+        check_slot_layout(&dir, 1, 1).unwrap();
+        std::fs::create_dir_all(dir.join("node-1-group-1")).unwrap();
+        assert!(check_slot_layout(&dir, 1, 1).is_err());
+        check_slot_layout(&dir, 1, 2).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
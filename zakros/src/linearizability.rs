@@ -0,0 +1,527 @@
+//! Linearizability test of zakros.
+//!
+//! Clients run concurrent operations against a cluster while the cluster is
+//! partitioned and its nodes are crashed and restarted. The recorded
+//! histories are then checked against sequential models of a register and a
+//! counter, which covers both reads served after `Raft::read` and writes
+//! replicated as `RaftCommand`s.
+//!
+//! The same cluster also checks that a client session and membership changes
+//! span the Raft groups, and that a node whose storage fails answers with
+//! MISCONF.
+
+mod checker;
+mod cluster;
+
+use checker::{is_linearizable, Counter, CounterInput, Model, Operation, Register, RegisterInput};
+use cluster::Cluster;
+use futures::{future::BoxFuture, FutureExt};
+use parking_lot::Mutex;
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+const NUM_NODES: usize = 3;
+const NUM_CLIENTS_PER_KEY: usize = 4;
+const NUM_FAULTS: usize = 10;
+const FAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time for the cluster to elect a leader after the faults are healed
+const RECOVERY_TIME: Duration = Duration::from_secs(4);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[test]
+fn register_and_counter_are_linearizable() {
+    let dir = std::env::temp_dir().join(format!("zakros-linearizability-{}", std::process::id()));
+    let mut cluster = Cluster::new(dir, NUM_NODES, 1).unwrap();
+    let addrs = cluster.addrs();
+
+    let clients = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let registers = History::default();
+    let counters = History::default();
+    let next_value = Arc::new(AtomicU64::new(0));
+    let is_done = Arc::new(Mutex::new(false));
+    let mut tasks = Vec::new();
+    for _ in 0..NUM_CLIENTS_PER_KEY {
+        tasks.push(clients.spawn(run_register_client(
+            addrs.clone(),
+            registers.clone(),
+            next_value.clone(),
+            is_done.clone(),
+        )));
+        tasks.push(clients.spawn(run_counter_client(
+            addrs.clone(),
+            counters.clone(),
+            is_done.clone(),
+        )));
+    }
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..NUM_FAULTS {
+        std::thread::sleep(FAULT_INTERVAL);
+        match rng.gen_range(0..4) {
+            0 => {
+                let mut node_ids: Vec<_> = (0..NUM_NODES).collect();
+                node_ids.shuffle(&mut rng);
+                let len = rng.gen_range(1..NUM_NODES);
+                cluster.partition(node_ids[..len].iter().copied().collect());
+            }
+            1 => cluster.heal(),
+            2 => {
+                // Crash at most a minority of the nodes so that the cluster
+                // can make progress
+                let num_crashed = (0..NUM_NODES).filter(|i| !cluster.is_running(*i)).count();
+                if num_crashed < NUM_NODES / 2 {
+                    cluster.crash(rng.gen_range(0..NUM_NODES));
+                }
+            }
+            3 => restart_crashed(&mut cluster),
+            _ => unreachable!(),
+        }
+    }
+    cluster.heal();
+    restart_crashed(&mut cluster);
+    std::thread::sleep(RECOVERY_TIME);
+
+    *is_done.lock() = true;
+    clients.block_on(async {
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    check::<Register>("register", &registers);
+    check::<Counter>("counter", &counters);
+}
+
+#[test]
+fn client_session_writes_to_two_groups() {
+    let dir = std::env::temp_dir().join(format!("zakros-session-{}", std::process::id()));
+    let cluster = Cluster::new(dir, NUM_NODES, 2).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut client = Client::new(cluster.addrs());
+        let id = loop {
+            match client.call(&["CLIENT", "SESSION", "REGISTER"]).await {
+                Some(Reply::Integer(id)) => break id.to_string(),
+                _ => tokio::time::sleep(RETRY_INTERVAL).await,
+            }
+        };
+
+        // The slots of "a" (15495) and "b" (3300) are in different groups.
+        // Each write is sent twice to check that the retry is not applied.
+        for (seq, key) in ["a", "a", "b", "b"].into_iter().enumerate() {
+            let seq = (seq / 2 + 1).to_string();
+            let write = &["INCR", key];
+            let value = timeout(
+                Duration::from_secs(10),
+                client.session_call(&id, &seq, write),
+            )
+            .await
+            .expect("session write was not accepted");
+            assert_eq!(value, 1);
+        }
+    });
+}
+
+#[test]
+fn membership_change_reaches_every_group() {
+    let dir = std::env::temp_dir().join(format!("zakros-membership-{}", std::process::id()));
+    let cluster = Cluster::new(dir, NUM_NODES, 2).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // A node that isn't the leader of some group has to forward the
+        // change to the group's leader
+        let mut client = 'found: loop {
+            for addr in cluster.addrs() {
+                let mut client = Client::new(vec![addr]);
+                let Some(Reply::Bulk(Some(id))) = client.call(&["CLUSTER", "MYID"]).await else {
+                    continue;
+                };
+                let Some(groups) = group_members(&mut client).await else {
+                    continue;
+                };
+                if groups.iter().any(|members| members.first() != Some(&id)) {
+                    break 'found client;
+                }
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        };
+
+        let learner = NUM_NODES.to_string();
+        let learner_id = format!("{:0>40x}", NUM_NODES);
+        for (command, is_member) in [
+            (
+                &["CLUSTER", "ADDLEARNER", &learner, "127.0.0.1:1"][..],
+                true,
+            ),
+            (&["CLUSTER", "REMOVENODE", &learner][..], false),
+        ] {
+            timeout(Duration::from_secs(10), async {
+                while !matches!(client.call(command).await, Some(Reply::Status)) {
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            })
+            .await
+            .expect("membership change was not accepted");
+
+            // The node learns the membership of the groups it follows once
+            // the leaders tell it the change is committed
+            timeout(Duration::from_secs(10), async {
+                loop {
+                    if let Some(groups) = group_members(&mut client).await {
+                        if groups
+                            .iter()
+                            .all(|members| members.contains(&learner_id) == is_member)
+                        {
+                            break;
+                        }
+                    }
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            })
+            .await
+            .expect("membership change did not reach every group");
+        }
+    });
+}
+
+#[test]
+fn storage_failure_is_reported_as_misconf() {
+    let dir = std::env::temp_dir().join(format!("zakros-misconf-{}", std::process::id()));
+    let cluster = Cluster::new(dir.clone(), NUM_NODES, 1).unwrap();
+    let addrs = cluster.addrs();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let leader = timeout(Duration::from_secs(10), async {
+            loop {
+                for (i, addr) in addrs.iter().enumerate() {
+                    let mut client = Client::new(vec![*addr]);
+                    if let Some(Reply::Status) = client.call(&["SET", "k", "v"]).await {
+                        return i;
+                    }
+                }
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+        })
+        .await
+        .expect("no leader was elected");
+
+        // The log of the group is still open, but the term can't be
+        // persisted once the directory is gone, so the node fails as soon
+        // as it hands leadership over
+        std::fs::remove_dir_all(dir.join(format!("node-{leader}"))).unwrap();
+        let mut client = Client::new(vec![addrs[leader]]);
+        let _ = client.call(&["CLUSTER", "FAILOVER"]).await;
+
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let mut client = Client::new(vec![addrs[leader]]);
+                if let Ok(Reply::Error(err)) = client.try_call(&["GET", "k"]).await {
+                    if err.starts_with("MISCONF ") {
+                        break;
+                    }
+                }
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+        })
+        .await
+        .expect("storage failure was not reported as MISCONF");
+    });
+}
+
+/// Returns the ids of the members of each group, starting with the leader,
+/// from CLUSTER SLOTS.
+async fn group_members(client: &mut Client) -> Option<Vec<Vec<String>>> {
+    let Some(Reply::Array(groups)) = client.call(&["CLUSTER", "SLOTS"]).await else {
+        return None;
+    };
+    let node_id = |node| match node {
+        Reply::Array(node) => match node.into_iter().nth(2) {
+            Some(Reply::Bulk(Some(id))) => Some(id),
+            _ => None,
+        },
+        _ => None,
+    };
+    groups
+        .into_iter()
+        .map(|group| match group {
+            Reply::Array(group) => group.into_iter().skip(2).map(node_id).collect(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn restart_crashed(cluster: &mut Cluster) {
+    for node_id in 0..NUM_NODES {
+        if !cluster.is_running(node_id) {
+            cluster.start(node_id).unwrap();
+        }
+    }
+}
+
+fn check<M: Model>(name: &str, history: &History<M::Input, M::Output>) {
+    let history = history.lock();
+    let num_completed = history.iter().filter(|op| op.returned.is_some()).count();
+    assert!(num_completed > 0, "no {name} operation completed");
+    if !is_linearizable::<M>(&history) {
+        let path = std::env::temp_dir().join(format!("zakros-linearizability-{name}.txt"));
+        std::fs::write(&path, format!("{:#?}", *history)).unwrap();
+        panic!(
+            "{name} history is not linearizable. The history was written to {}",
+            path.display()
+        );
+    }
+}
+
+type History<I, O> = Arc<Mutex<Vec<Operation<I, O>>>>;
+
+async fn run_register_client(
+    addrs: Vec<SocketAddr>,
+    history: History<RegisterInput, Option<u64>>,
+    next_value: Arc<AtomicU64>,
+    is_done: Arc<Mutex<bool>>,
+) {
+    let mut client = Client::new(addrs);
+    while !*is_done.lock() {
+        if rand::thread_rng().gen_bool(0.5) {
+            if !client.find_leader().await {
+                continue;
+            }
+            let value = next_value.fetch_add(1, Ordering::Relaxed);
+            let invoked_at = Instant::now();
+            let returned = match client.call(&["SET", "register", &value.to_string()]).await {
+                Some(Reply::Status) => Some((Instant::now(), None)),
+                _ => None,
+            };
+            history.lock().push(Operation {
+                input: RegisterInput::Write(value),
+                invoked_at,
+                returned,
+            });
+        } else {
+            let invoked_at = Instant::now();
+            let value = match client.call(&["GET", "register"]).await {
+                Some(Reply::Bulk(value)) => value.map(|value| value.parse().unwrap()),
+                // Failed reads have no effect
+                _ => continue,
+            };
+            history.lock().push(Operation {
+                input: RegisterInput::Read,
+                invoked_at,
+                returned: Some((Instant::now(), value)),
+            });
+        }
+    }
+}
+
+async fn run_counter_client(
+    addrs: Vec<SocketAddr>,
+    history: History<CounterInput, i64>,
+    is_done: Arc<Mutex<bool>>,
+) {
+    let mut client = Client::new(addrs);
+    while !*is_done.lock() {
+        if rand::thread_rng().gen_bool(0.5) {
+            if !client.find_leader().await {
+                continue;
+            }
+            let invoked_at = Instant::now();
+            let returned = match client.call(&["INCR", "counter"]).await {
+                Some(Reply::Integer(value)) => Some((Instant::now(), value)),
+                _ => None,
+            };
+            history.lock().push(Operation {
+                input: CounterInput::Increment,
+                invoked_at,
+                returned,
+            });
+        } else {
+            let invoked_at = Instant::now();
+            let value = match client.call(&["GET", "counter"]).await {
+                Some(Reply::Bulk(value)) => value.map_or(0, |value| value.parse().unwrap()),
+                _ => continue,
+            };
+            history.lock().push(Operation {
+                input: CounterInput::Read,
+                invoked_at,
+                returned: Some((Instant::now(), value)),
+            });
+        }
+    }
+}
+
+enum Reply {
+    Status,
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+/// Minimal RESP client that reconnects to the leader, or to a random node if
+/// the leader is unknown, after errors.
+struct Client {
+    addrs: Vec<SocketAddr>,
+    conn: Option<BufReader<TcpStream>>,
+
+    /// node to connect to next
+    redirect_addr: Option<SocketAddr>,
+
+    /// whether the connected node has served a request as the leader
+    is_leader: bool,
+}
+
+impl Client {
+    fn new(addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            addrs,
+            conn: None,
+            redirect_addr: None,
+            is_leader: false,
+        }
+    }
+
+    /// Makes sure that the client is connected to the leader with a read,
+    /// which has no effect even if it fails.
+    ///
+    /// Writes sent to other nodes are rejected, but the client can't tell
+    /// the rejections from the failures of writes that may have been
+    /// committed. Finding the leader first keeps the number of operations
+    /// with unknown results small so that the histories can be checked in
+    /// a reasonable time.
+    async fn find_leader(&mut self) -> bool {
+        if !self.is_leader && self.call(&["GET", "leader-probe"]).await.is_none() {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+        self.is_leader
+    }
+
+    /// Sends the write numbered `seq` in the session `id`, binding the
+    /// session to the connection again after each redirection, until it
+    /// returns an integer.
+    async fn session_call(&mut self, id: &str, seq: &str, args: &[&str]) -> i64 {
+        loop {
+            if let Some(Reply::Status) = self.call(&["CLIENT", "SESSION", id, seq]).await {
+                if let Some(Reply::Integer(value)) = self.call(args).await {
+                    return value;
+                }
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Returns `None` if the request failed or its result is unknown.
+    async fn call(&mut self, args: &[&str]) -> Option<Reply> {
+        let result = timeout(REQUEST_TIMEOUT, self.try_call(args)).await;
+        match result {
+            Ok(Ok(Reply::Error(err))) => {
+                // MOVED <slot> <addr>
+                self.redirect_addr = err
+                    .strip_prefix("MOVED ")
+                    .and_then(|moved| moved.split_once(' '))
+                    .and_then(|(_, addr)| addr.parse().ok());
+                self.conn = None;
+                self.is_leader = false;
+                None
+            }
+            Ok(Err(_)) | Err(_) => {
+                // The node may be unreachable
+                self.conn = None;
+                self.is_leader = false;
+                None
+            }
+            Ok(Ok(reply)) => {
+                self.is_leader = true;
+                Some(reply)
+            }
+        }
+    }
+
+    async fn try_call(&mut self, args: &[&str]) -> std::io::Result<Reply> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let addr = match self.redirect_addr.take() {
+                    Some(addr) => addr,
+                    None => *self.addrs.choose(&mut rand::thread_rng()).unwrap(),
+                };
+                self.conn
+                    .insert(BufReader::new(TcpStream::connect(addr).await?))
+            }
+        };
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        conn.get_mut().write_all(request.as_bytes()).await?;
+        read_reply(conn).await
+    }
+}
+
+/// Reads a reply, including the elements of an array.
+fn read_reply(conn: &mut BufReader<TcpStream>) -> BoxFuture<'_, std::io::Result<Reply>> {
+    async move {
+        let mut line = String::new();
+        conn.read_line(&mut line).await?;
+        let Some(line) = line.strip_suffix("\r\n") else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+        if line.is_empty() {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let invalid_data = |_| std::io::Error::from(std::io::ErrorKind::InvalidData);
+        let (kind, payload) = line.split_at(1);
+        Ok(match kind {
+            "+" => Reply::Status,
+            "-" => Reply::Error(payload.to_owned()),
+            ":" => Reply::Integer(payload.parse().map_err(invalid_data)?),
+            "$" => match payload.parse::<i64>().map_err(invalid_data)? {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut buf = vec![0; len as usize + 2];
+                    conn.read_exact(&mut buf).await?;
+                    buf.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(buf).map_err(|_| {
+                        std::io::Error::from(std::io::ErrorKind::InvalidData)
+                    })?))
+                }
+            },
+            "*" => {
+                let len = payload.parse::<i64>().map_err(invalid_data)?;
+                let mut values = Vec::new();
+                for _ in 0..len {
+                    values.push(read_reply(conn).await?);
+                }
+                Reply::Array(values)
+            }
+            _ => return Err(std::io::ErrorKind::InvalidData.into()),
+        })
+    }
+    .boxed()
+}
@@ -0,0 +1,311 @@
+//! Linearizability checker based on the algorithm by Wing and Gong with the
+//! memoization by Lowe, as implemented in Knossos and Porcupine.
+//!
+//! The checker searches for a total order of the operations that respects
+//! their real-time order and is valid under a sequential model. Operations
+//! that are not yet linearized are kept in doubly linked lists sorted by
+//! invocation time, so that finding the candidates for the next linearized
+//! operation only scans the operations that are concurrent with each other.
+
+use std::{collections::HashSet, fmt::Debug, hash::Hash, time::Instant};
+
+/// Sequential specification of an object.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+    type Input: Debug;
+    type Output: Debug;
+
+    fn init() -> Self::State;
+
+    /// Returns the state after applying `input` to `state`, or `None` if the
+    /// operation can't return `output` in `state`.
+    ///
+    /// `output` is `None` if the client doesn't know the result of the
+    /// operation.
+    fn step(
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State>;
+}
+
+#[derive(Debug)]
+pub struct Operation<I, O> {
+    pub input: I,
+    pub invoked_at: Instant,
+
+    /// `None` if the client doesn't know whether the operation took effect,
+    /// e.g. because the request timed out. Such an operation may take effect
+    /// at any point after its invocation, or never.
+    pub returned: Option<(Instant, O)>,
+}
+
+pub fn is_linearizable<M: Model>(history: &[Operation<M::Input, M::Output>]) -> bool {
+    // Operations with known results come first so that the search tries
+    // them before guessing which of the operations with unknown results
+    // took effect
+    let mut ops: Vec<_> = history.iter().collect();
+    ops.sort_by_key(|op| (op.returned.is_none(), op.invoked_at));
+    let returned_at = |i: usize| ops[i].returned.as_ref().map(|(at, _)| *at);
+    let is_before = |invoked_at: Instant, returned_at: Option<Instant>| {
+        returned_at.is_none_or(|returned_at| invoked_at < returned_at)
+    };
+
+    // Two circular doubly linked lists of the operations that are not
+    // linearized yet, one for the operations with known results with the
+    // sentinel at index `n` and one for the rest with the sentinel at
+    // index `n + 1`
+    let n = ops.len();
+    let num_returned = ops.iter().filter(|op| op.returned.is_some()).count();
+    let mut next = vec![0; n + 2];
+    let mut prev = vec![0; n + 2];
+    for (sentinel, range) in [(n, 0..num_returned), (n + 1, num_returned..n)] {
+        let mut last = sentinel;
+        for i in range {
+            next[last] = i;
+            prev[i] = last;
+            last = i;
+        }
+        next[last] = sentinel;
+        prev[sentinel] = last;
+    }
+
+    // Moves on to the operations with unknown results at the end of the
+    // first list
+    let skip_sentinel = |next: &[usize], cursor: usize| {
+        if cursor == n {
+            next[n + 1]
+        } else {
+            cursor
+        }
+    };
+
+    let mut num_remaining = num_returned;
+    let mut linearized = vec![0u64; n.div_ceil(64)];
+    let mut cache = HashSet::new();
+    let mut state = M::init();
+    let mut stack: Vec<Frame<M::State>> = Vec::new();
+
+    // Scan position in the lists and the earliest return of the operations
+    // skipped so far. An operation can be linearized next only if it was
+    // invoked before every other remaining operation returned.
+    let mut cursor = skip_sentinel(&next, next[n]);
+    let mut min_returned_at = None;
+    loop {
+        // Operations with unknown results don't have to be linearized
+        if num_remaining == 0 {
+            return true;
+        }
+
+        if cursor != n + 1 && !is_before(ops[cursor].invoked_at, min_returned_at) {
+            // The rest of the list is invoked even later
+            cursor = if cursor < num_returned {
+                next[n + 1]
+            } else {
+                n + 1
+            };
+            continue;
+        }
+        if cursor != n + 1 {
+            let op = ops[cursor];
+            let output = op.returned.as_ref().map(|(_, output)| output);
+            if let Some(next_state) = M::step(&state, &op.input, output) {
+                linearized[cursor / 64] |= 1 << (cursor % 64);
+                if cache.insert((linearized.clone(), next_state.clone())) {
+                    stack.push(Frame {
+                        op: cursor,
+                        state: std::mem::replace(&mut state, next_state),
+                        min_returned_at,
+                    });
+                    next[prev[cursor]] = next[cursor];
+                    prev[next[cursor]] = prev[cursor];
+                    if op.returned.is_some() {
+                        num_remaining -= 1;
+                    }
+                    cursor = skip_sentinel(&next, next[n]);
+                    min_returned_at = None;
+                    continue;
+                }
+                linearized[cursor / 64] &= !(1 << (cursor % 64));
+            }
+            min_returned_at = min_instant(min_returned_at, returned_at(cursor));
+            cursor = skip_sentinel(&next, next[cursor]);
+            continue;
+        }
+
+        // No more candidates. Undo the last linearized operation and try
+        // the operations following it.
+        let Some(frame) = stack.pop() else {
+            return false;
+        };
+        let op = frame.op;
+        next[prev[op]] = op;
+        prev[next[op]] = op;
+        linearized[op / 64] &= !(1 << (op % 64));
+        if ops[op].returned.is_some() {
+            num_remaining += 1;
+        }
+        state = frame.state;
+        min_returned_at = min_instant(frame.min_returned_at, returned_at(op));
+        cursor = skip_sentinel(&next, next[op]);
+    }
+}
+
+struct Frame<S> {
+    op: usize,
+    state: S,
+    min_returned_at: Option<Instant>,
+}
+
+/// Returns the earlier of the two instants, where `None` is infinitely late.
+fn min_instant(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// A single value that can be overwritten.
+pub struct Register;
+
+#[derive(Debug)]
+pub enum RegisterInput {
+    Write(u64),
+    Read,
+}
+
+impl Model for Register {
+    type State = Option<u64>;
+    type Input = RegisterInput;
+
+    /// Value returned by reads
+    type Output = Option<u64>;
+
+    fn init() -> Self::State {
+        None
+    }
+
+    fn step(
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        match input {
+            RegisterInput::Write(value) => Some(Some(*value)),
+            RegisterInput::Read => output.is_none_or(|value| value == state).then_some(*state),
+        }
+    }
+}
+
+/// An integer that can be incremented.
+pub struct Counter;
+
+#[derive(Debug)]
+pub enum CounterInput {
+    Increment,
+    Read,
+}
+
+impl Model for Counter {
+    type State = i64;
+    type Input = CounterInput;
+
+    /// Value after increments and value returned by reads
+    type Output = i64;
+
+    fn init() -> Self::State {
+        0
+    }
+
+    fn step(
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        let next_state = match input {
+            CounterInput::Increment => state + 1,
+            CounterInput::Read => *state,
+        };
+        output
+            .is_none_or(|value| *value == next_state)
+            .then_some(next_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_linearizable, Counter, CounterInput, Operation, Register, RegisterInput};
+    use std::{
+        sync::LazyLock,
+        time::{Duration, Instant},
+    };
+
+    static ORIGIN: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+    fn op<I, O>(input: I, invoked_at: u64, returned: Option<(u64, O)>) -> Operation<I, O> {
+        let at = |t| *ORIGIN + Duration::from_millis(t);
+        Operation {
+            input,
+            invoked_at: at(invoked_at),
+            returned: returned.map(|(t, output)| (at(t), output)),
+        }
+    }
+
+    #[test]
+    fn concurrent_operations_can_be_reordered() {
+        // The read overlaps with the write, so it can be ordered either way
+        let history = [
+            op(RegisterInput::Write(1), 0, Some((10, None))),
+            op(RegisterInput::Write(2), 20, Some((40, None))),
+            op(RegisterInput::Read, 30, Some((50, Some(1)))),
+            op(RegisterInput::Read, 45, Some((60, Some(2)))),
+        ];
+        assert!(is_linearizable::<Register>(&history));
+    }
+
+    #[test]
+    fn stale_read_is_detected() {
+        let history = [
+            op(RegisterInput::Write(1), 0, Some((10, None))),
+            op(RegisterInput::Write(2), 20, Some((30, None))),
+            op(RegisterInput::Read, 40, Some((50, Some(1)))),
+        ];
+        assert!(!is_linearizable::<Register>(&history));
+    }
+
+    #[test]
+    fn operations_with_unknown_results_may_or_may_not_take_effect() {
+        let history = [
+            op(CounterInput::Increment, 0, None),
+            op(CounterInput::Increment, 10, Some((20, 1))),
+            op(CounterInput::Read, 30, Some((40, 1))),
+        ];
+        assert!(is_linearizable::<Counter>(&history));
+
+        let history = [
+            op(CounterInput::Increment, 0, None),
+            op(CounterInput::Increment, 10, Some((20, 1))),
+            op(CounterInput::Read, 30, Some((40, 2))),
+        ];
+        assert!(is_linearizable::<Counter>(&history));
+
+        let history = [
+            op(CounterInput::Increment, 0, None),
+            op(CounterInput::Increment, 10, Some((20, 2))),
+            op(CounterInput::Read, 30, Some((40, 1))),
+        ];
+        assert!(!is_linearizable::<Counter>(&history));
+    }
+
+    #[test]
+    fn operations_with_unknown_results_are_linearized_lazily() {
+        // Trying to linearize any of the failed increments before the read
+        // would require backtracking through all of their subsets
+        let mut history: Vec<_> = (0..100)
+            .map(|i| op(CounterInput::Increment, i, None))
+            .collect();
+        history.push(op(CounterInput::Read, 200, Some((210, 0))));
+        assert!(is_linearizable::<Counter>(&history));
+    }
+}
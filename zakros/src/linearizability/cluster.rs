@@ -0,0 +1,195 @@
+//! Cluster of zakros nodes running in the test process.
+//!
+//! Each node runs in its own tokio runtime so that it can be crashed by
+//! shutting down the runtime, which drops all of its tasks and connections
+//! at once. Nodes reach each other through proxies, one for each direction
+//! of each pair of nodes, which refuse and sever connections to partition
+//! the cluster.
+
+use crate::{config::Config, serve};
+use std::{
+    collections::BTreeSet,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    sync::watch,
+};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Cluster {
+    dir: PathBuf,
+    nodes: Vec<Node>,
+    num_groups: usize,
+
+    /// runtime running the proxies, which stop when it is dropped
+    _proxies: Runtime,
+
+    /// nodes on one side of the partition
+    partition: watch::Sender<BTreeSet<usize>>,
+}
+
+struct Node {
+    /// address that clients connect to
+    addr: SocketAddr,
+
+    /// addresses of the proxies to the other nodes, indexed by node id
+    peer_addrs: Vec<SocketAddr>,
+
+    runtime: Option<Runtime>,
+}
+
+impl Cluster {
+    pub fn new(dir: PathBuf, num_nodes: usize, num_groups: usize) -> std::io::Result<Self> {
+        let _ = std::fs::remove_dir_all(&dir);
+        let proxies = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let (partition, _) = watch::channel(BTreeSet::new());
+
+        let mut addrs = Vec::with_capacity(num_nodes);
+        for _ in 0..num_nodes {
+            // The port is released here and bound again by the node
+            addrs.push(StdTcpListener::bind("127.0.0.1:0")?.local_addr()?);
+        }
+        let mut nodes = Vec::with_capacity(num_nodes);
+        for (src, addr) in addrs.iter().enumerate() {
+            let mut peer_addrs = Vec::with_capacity(num_nodes);
+            for (dest, dest_addr) in addrs.iter().enumerate() {
+                if dest == src {
+                    peer_addrs.push(*addr);
+                    continue;
+                }
+                let listener = proxies.block_on(TcpListener::bind("127.0.0.1:0"))?;
+                peer_addrs.push(listener.local_addr()?);
+                proxies.spawn(proxy(
+                    listener,
+                    *dest_addr,
+                    src,
+                    dest,
+                    partition.subscribe(),
+                ));
+            }
+            nodes.push(Node {
+                addr: *addr,
+                peer_addrs,
+                runtime: None,
+            });
+        }
+
+        let mut cluster = Self {
+            dir,
+            nodes,
+            num_groups,
+            _proxies: proxies,
+            partition,
+        };
+        for node_id in 0..num_nodes {
+            cluster.start(node_id)?;
+        }
+        Ok(cluster)
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.nodes.iter().map(|node| node.addr).collect()
+    }
+
+    pub fn is_running(&self, node_id: usize) -> bool {
+        self.nodes[node_id].runtime.is_some()
+    }
+
+    /// Starts the node, recovering its state from the disk.
+    pub fn start(&mut self, node_id: usize) -> std::io::Result<()> {
+        let node = &mut self.nodes[node_id];
+        assert!(node.runtime.is_none());
+        let cluster_addrs: Vec<_> = node
+            .peer_addrs
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        let config = format!(
+            "bind {}\nport {}\ndir {}\nnode-id {}\nraft-groups {}\ncluster-addrs {}\n",
+            node.addr.ip(),
+            node.addr.port(),
+            self.dir.display(),
+            node_id,
+            self.num_groups,
+            cluster_addrs.join(" ")
+        );
+        let config: Config = zakros_redis::config::from_bytes(config.as_bytes())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let listener = runtime.block_on(TcpListener::bind(node.addr))?;
+        runtime.spawn(async move {
+            if let Err(err) = serve(config, listener).await {
+                panic!("node {node_id} failed: {err}");
+            }
+        });
+        node.runtime = Some(runtime);
+        Ok(())
+    }
+
+    /// Kills the node without letting it clean up.
+    pub fn crash(&mut self, node_id: usize) {
+        if let Some(runtime) = self.nodes[node_id].runtime.take() {
+            // Wait for the tasks to be dropped so that the listener is
+            // closed before the node is started again
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    /// Cuts the given nodes off from the rest of the cluster.
+    pub fn partition(&self, nodes: BTreeSet<usize>) {
+        self.partition.send_replace(nodes);
+    }
+
+    pub fn heal(&self) {
+        self.partition.send_replace(BTreeSet::new());
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node_id in 0..self.nodes.len() {
+            self.crash(node_id);
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Forwards connections from `src` to the node listening on `dest_addr`
+/// while the two nodes are on the same side of the partition.
+async fn proxy(
+    listener: TcpListener,
+    dest_addr: SocketAddr,
+    src: usize,
+    dest: usize,
+    partition: watch::Receiver<BTreeSet<usize>>,
+) {
+    let is_connected =
+        move |partition: &BTreeSet<usize>| partition.contains(&src) == partition.contains(&dest);
+    while let Ok((mut inbound, _)) = listener.accept().await {
+        if !is_connected(&partition.borrow()) {
+            continue;
+        }
+        let mut partition = partition.clone();
+        tokio::spawn(async move {
+            let Ok(mut outbound) = TcpStream::connect(dest_addr).await else {
+                return;
+            };
+            tokio::select! {
+                _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => (),
+                _ = partition.wait_for(|partition| !is_connected(partition)) => (),
+            }
+        });
+    }
+}
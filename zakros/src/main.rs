@@ -1,14 +1,26 @@
 mod command;
 mod config;
 mod connection;
+mod expire;
+mod group;
+#[cfg(test)]
+mod linearizability;
 mod rpc;
+mod session;
+mod shutdown;
 mod store;
 
 use config::{Config, RaftStorageKind};
+use group::{Group, Groups};
 use rand::seq::SliceRandom;
 use rpc::{RpcClient, RpcServer, RpcService};
-use std::{sync::Arc, time::SystemTime};
-use store::{RaftCommand, Store};
+use shutdown::{Coordinator, Shutdown};
+use std::{
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use store::{NodeAddrs, Store};
 use tarpc::{
     server::{BaseChannel, Channel},
     tokio_serde::formats::Bincode,
@@ -16,14 +28,17 @@ use tarpc::{
 use tokio::{
     io::AsyncReadExt,
     net::{TcpListener, TcpStream},
-    sync::Semaphore,
+    sync::{
+        broadcast::{self, error::RecvError},
+        Semaphore,
+    },
 };
 use tokio_util::codec::LengthDelimitedCodec;
 use tracing_subscriber::{fmt::Subscriber, EnvFilter};
 use zakros_raft::{
     config::RaftConfig,
     storage::{DiskStorage, MemoryStorage},
-    NodeId, Raft,
+    Event, NodeId, Raft,
 };
 use zakros_redis::pubsub::Publisher;
 
@@ -41,8 +56,11 @@ fn main() -> anyhow::Result<()> {
         .enable_all()
         .worker_threads(config.worker_threads.get())
         .build()?
-        .block_on(async { tokio::spawn(serve(config)).await })?
-        .map_err(Into::into)
+        .block_on(async {
+            let listener = TcpListener::bind((config.bind, config.port)).await?;
+            tracing::info!("bound to {}", listener.local_addr()?);
+            tokio::spawn(serve(config, listener)).await?
+        })
 }
 
 async fn is_rpc(conn: &mut TcpStream) -> std::io::Result<bool> {
@@ -61,12 +79,14 @@ async fn is_rpc(conn: &mut TcpStream) -> std::io::Result<bool> {
     }
 }
 
-async fn serve(config: Config) -> anyhow::Result<()> {
-    let listener = TcpListener::bind((config.bind, config.port)).await?;
-    tracing::info!("bound to {}", listener.local_addr()?);
-    let shared = Arc::new(Shared::new(config).await?);
-    loop {
-        let (mut conn, addr) = listener.accept().await?;
+async fn serve(config: Config, listener: TcpListener) -> anyhow::Result<()> {
+    let (mut coordinator, shutdown) = Coordinator::new()?;
+    let shared = Arc::new(Shared::new(config, shutdown).await?);
+    let save = loop {
+        let (mut conn, addr) = tokio::select! {
+            result = listener.accept() => result?,
+            save = coordinator.wait(&shared.groups) => break save,
+        };
         tracing::trace!("accepting connection: {}", addr);
         let shared = shared.clone();
         tokio::spawn(async move {
@@ -85,6 +105,39 @@ async fn serve(config: Config) -> anyhow::Result<()> {
                 let _ = connection::serve(shared, conn).await;
             }
         });
+    };
+
+    // Stop accepting connections
+    drop(listener);
+    for raft in shared.groups.iter().filter_map(|group| group.raft.as_ref()) {
+        if let Err(err) = raft.shutdown(save).await {
+            tracing::warn!("failed to shut down Raft server: {}", err);
+        }
+    }
+    coordinator.finish();
+    tracing::info!("ready to exit");
+    Ok(())
+}
+
+async fn log_leader_changes(
+    group: usize,
+    mut events: broadcast::Receiver<Event>,
+    node_addrs: NodeAddrs,
+) {
+    loop {
+        match events.recv().await {
+            Ok(Event::LeaderChanged {
+                leader_id: Some(leader_id),
+                term,
+            }) => match node_addrs.get(leader_id) {
+                Some(addr) => {
+                    tracing::info!(group, term, "leader changed to {:?} at {}", leader_id, addr)
+                }
+                None => tracing::info!(group, term, "leader changed to {:?}", leader_id),
+            },
+            Ok(_) | Err(RecvError::Lagged(_)) => (),
+            Err(RecvError::Closed) => return,
+        }
     }
 }
 
@@ -92,9 +145,8 @@ const RUN_ID_LEN: usize = 40;
 
 pub struct Shared {
     config: Config,
-    store: Store,
-    raft: Option<Raft<RaftCommand>>,
-    rpc_client: Arc<RpcClient>,
+    groups: Groups,
+    shutdown: Shutdown,
     publisher: Publisher,
     run_id: [u8; RUN_ID_LEN],
     started_at: SystemTime,
@@ -102,7 +154,7 @@ pub struct Shared {
 }
 
 impl Shared {
-    async fn new(config: Config) -> anyhow::Result<Self> {
+    async fn new(config: Config, shutdown: Shutdown) -> anyhow::Result<Self> {
         let started_at = SystemTime::now();
 
         let mut run_id = [0; RUN_ID_LEN];
@@ -114,41 +166,28 @@ impl Shared {
             }
         }
 
-        let store = Store::new();
-        let rpc_client = Arc::new(RpcClient::new(config.cluster_addrs.clone()));
-
-        let raft = if config.raft_enabled {
-            let node_id = NodeId::from(config.node_id);
-            let nodes = (0..config.cluster_addrs.len() as u64)
-                .map(NodeId::from)
-                .collect();
-            let raft_config = RaftConfig::default();
-            let store = store.clone();
-            let rpc_client = rpc_client.clone();
-            let raft = match config.raft_storage {
-                RaftStorageKind::Disk => {
-                    let dir = format!("node-{}", Into::<u64>::into(node_id));
-                    let storage = DiskStorage::new(config.dir.join(dir)).await?;
-                    Raft::new(node_id, nodes, raft_config, store, storage, rpc_client)
-                }
-                RaftStorageKind::Memory => {
-                    let storage = MemoryStorage::new();
-                    Raft::new(node_id, nodes, raft_config, store, storage, rpc_client)
-                }
-            };
-            Some(raft)
+        // Without Raft, a single store holds all the slots
+        let num_groups = if config.raft_enabled {
+            config.raft_groups.get()
         } else {
-            None
+            1
         };
+        if config.raft_enabled && config.raft_storage == RaftStorageKind::Disk {
+            group::check_slot_layout(&config.dir, config.node_id, num_groups)?;
+        }
+        let mut groups = Vec::with_capacity(num_groups);
+        for (i, slots) in group::slot_ranges(num_groups).enumerate() {
+            groups.push(new_group(&config, i, slots).await?);
+        }
+        let groups = Groups::new(groups);
 
         let publisher = Publisher::new(32768);
         let conn_limit = Arc::new(Semaphore::new(config.max_clients));
 
         Ok(Self {
             config,
-            store,
-            raft,
-            rpc_client,
+            groups,
+            shutdown,
             publisher,
             run_id,
             started_at,
@@ -156,3 +195,93 @@ impl Shared {
         })
     }
 }
+
+impl Shared {
+    /// Returns the addresses of the nodes, as known to the first Raft group.
+    fn node_addrs(&self) -> &NodeAddrs {
+        self.groups[0].node_addrs()
+    }
+}
+
+async fn new_group(config: &Config, index: usize, slots: Range<u16>) -> anyhow::Result<Group> {
+    // Learners are numbered after the voting members
+    let node_addrs = NodeAddrs::new(
+        config
+            .cluster_addrs
+            .iter()
+            .chain(&config.learner_addrs)
+            .enumerate()
+            .map(|(i, addr)| (NodeId::from(i as u64), *addr)),
+    );
+    let store = Store::new(node_addrs.clone());
+    let rpc_client = Arc::new(RpcClient::new(index, node_addrs.clone()));
+
+    let raft = if config.raft_enabled {
+        let node_id = NodeId::from(config.node_id);
+        let num_nodes = config.cluster_addrs.len() as u64;
+        let num_learners = config.learner_addrs.len() as u64;
+        let nodes = (0..num_nodes).map(NodeId::from).collect();
+        let learners = (num_nodes..num_nodes + num_learners)
+            .map(NodeId::from)
+            .collect();
+        let raft_config = RaftConfig::builder()
+            .lease_read(config.raft_lease_read)
+            .max_clock_drift(Duration::from_millis(config.raft_max_clock_drift))
+            .follower_read(config.raft_follower_read)
+            .build()?;
+        let store = store.clone();
+        let rpc_client = rpc_client.clone();
+        let raft = match config.raft_storage {
+            RaftStorageKind::Disk => {
+                // The first group keeps the directory used before the
+                // keyspace was split into groups
+                let dir = match index {
+                    0 => format!("node-{}", config.node_id),
+                    _ => format!("node-{}-group-{}", config.node_id, index),
+                };
+                let storage = DiskStorage::builder(config.dir.join(dir))
+                    .cache_size(config.raft_log_cache_size)
+                    .build()
+                    .await?;
+                Raft::new(
+                    node_id,
+                    nodes,
+                    learners,
+                    raft_config,
+                    store,
+                    storage,
+                    rpc_client,
+                )
+            }
+            RaftStorageKind::Memory => {
+                let storage = MemoryStorage::new();
+                Raft::new(
+                    node_id,
+                    nodes,
+                    learners,
+                    raft_config,
+                    store,
+                    storage,
+                    rpc_client,
+                )
+            }
+        };
+        tokio::spawn(log_leader_changes(
+            index,
+            raft.subscribe(),
+            node_addrs.clone(),
+        ));
+        Some(raft)
+    } else {
+        None
+    };
+
+    tokio::spawn(expire::run(store.clone(), raft.clone()));
+
+    Ok(Group {
+        slots,
+        store,
+        raft,
+        rpc_client,
+    })
+}
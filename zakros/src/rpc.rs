@@ -1,34 +1,73 @@
-use crate::{store::RaftCommand, Shared};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use crate::{
+    group::ClusterCommand,
+    store::{NodeAddrs, RaftCommand},
+    Shared,
+};
+use bytes::Bytes;
+use std::{sync::Arc, time::Duration};
 use tarpc::{context::Context, tokio_serde::formats::Bincode};
 use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
 use tokio_util::codec::LengthDelimitedCodec;
 use zakros_raft::{
-    rpc::{AppendEntries, AppendEntriesResponse, RequestVote, RequestVoteResponse, Transport},
+    rpc::{
+        AppendEntries, AppendEntriesResponse, InstallSnapshot, InstallSnapshotResponse, PreVote,
+        PreVoteResponse, ReadIndex, ReadIndexResponse, RequestVote, RequestVoteResponse,
+        TimeoutNow, TimeoutNowResponse, Transport,
+    },
     NodeId, Raft, RaftError, RaftResult,
 };
-use zakros_redis::pubsub::PubSubMessage;
+use zakros_redis::{command::ReadCommand, pubsub::PubSubMessage, resp};
+
+/// Time to wait for a CLUSTER command forwarded to the leader, which can
+/// take a few election timeouts to transfer leadership
+const CLUSTER_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tarpc::service]
 pub trait RpcService {
     async fn append_entries(
+        group: usize,
         request: AppendEntries<RaftCommand>,
     ) -> RaftResult<AppendEntriesResponse>;
 
-    async fn request_vote(request: RequestVote) -> RaftResult<RequestVoteResponse>;
+    async fn request_vote(group: usize, request: RequestVote) -> RaftResult<RequestVoteResponse>;
+
+    async fn pre_vote(group: usize, request: PreVote) -> RaftResult<PreVoteResponse>;
+
+    async fn install_snapshot(
+        group: usize,
+        request: InstallSnapshot,
+    ) -> RaftResult<InstallSnapshotResponse>;
+
+    async fn timeout_now(group: usize, request: TimeoutNow) -> RaftResult<TimeoutNowResponse>;
+
+    async fn read_index(group: usize, request: ReadIndex) -> RaftResult<ReadIndexResponse>;
+
+    /// Replicates the write as the leader of the group, and returns the
+    /// RESP-encoded reply.
+    async fn write(group: usize, command: RaftCommand) -> RaftResult<Bytes>;
+
+    /// Runs the read as the leader of the group, and returns the
+    /// RESP-encoded reply.
+    async fn read(group: usize, command: ReadCommand, args: Vec<Bytes>) -> RaftResult<Bytes>;
+
+    /// Runs the CLUSTER command as the leader of the group.
+    async fn cluster(group: usize, command: ClusterCommand) -> RaftResult<()>;
 
     async fn publish(message: PubSubMessage);
 }
 
+/// Client sending RPCs of a Raft group
 pub struct RpcClient {
-    cluster_addrs: Vec<SocketAddr>,
+    group: usize,
+    node_addrs: NodeAddrs,
     timeout: Duration,
 }
 
 impl RpcClient {
-    pub(crate) fn new(cluster_addrs: Vec<SocketAddr>) -> Self {
+    pub(crate) fn new(group: usize, node_addrs: NodeAddrs) -> Self {
         Self {
-            cluster_addrs,
+            group,
+            node_addrs,
             timeout: Duration::from_secs(1),
         }
     }
@@ -48,7 +87,7 @@ impl Transport for RpcClient {
         timeout(self.timeout, async {
             self.client(dest)
                 .await?
-                .append_entries(Context::current(), request)
+                .append_entries(Context::current(), self.group, request)
                 .await?
                 .map_err(Into::into)
         })
@@ -63,7 +102,67 @@ impl Transport for RpcClient {
         timeout(self.timeout, async {
             self.client(dest)
                 .await?
-                .request_vote(Context::current(), request)
+                .request_vote(Context::current(), self.group, request)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    async fn send_pre_vote(
+        &self,
+        dest: NodeId,
+        request: PreVote,
+    ) -> Result<PreVoteResponse, Self::Error> {
+        timeout(self.timeout, async {
+            self.client(dest)
+                .await?
+                .pre_vote(Context::current(), self.group, request)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        dest: NodeId,
+        request: InstallSnapshot,
+    ) -> Result<InstallSnapshotResponse, Self::Error> {
+        timeout(self.timeout, async {
+            self.client(dest)
+                .await?
+                .install_snapshot(Context::current(), self.group, request)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    async fn send_timeout_now(
+        &self,
+        dest: NodeId,
+        request: TimeoutNow,
+    ) -> Result<TimeoutNowResponse, Self::Error> {
+        timeout(self.timeout, async {
+            self.client(dest)
+                .await?
+                .timeout_now(Context::current(), self.group, request)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    async fn send_read_index(
+        &self,
+        dest: NodeId,
+        request: ReadIndex,
+    ) -> Result<ReadIndexResponse, Self::Error> {
+        timeout(self.timeout, async {
+            self.client(dest)
+                .await?
+                .read_index(Context::current(), self.group, request)
                 .await?
                 .map_err(Into::into)
         })
@@ -75,7 +174,9 @@ impl RpcClient {
     pub const RPC_MARKER: &'static [u8] = b"\0EZwHMud4TueVKxhHinaj3PgyZhSm8Nj";
 
     async fn client(&self, node_id: NodeId) -> std::io::Result<RpcServiceClient> {
-        let addr = self.cluster_addrs[Into::<u64>::into(node_id) as usize];
+        let addr = self.node_addrs.get(node_id).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "unknown node address")
+        })?;
         let mut conn = TcpStream::connect(addr).await?;
         conn.write_all(Self::RPC_MARKER).await?;
         let transport = tarpc::serde_transport::new(
@@ -85,6 +186,44 @@ impl RpcClient {
         Ok(RpcServiceClient::new(Default::default(), transport).spawn())
     }
 
+    pub async fn write(&self, dest: NodeId, command: RaftCommand) -> anyhow::Result<Bytes> {
+        timeout(self.timeout, async move {
+            self.client(dest)
+                .await?
+                .write(Context::current(), self.group, command)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    pub async fn read(
+        &self,
+        dest: NodeId,
+        command: ReadCommand,
+        args: Vec<Bytes>,
+    ) -> anyhow::Result<Bytes> {
+        timeout(self.timeout, async move {
+            self.client(dest)
+                .await?
+                .read(Context::current(), self.group, command, args)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
+    pub async fn cluster(&self, dest: NodeId, command: ClusterCommand) -> anyhow::Result<()> {
+        timeout(CLUSTER_COMMAND_TIMEOUT, async move {
+            self.client(dest)
+                .await?
+                .cluster(Context::current(), self.group, command)
+                .await?
+                .map_err(Into::into)
+        })
+        .await?
+    }
+
     pub async fn publish(&self, dest: NodeId, message: PubSubMessage) -> anyhow::Result<()> {
         timeout(self.timeout, async move {
             self.client(dest)
@@ -105,11 +244,9 @@ impl RpcServer {
         Self(shared)
     }
 
-    fn raft(&self) -> RaftResult<&Raft<RaftCommand>> {
-        match &self.0.raft {
-            Some(raft) => Ok(raft),
-            None => Err(RaftError::Shutdown),
-        }
+    fn raft(&self, group: usize) -> RaftResult<&Raft<RaftCommand>> {
+        let group = self.0.groups.get(group).ok_or(RaftError::Shutdown)?;
+        group.raft.as_ref().ok_or(RaftError::Shutdown)
     }
 }
 
@@ -118,17 +255,76 @@ impl RpcService for RpcServer {
     async fn append_entries(
         self,
         _: Context,
+        group: usize,
         request: AppendEntries<RaftCommand>,
     ) -> RaftResult<AppendEntriesResponse> {
-        self.raft()?.append_entries(request).await
+        self.raft(group)?.append_entries(request).await
     }
 
     async fn request_vote(
         self,
         _: Context,
+        group: usize,
         request: RequestVote,
     ) -> RaftResult<RequestVoteResponse> {
-        self.raft()?.request_vote(request).await
+        self.raft(group)?.request_vote(request).await
+    }
+
+    async fn pre_vote(
+        self,
+        _: Context,
+        group: usize,
+        request: PreVote,
+    ) -> RaftResult<PreVoteResponse> {
+        self.raft(group)?.pre_vote(request).await
+    }
+
+    async fn install_snapshot(
+        self,
+        _: Context,
+        group: usize,
+        request: InstallSnapshot,
+    ) -> RaftResult<InstallSnapshotResponse> {
+        self.raft(group)?.install_snapshot(request).await
+    }
+
+    async fn timeout_now(
+        self,
+        _: Context,
+        group: usize,
+        request: TimeoutNow,
+    ) -> RaftResult<TimeoutNowResponse> {
+        self.raft(group)?.timeout_now(request).await
+    }
+
+    async fn read_index(
+        self,
+        _: Context,
+        group: usize,
+        request: ReadIndex,
+    ) -> RaftResult<ReadIndexResponse> {
+        self.raft(group)?.read_index(request).await
+    }
+
+    async fn write(self, _: Context, group: usize, command: RaftCommand) -> RaftResult<Bytes> {
+        let result = self.raft(group)?.write(command).await?;
+        Ok(resp::to_bytes(&result))
+    }
+
+    async fn read(
+        self,
+        _: Context,
+        group: usize,
+        command: ReadCommand,
+        args: Vec<Bytes>,
+    ) -> RaftResult<Bytes> {
+        self.raft(group)?.read().await?;
+        let result = command.call(&self.0.groups[group].store, &args);
+        Ok(resp::to_bytes(&result))
+    }
+
+    async fn cluster(self, _: Context, group: usize, command: ClusterCommand) -> RaftResult<()> {
+        command.run(self.raft(group)?).await
     }
 
     async fn publish(self, _: Context, message: PubSubMessage) {
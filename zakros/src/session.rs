@@ -0,0 +1,148 @@
+//! Client sessions for exactly-once execution of writes.
+//!
+//! A client registers a session and numbers its writes with consecutive
+//! sequence numbers. The state machine remembers the reply to the latest
+//! write of each session, so a write that is retried after its reply was lost
+//! is answered from the table instead of being applied again. The table is
+//! part of the replicated state, so it survives failovers and snapshots.
+//!
+//! Each Raft group keeps its own table. A session is registered in every
+//! group under the same id, and each group sees an increasing subset of the
+//! sequence numbers.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use zakros_redis::{
+    resp::{self, Value},
+    RedisResult, ResponseError,
+};
+
+/// Maximum number of sessions kept in the table.
+///
+/// When a new session is registered while the table is full, the least
+/// recently used session is evicted.
+const MAX_SESSIONS: usize = 16384;
+
+/// Client session bound to a connection
+pub struct ClientSession {
+    pub id: u64,
+
+    /// sequence number of the next write
+    pub next_seq: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Sessions {
+    /// incremented on every access to the table. Used to find the least
+    /// recently used session deterministically on every node.
+    clock: u64,
+
+    sessions: HashMap<u64, Session>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    /// sequence number of the last applied write, or 0 if none
+    last_seq: u64,
+
+    /// RESP-encoded reply to the last applied write
+    reply: Bytes,
+
+    last_used: u64,
+}
+
+impl Sessions {
+    /// Registers a session with the id chosen by the node that the client
+    /// is connected to.
+    pub fn register(&mut self, id: u64) -> RedisResult {
+        if self.sessions.contains_key(&id) {
+            return Err(ResponseError::Other("Client session id is already in use").into());
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            let lru = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.last_used)
+                .map(|(id, _)| *id);
+            if let Some(id) = lru {
+                self.sessions.remove(&id);
+            }
+        }
+        self.clock += 1;
+        self.sessions.insert(
+            id,
+            Session {
+                last_seq: 0,
+                reply: Bytes::new(),
+                last_used: self.clock,
+            },
+        );
+        Ok(Value::ok())
+    }
+
+    /// Calls `f` to apply the write `seq` of the session `id`, unless the
+    /// write has already been applied.
+    pub fn apply(&mut self, id: u64, seq: u64, f: impl FnOnce() -> RedisResult) -> RedisResult {
+        self.clock += 1;
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return Err(ResponseError::Other("No such client session").into());
+        };
+        session.last_used = self.clock;
+        if seq == session.last_seq && seq > 0 {
+            return Ok(Value::Raw(session.reply.clone()));
+        }
+        if seq <= session.last_seq {
+            return Err(
+                ResponseError::Other("Client session sequence number is already used").into(),
+            );
+        }
+        let result = f();
+        session.last_seq = seq;
+        session.reply = resp::to_bytes(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_writes_are_applied_once() {
+        let mut sessions = Sessions::default();
+        let id = 42;
+        sessions.register(id).unwrap();
+        assert!(sessions.register(id).is_err());
+        let mut counter: i64 = 0;
+        let mut incr = |seq| {
+            sessions.apply(id, seq, || {
+                counter += 1;
+                Ok(counter.into())
+            })
+        };
+        assert_eq!(incr(1), Ok(1.into()));
+        assert_eq!(incr(1), Ok(Value::Raw(Bytes::from_static(b":1\r\n"))));
+        assert_eq!(incr(2), Ok(2.into()));
+        assert!(incr(1).is_err());
+        assert_eq!(counter, 2);
+
+        assert!(sessions.apply(id + 1, 1, || Ok(Value::ok())).is_err());
+    }
+
+    #[test]
+    fn least_recently_used_session_is_evicted() {
+        let mut sessions = Sessions::default();
+        let (first, second) = (0, 1);
+        sessions.register(first).unwrap();
+        sessions.register(second).unwrap();
+        sessions.apply(first, 1, || Ok(Value::ok())).unwrap();
+        for id in 2..MAX_SESSIONS as u64 {
+            sessions.register(id).unwrap();
+        }
+        assert_eq!(sessions.sessions.len(), MAX_SESSIONS);
+        sessions.register(MAX_SESSIONS as u64).unwrap();
+        assert!(sessions.sessions.contains_key(&first));
+        assert!(!sessions.sessions.contains_key(&second));
+    }
+}
@@ -0,0 +1,185 @@
+//! Graceful shutdown of the server.
+//!
+//! Shutdown is started by the SHUTDOWN command or by SIGTERM/SIGINT. The
+//! node first hands over leadership of the Raft groups it leads so that the
+//! cluster doesn't have to wait for an election timeout. Until the handover
+//! completes, the shutdown can be aborted with SHUTDOWN ABORT. Then the
+//! server stops accepting connections and the Raft server makes its log
+//! durable.
+
+use crate::{group::Group, store::RaftCommand};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use zakros_raft::{Raft, RaftError};
+use zakros_redis::{RedisError, ResponseError};
+
+/// Handle to request the shutdown.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl Shutdown {
+    /// Starts shutting down, and waits until the server is about to exit.
+    ///
+    /// If `save` is true, a snapshot is taken before exiting.
+    pub async fn start(&self, save: bool) -> Result<(), RedisError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Request::Start { save, tx: Some(tx) });
+        rx.await.unwrap_or(Ok(()))
+    }
+
+    pub async fn abort(&self) -> Result<(), RedisError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(Request::Abort(tx));
+        rx.await.unwrap_or(Ok(()))
+    }
+}
+
+enum Request {
+    Start {
+        save: bool,
+        tx: Option<Responder>,
+    },
+    Abort(Responder),
+
+    /// the handover with the given id has completed
+    HandedOver(u64),
+}
+
+type Responder = oneshot::Sender<Result<(), RedisError>>;
+
+pub struct Coordinator {
+    tx: mpsc::UnboundedSender<Request>,
+    rx: mpsc::UnboundedReceiver<Request>,
+    handover: Option<Handover>,
+    next_handover_id: u64,
+}
+
+/// Leadership handover of the shutdown in progress
+struct Handover {
+    id: u64,
+    save: bool,
+    task: JoinHandle<()>,
+
+    /// clients waiting for the shutdown
+    waiters: Vec<Responder>,
+}
+
+impl Coordinator {
+    /// Creates a coordinator and starts listening to the signals.
+    pub fn new() -> std::io::Result<(Self, Shutdown)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let signal = tokio::select! {
+                        Some(()) = terminate.recv() => "SIGTERM",
+                        Some(()) = interrupt.recv() => "SIGINT",
+                        else => return,
+                    };
+                    tracing::info!("received {}, shutting down", signal);
+                    let request = Request::Start {
+                        save: false,
+                        tx: None,
+                    };
+                    if tx.send(request).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        let coordinator = Self {
+            tx: tx.clone(),
+            rx,
+            handover: None,
+            next_handover_id: 0,
+        };
+        Ok((coordinator, Shutdown { tx }))
+    }
+
+    /// Handles the requests until the server is ready to shut down, and
+    /// returns whether to take a snapshot.
+    ///
+    /// This is cancel safe.
+    pub async fn wait(&mut self, groups: &[Group]) -> bool {
+        loop {
+            let Some(request) = self.rx.recv().await else {
+                // `self` holds a sender, so the channel never closes
+                unreachable!()
+            };
+            match request {
+                Request::Start { save, tx } => match &mut self.handover {
+                    Some(handover) => {
+                        handover.save |= save;
+                        handover.waiters.extend(tx);
+                    }
+                    None => {
+                        let rafts = groups.iter().filter_map(|group| group.raft.clone());
+                        self.start_handover(rafts.collect(), save, tx);
+                    }
+                },
+                Request::Abort(tx) => match self.handover.take() {
+                    Some(handover) => {
+                        tracing::info!("shutdown aborted");
+                        handover.task.abort();
+                        for waiter in handover.waiters {
+                            let _ = waiter.send(Err(ResponseError::Other(
+                                "Errors trying to SHUTDOWN. Check logs.",
+                            )
+                            .into()));
+                        }
+                        let _ = tx.send(Ok(()));
+                    }
+                    None => {
+                        let _ =
+                            tx.send(Err(ResponseError::Other("No shutdown in progress.").into()));
+                    }
+                },
+                Request::HandedOver(id) => {
+                    // The handover may have been aborted
+                    if self.handover.as_ref().is_some_and(|h| h.id == id) {
+                        return self.handover.as_ref().unwrap().save;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replies to the clients waiting for the shutdown.
+    pub fn finish(self) {
+        if let Some(handover) = self.handover {
+            for waiter in handover.waiters {
+                let _ = waiter.send(Ok(()));
+            }
+        }
+    }
+
+    fn start_handover(&mut self, rafts: Vec<Raft<RaftCommand>>, save: bool, tx: Option<Responder>) {
+        let id = self.next_handover_id;
+        self.next_handover_id += 1;
+        let done_tx = self.tx.clone();
+        let task = tokio::spawn(async move {
+            let handovers = rafts.iter().map(|raft| async move {
+                match raft.transfer_leadership(None).await {
+                    Ok(()) | Err(RaftError::NotLeader { .. } | RaftError::NoTransferTarget) => (),
+                    Err(err) => tracing::warn!("failed to transfer leadership: {}", err),
+                }
+            });
+            futures::future::join_all(handovers).await;
+            let _ = done_tx.send(Request::HandedOver(id));
+        });
+        self.handover = Some(Handover {
+            id,
+            save,
+            task,
+            waiters: tx.into_iter().collect(),
+        });
+    }
+}
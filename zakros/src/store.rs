@@ -1,8 +1,10 @@
+use crate::session::Sessions;
+use bincode::Options;
 use bytes::Bytes;
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, sync::Arc};
-use zakros_raft::StateMachine;
+use std::{cell::RefCell, collections::BTreeMap, net::SocketAddr, sync::Arc};
+use zakros_raft::{ApplyContext, NodeId, StateMachine};
 use zakros_redis::{
     command::{RedisCommand, WriteCommand},
     lockable::RwLockable,
@@ -10,53 +12,187 @@ use zakros_redis::{
     Dictionary, RedisResult,
 };
 
-#[derive(Clone, Default)]
-pub struct Store(Arc<RwLock<Dictionary>>);
+#[derive(Clone)]
+pub struct Store {
+    dict: Arc<RwLock<Dictionary>>,
+    node_addrs: NodeAddrs,
+    sessions: Arc<Mutex<Sessions>>,
+}
 
 impl Store {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(node_addrs: NodeAddrs) -> Self {
+        Self {
+            dict: Default::default(),
+            node_addrs,
+            sessions: Default::default(),
+        }
+    }
+
+    pub fn node_addrs(&self) -> &NodeAddrs {
+        &self.node_addrs
     }
 
     pub fn exec(&self, commands: Vec<(RedisCommand, Vec<Bytes>)>) -> RedisResult {
-        let mut responses = Vec::with_capacity(commands.len());
-        let dict = RefCell::new(self.0.write());
-        for (command, args) in commands {
-            let response = match command {
-                RedisCommand::Write(command) => command.call(&dict, &args),
-                RedisCommand::Read(command) => command.call(&dict, &args),
-                RedisCommand::Stateless(command) => command.call(&args),
-                RedisCommand::System(_) | RedisCommand::Transaction(_) => {
-                    unreachable!()
-                }
-            };
-            responses.push(response);
+        exec(&RefCell::new(self.dict.write()), commands)
+    }
+
+    /// Returns up to `limit` keys that have expired as of now.
+    pub fn expired_keys(&self, limit: usize) -> Vec<Bytes> {
+        self.dict.read().expired_keys(limit)
+    }
+
+    /// Removes the keys that have expired, and returns the number of
+    /// removed keys.
+    pub fn remove_expired(&self, keys: &[Bytes]) -> usize {
+        remove_expired(&mut self.dict.write(), keys)
+    }
+
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.dict.read().count_keys_in_slot(slot)
+    }
+
+    /// Returns up to `count` keys in the slot.
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Bytes> {
+        self.dict
+            .read()
+            .keys_in_slot(slot)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    fn apply_command(
+        &self,
+        dict: &LockedDictionary,
+        sessions: &mut Sessions,
+        command: RaftCommand,
+    ) -> RedisResult {
+        match command {
+            RaftCommand::SingleWrite(_) | RaftCommand::Exec(_) => apply_write(dict, command),
+            RaftCommand::SetNodeAddr(node_id, addr) => {
+                let mut node_addrs = self.node_addrs.0.write();
+                match addr {
+                    Some(addr) => node_addrs.insert(node_id, addr),
+                    None => node_addrs.remove(&node_id),
+                };
+                Ok(Value::ok())
+            }
+            RaftCommand::Expire(keys) => {
+                Ok((remove_expired(&mut dict.borrow_mut(), &keys) as i64).into())
+            }
+            RaftCommand::RegisterSession(id) => sessions.register(id),
+            RaftCommand::Session { id, seq, command } => {
+                sessions.apply(id, seq, || apply_write(dict, *command))
+            }
         }
-        Ok(Value::Array(responses))
     }
 }
 
 impl StateMachine for Store {
     type Command = RaftCommand;
+    type Error = bincode::Error;
 
-    async fn apply(&mut self, command: RaftCommand) -> RedisResult {
-        match command {
-            RaftCommand::SingleWrite((command, args)) => command.call(self, &args),
-            RaftCommand::Exec(commands) => self.exec(commands),
-        }
+    async fn apply_batch(
+        &mut self,
+        commands: Vec<(ApplyContext, RaftCommand)>,
+    ) -> Vec<RedisResult> {
+        // The locks are taken once for the whole batch
+        let dict = RefCell::new(self.dict.write());
+        let mut sessions = self.sessions.lock();
+        let outputs = commands
+            .into_iter()
+            .map(|(context, command)| {
+                // Keys expire at the time assigned by the leader, so that
+                // every node gives the same results
+                dict.borrow_mut().set_time(Some(context.timestamp));
+                self.apply_command(&dict, &mut sessions, command)
+            })
+            .collect();
+        dict.borrow_mut().set_time(None);
+        outputs
+    }
+
+    async fn snapshot(&self) -> Bytes {
+        let dict = self.dict.read();
+        let node_addrs = self.node_addrs.0.read();
+        let sessions = self.sessions.lock();
+        bincode::DefaultOptions::new()
+            .serialize(&(&*dict, &*node_addrs, &*sessions))
+            .unwrap()
+            .into()
+    }
+
+    async fn restore(&mut self, snapshot: Bytes) -> bincode::Result<()> {
+        let (dict, node_addrs, sessions) = bincode::DefaultOptions::new().deserialize(&snapshot)?;
+        *self.dict.write() = dict;
+        *self.node_addrs.0.write() = node_addrs;
+        *self.sessions.lock() = sessions;
+        Ok(())
     }
 }
 
+fn apply_write(dict: &LockedDictionary, command: RaftCommand) -> RedisResult {
+    match command {
+        RaftCommand::SingleWrite((command, args)) => command.call(dict, &args),
+        RaftCommand::Exec(commands) => exec(dict, commands),
+        _ => unreachable!("client sessions only wrap writes"),
+    }
+}
+
+fn remove_expired(dict: &mut Dictionary, keys: &[Bytes]) -> usize {
+    keys.iter().filter(|key| dict.expire_if_needed(key)).count()
+}
+
+fn exec(dict: &LockedDictionary, commands: Vec<(RedisCommand, Vec<Bytes>)>) -> RedisResult {
+    let mut responses = Vec::with_capacity(commands.len());
+    for (command, args) in commands {
+        let response = match command {
+            RedisCommand::Write(command) => command.call(dict, &args),
+            RedisCommand::Read(command) => command.call(dict, &args),
+            RedisCommand::Stateless(command) => command.call(&args),
+            RedisCommand::System(_) | RedisCommand::Transaction(_) => {
+                unreachable!()
+            }
+        };
+        responses.push(response);
+    }
+    Ok(Value::Array(responses))
+}
+
+/// Dictionary locked for the duration of a batch of commands
+type LockedDictionary<'a> = RefCell<RwLockWriteGuard<'a, Dictionary>>;
+
 impl<'a> RwLockable<'a, Dictionary> for Store {
     type ReadGuard = RwLockReadGuard<'a, Dictionary>;
     type WriteGuard = RwLockWriteGuard<'a, Dictionary>;
 
     fn read(&'a self) -> Self::ReadGuard {
-        self.0.read()
+        self.dict.read()
     }
 
     fn write(&'a self) -> Self::WriteGuard {
-        self.0.write()
+        self.dict.write()
+    }
+}
+
+/// Addresses of the nodes in the cluster.
+///
+/// The addresses are replicated with Raft so that nodes added to the cluster
+/// at runtime can be reached from every node.
+#[derive(Clone, Default)]
+pub struct NodeAddrs(Arc<RwLock<BTreeMap<NodeId, SocketAddr>>>);
+
+impl NodeAddrs {
+    pub fn new(addrs: impl IntoIterator<Item = (NodeId, SocketAddr)>) -> Self {
+        Self(Arc::new(RwLock::new(addrs.into_iter().collect())))
+    }
+
+    pub fn get(&self, node_id: NodeId) -> Option<SocketAddr> {
+        self.0.read().get(&node_id).copied()
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.0.read().keys().copied().collect()
     }
 }
 
@@ -64,6 +200,21 @@ impl<'a> RwLockable<'a, Dictionary> for Store {
 pub enum RaftCommand {
     SingleWrite((WriteCommand, Vec<Bytes>)),
     Exec(Vec<(RedisCommand, Vec<Bytes>)>),
+    SetNodeAddr(NodeId, Option<SocketAddr>),
+
+    /// Removes the keys that have expired as of the time of the entry.
+    Expire(Vec<Bytes>),
+
+    /// Registers a client session with the id.
+    RegisterSession(u64),
+
+    /// Write `seq` of the client session `id`. Retries of the write return
+    /// the reply to the original write without applying `command` again.
+    Session {
+        id: u64,
+        seq: u64,
+        command: Box<RaftCommand>,
+    },
 }
 
 impl zakros_raft::Command for RaftCommand {